@@ -0,0 +1,48 @@
+/* C interface to the fstsed matcher. See ffi/README.md */
+#ifndef FSTSED_H
+#define FSTSED_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An opened fst database. Safe to share between threads */
+typedef struct fstsed fstsed_t;
+
+/* Byte offsets of a match in the searched text, end exclusive */
+typedef struct {
+    size_t start;
+    size_t end;
+} fstsed_match_t;
+
+/* Open the fst database at path. template is the decoration used by fstsed_render, NULL for
+ * the default "<{key}|{value}>". Returns NULL on error, see fstsed_last_error */
+fstsed_t *fstsed_open(const char *path, const char *template_);
+
+/* Close a database opened by fstsed_open. NULL is ignored */
+void fstsed_close(fstsed_t *db);
+
+/* Find the matches in text[0..len]. Up to cap of them are written to out, in order. Returns
+ * the total number of matches, which may be more than cap */
+size_t fstsed_find(const fstsed_t *db, const uint8_t *text, size_t len, fstsed_match_t *out,
+                   size_t cap);
+
+/* Decorate every match in text[0..len] with the template, as the fstsed command does to a
+ * line. Returns a buffer of *out_len bytes, not nul terminated, to be released with
+ * fstsed_free. Returns NULL on error */
+uint8_t *fstsed_render(const fstsed_t *db, const uint8_t *text, size_t len, size_t *out_len);
+
+/* Release a buffer returned by fstsed_render */
+void fstsed_free(uint8_t *buf, size_t len);
+
+/* Message of the last error on this thread, or NULL. Valid until the next call that fails */
+const char *fstsed_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* FSTSED_H */
@@ -0,0 +1,156 @@
+use ::fstsed::fstsed::FstSed;
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+use std::slice;
+
+/// Byte offsets of a match, as fstsed_match_t
+#[repr(C)]
+pub struct FfiMatch {
+    pub start: usize,
+    pub end: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(e: anyhow::Error) {
+    let message = CString::new(format!("{e:#}").replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// The input slice, tolerating a null pointer for empty text
+unsafe fn text<'a>(text: *const u8, len: usize) -> &'a [u8] {
+    if text.is_null() || len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(text, len)
+    }
+}
+
+unsafe fn open(path: *const c_char, template: *const c_char) -> Result<FstSed> {
+    if path.is_null() {
+        return Err(anyhow!("fstsed_open: path is NULL"));
+    }
+    let path = CStr::from_ptr(path).to_str()?;
+    let template = if template.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(template).to_str()?.to_string())
+    };
+    FstSed::new(Utf8PathBuf::from(path), template)
+}
+
+/// Open the fst database at `path`, returning NULL on error
+///
+/// # Safety
+///
+/// `path` must be a nul terminated string, and `template` one too or NULL
+#[no_mangle]
+pub unsafe extern "C" fn fstsed_open(path: *const c_char, template: *const c_char) -> *mut FstSed {
+    match open(path, template) {
+        Ok(fsed) => Box::into_raw(Box::new(fsed)),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Close a database opened by fstsed_open
+///
+/// # Safety
+///
+/// `db` must come from fstsed_open, or be NULL, and not be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn fstsed_close(db: *mut FstSed) {
+    if !db.is_null() {
+        drop(Box::from_raw(db));
+    }
+}
+
+/// Write up to `cap` matches of the text to `out`, returning how many there are in total
+///
+/// # Safety
+///
+/// `db` must be an open database, `text` must point to `len` readable bytes and `out` to room
+/// for `cap` matches
+#[no_mangle]
+pub unsafe extern "C" fn fstsed_find(
+    db: *const FstSed,
+    text_ptr: *const u8,
+    len: usize,
+    out: *mut FfiMatch,
+    cap: usize,
+) -> usize {
+    let Some(db) = db.as_ref() else {
+        return 0;
+    };
+    let mut count = 0;
+    for m in db.find_iter(text(text_ptr, len)) {
+        if count < cap && !out.is_null() {
+            out.add(count).write(FfiMatch {
+                start: m.start,
+                end: m.end,
+            });
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Decorate every match in the text, returning a new buffer of `*out_len` bytes
+///
+/// # Safety
+///
+/// `db` must be an open database, `text` must point to `len` readable bytes and `out_len` to a
+/// writable size_t. The result must be released with fstsed_free
+#[no_mangle]
+pub unsafe extern "C" fn fstsed_render(
+    db: *const FstSed,
+    text_ptr: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let Some(db) = db.as_ref() else {
+        set_error(anyhow!("fstsed_render: db is NULL"));
+        return ptr::null_mut();
+    };
+    let input = text(text_ptr, len);
+    let mut rendered = Vec::with_capacity(input.len());
+    let mut lastpos = 0;
+    for m in db.find_iter(input) {
+        rendered.extend_from_slice(&input[lastpos..m.start]);
+        rendered.extend_from_slice(db.get_match(&m).render().as_bytes());
+        lastpos = m.end;
+    }
+    rendered.extend_from_slice(&input[lastpos..]);
+
+    // hand over a boxed slice so fstsed_free can rebuild it from the pointer and length alone
+    let rendered = rendered.into_boxed_slice();
+    if !out_len.is_null() {
+        *out_len = rendered.len();
+    }
+    Box::into_raw(rendered) as *mut u8
+}
+
+/// Release a buffer returned by fstsed_render
+///
+/// # Safety
+///
+/// `buf` and `len` must be exactly as returned by fstsed_render, or `buf` NULL
+#[no_mangle]
+pub unsafe extern "C" fn fstsed_free(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)));
+    }
+}
+
+/// The last error raised on the calling thread, or NULL
+#[no_mangle]
+pub extern "C" fn fstsed_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
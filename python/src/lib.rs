@@ -0,0 +1,118 @@
+// the pymethods expansion converts PyErr into itself
+#![allow(clippy::useless_conversion)]
+
+use ::fstsed::build::{build_fstsed, BuildOptions, InputOrder, KeySpec};
+use ::fstsed::fstsed::{FstSed, Match};
+use camino::Utf8PathBuf;
+use pyo3::prelude::*;
+use std::fs::File;
+use std::io::BufReader;
+
+const BUFFERSIZE: usize = 64 * 1024;
+
+/// A fstsed database opened for searching
+#[pyclass(name = "FstSed", module = "fstsed", frozen)]
+struct PyFstSed {
+    inner: FstSed,
+}
+
+// The value of a match as text, the same as the {value} of a template
+fn value_text(fsed: &FstSed, m: &Match) -> PyResult<String> {
+    Ok(String::from_utf8_lossy(&fsed.value(m)?).into_owned())
+}
+
+#[pymethods]
+impl PyFstSed {
+    /// Open the fst database at `path`. Keys match as whole words, as on the command line,
+    /// unless `boundaries` is False
+    #[new]
+    #[pyo3(signature = (path, boundaries = true))]
+    fn new(path: String, boundaries: bool) -> PyResult<Self> {
+        let mut inner = FstSed::new(Utf8PathBuf::from(path), None)?;
+        if !boundaries {
+            inner = inner.without_boundaries();
+        }
+        Ok(Self { inner })
+    }
+
+    /// The (start, end, key, value) of every match in `text`, in order. Offsets count
+    /// characters, so text[start:end] is the matched text
+    fn find_iter(
+        &self,
+        py: Python<'_>,
+        text: &str,
+    ) -> PyResult<Vec<(usize, usize, String, String)>> {
+        let matches: Vec<Match> =
+            py.allow_threads(|| self.inner.find_iter(text.as_bytes()).collect());
+        // the matcher works on utf8 bytes, python indexes by character
+        let mut chars = 0;
+        let mut bytes = 0;
+        let mut char_offset = |byte: usize| {
+            chars += text[bytes..byte].chars().count();
+            bytes = byte;
+            chars
+        };
+        matches
+            .iter()
+            .map(|m| {
+                let start = char_offset(m.start);
+                let end = char_offset(m.end);
+                let key = String::from_utf8_lossy(m.key()).into_owned();
+                Ok((start, end, key, value_text(&self.inner, m)?))
+            })
+            .collect()
+    }
+
+    /// The value stored with exactly `key`, or None
+    fn get(&self, key: &str) -> PyResult<Option<String>> {
+        self.inner
+            .get(key.as_bytes())
+            .map(|m| value_text(&self.inner, &m))
+            .transpose()
+    }
+
+    fn __contains__(&self, key: &str) -> bool {
+        self.inner.get(key.as_bytes()).is_some()
+    }
+}
+
+/// Build a fst database at `output` from the ndjson records in `input`. Each record is stored
+/// under the string found at each of `keys`, which are field names or jsonpointers. Set
+/// `sorted` when the records are already sorted by key for a much faster build. The values
+/// are written next to it, to `output`.values
+#[pyfunction]
+#[pyo3(signature = (input, output, keys = None, sorted = false))]
+fn build(
+    py: Python<'_>,
+    input: String,
+    output: String,
+    keys: Option<Vec<String>>,
+    sorted: bool,
+) -> PyResult<()> {
+    let output = Utf8PathBuf::from(output);
+    if output.exists() {
+        return Err(pyo3::exceptions::PyFileExistsError::new_err(format!(
+            "fst path {output} already exists"
+        )));
+    }
+    let keys: Vec<KeySpec> = keys
+        .unwrap_or_else(|| vec!["key".to_string()])
+        .into_iter()
+        .map(KeySpec::Field)
+        .collect();
+    let reader = BufReader::with_capacity(BUFFERSIZE, File::open(&input)?);
+    let order = if sorted {
+        InputOrder::Sorted
+    } else {
+        InputOrder::Unsorted
+    };
+    py.allow_threads(|| build_fstsed(reader, &keys, &output, order, &BuildOptions::default()))?;
+    Ok(())
+}
+
+#[pymodule]
+fn fstsed(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyFstSed>()?;
+    m.add_function(wrap_pyfunction!(build, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use crate::fstsed::FstSed;
+use crate::{diagnostics, stats, timings};
+use std::time::{Duration, Instant};
+
+const MIN_BUFFERSIZE: usize = 64 * 1024;
+const MAX_BUFFERSIZE: usize = 8 * 1024 * 1024;
+
+// below this many nanoseconds of search work per input byte, reading is the bottleneck and
+// fewer, larger reads pay off
+const IO_BOUND_NS_PER_BYTE: f64 = 2.0;
+
+/// Tuning holds the measurements taken from a sample of the input and the settings
+/// chosen from them. The pre-filters aren't among them: the db is opened with its filters
+/// before there is input to sample, and shared by the searching threads from then on.
+pub struct Tuning {
+    pub bufsize: usize,
+    pub threads: usize,
+    lines: usize,
+    bytes: usize,
+    hits: usize,
+    elapsed: Duration,
+}
+
+impl Tuning {
+    /// Search every complete line of the sample with the given fstsed db, timing the work
+    /// and counting matches, then pick settings accordingly. The sample is searched again by
+    /// the run itself, so it's left out of --stats, --timings and the warnings
+    pub fn sample(chunk: &[u8], fsed: &FstSed) -> Self {
+        // only measure whole lines. if the chunk has no line terminator at all, the whole
+        // chunk is one (long) partial line
+        let end = memchr::memrchr(b'\n', chunk).map_or(chunk.len(), |i| i + 1);
+        let sample = &chunk[..end];
+
+        let counting = stats::set_enabled(false);
+        let timing = timings::set_enabled(false);
+        let muted = diagnostics::set_muted(true);
+        let mut lines = 0;
+        let mut hits = 0;
+        let start = Instant::now();
+        for line in sample.split_inclusive(|&b| b == b'\n') {
+            for m in fsed.find_iter(line) {
+                // rendering is part of the per-line cost
+                let _ = fsed.get_match(&m).render();
+                hits += 1;
+            }
+            lines += 1;
+        }
+        let elapsed = start.elapsed();
+        stats::set_enabled(counting);
+        timings::set_enabled(timing);
+        diagnostics::set_muted(muted);
+
+        let mut tuning = Self {
+            bufsize: MIN_BUFFERSIZE,
+            threads: 1,
+            lines,
+            bytes: sample.len(),
+            hits,
+            elapsed,
+        };
+        tuning.bufsize = tuning.choose_bufsize();
+        tuning.threads = tuning.choose_threads();
+        tuning
+    }
+
+    fn ns_per_byte(&self) -> f64 {
+        self.elapsed.as_nanos() as f64 / self.bytes.max(1) as f64
+    }
+
+    fn choose_bufsize(&self) -> usize {
+        if self.lines == 0 {
+            return MIN_BUFFERSIZE;
+        }
+        // cheap searching means we spend proportionally more time in read calls
+        let base = if self.ns_per_byte() < IO_BOUND_NS_PER_BYTE {
+            1024 * 1024
+        } else {
+            MIN_BUFFERSIZE
+        };
+        // and make sure a buffer holds a good number of the average sized lines
+        let avgline = self.bytes / self.lines;
+        base.max((avgline * 16).next_power_of_two())
+            .clamp(MIN_BUFFERSIZE, MAX_BUFFERSIZE)
+    }
+
+    fn choose_threads(&self) -> usize {
+        if self.lines == 0 {
+            return 1;
+        }
+        // enough threads that the searching keeps up with reads of an io bound search, but
+        // no more than there are cores to run them
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let wanted = (self.ns_per_byte() / IO_BOUND_NS_PER_BYTE).ceil() as usize;
+        wanted.clamp(1, cores)
+    }
+
+    /// Print the measurements and choices to stderr
+    pub fn report(&self) {
+        let hitrate = if self.lines > 0 {
+            self.hits as f64 / self.lines as f64
+        } else {
+            0.0
+        };
+        eprintln!(
+            "auto-tune: sampled {} lines ({} bytes) in {:?}: {:.0} ns/line, {:.2} ns/byte, {:.3} matches/line",
+            self.lines,
+            self.bytes,
+            self.elapsed,
+            self.elapsed.as_nanos() as f64 / self.lines.max(1) as f64,
+            self.ns_per_byte(),
+            hitrate,
+        );
+        eprintln!("auto-tune: using read buffer size {}", self.bufsize);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::fstsed::FstSed;
+use crate::stats::{self, Stat};
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// Time the matcher over an input, to compare templates and boundary settings. The input is
+/// read into memory first, then each line of it is decorated as the main command does, N
+/// times over, with the output thrown away
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// The fst db to search
+    #[clap(short = 'f', value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    fst: Utf8PathBuf,
+
+    /// Input file to search, or - for stdin
+    #[clap(value_name = "INPUT", default_value = "-", value_hint = clap::ValueHint::FilePath)]
+    input: Utf8PathBuf,
+
+    /// Template to render each match with, as with the main command
+    #[clap(short = 't', long, value_name = "TEMPLATE")]
+    template: Option<String>,
+
+    /// How many times to run over the input
+    #[clap(short = 'n', long, value_name = "N", default_value_t = 5)]
+    iterations: usize,
+
+    /// Search after unicode non-word characters, as with the main command
+    #[clap(long)]
+    unicode: bool,
+
+    /// Search from every position, as with the main command
+    #[clap(long, conflicts_with = "unicode")]
+    no_boundary: bool,
+
+    /// Print the report as json
+    #[clap(long)]
+    json: bool,
+}
+
+/// What one pass over the input counted
+struct Pass {
+    elapsed: Duration,
+    candidates: u64,
+    matches: u64,
+    matched_lines: u64,
+}
+
+pub fn run_bench<W: Write>(args: &BenchArgs, out: &mut W) -> Result<()> {
+    let mut fsed = FstSed::new(args.fst.clone(), args.template.clone())?;
+    if args.unicode {
+        fsed = fsed.with_unicode_boundaries();
+    }
+    if args.no_boundary {
+        fsed = fsed.without_boundaries();
+    }
+    let mut input = Vec::new();
+    if args.input == "-" {
+        std::io::stdin().lock().read_to_end(&mut input)
+    } else {
+        std::fs::File::open(&args.input).and_then(|mut file| file.read_to_end(&mut input))
+    }
+    .with_context(|| format!("Error reading {}", args.input))?;
+    let lines = input.split_inclusive(|&b| b == b'\n').count() as u64;
+
+    stats::enable();
+    let passes: Vec<Pass> = (0..args.iterations.max(1))
+        .map(|_| run_pass(&fsed, &input))
+        .collect();
+    let mut times: Vec<f64> = passes.iter().map(|p| p.elapsed.as_secs_f64()).collect();
+    times.sort_by(f64::total_cmp);
+    let best = times[0];
+    let median = times[times.len() / 2];
+    let mb = input.len() as f64 / 1e6;
+    // every pass searches the same text, so the counts of the first stand for all of them
+    let first = &passes[0];
+
+    if args.json {
+        let report = serde_json::json!({
+            "lines": lines,
+            "bytes": input.len(),
+            "iterations": passes.len(),
+            "seconds": passes.iter().map(|p| p.elapsed.as_secs_f64()).collect::<Vec<_>>(),
+            "best": { "seconds": best, "lines_per_sec": lines as f64 / best, "mb_per_sec": mb / best },
+            "median": { "seconds": median, "lines_per_sec": lines as f64 / median, "mb_per_sec": mb / median },
+            "candidates": first.candidates,
+            "matches": first.matches,
+            "matched_lines": first.matched_lines,
+        });
+        writeln!(out, "{report}")?;
+        return Ok(());
+    }
+    writeln!(out, "input: {lines} lines, {mb:.2} MB")?;
+    for (i, pass) in passes.iter().enumerate() {
+        let secs = pass.elapsed.as_secs_f64();
+        writeln!(
+            out,
+            "pass {}: {secs:.3}s, {:.0} lines/sec, {:.2} MB/sec",
+            i + 1,
+            lines as f64 / secs,
+            mb / secs
+        )?;
+    }
+    for (name, secs) in [("best", best), ("median", median)] {
+        writeln!(
+            out,
+            "{name}: {secs:.3}s, {:.0} lines/sec, {:.2} MB/sec",
+            lines as f64 / secs,
+            mb / secs
+        )?;
+    }
+    writeln!(out, "candidates tested: {}", first.candidates)?;
+    writeln!(
+        out,
+        "matches: {} on {} lines",
+        first.matches, first.matched_lines
+    )?;
+    Ok(())
+}
+
+// Decorate every line of the input into a scratch buffer, as process_line does
+fn run_pass(fsed: &FstSed, input: &[u8]) -> Pass {
+    let candidates = stats::get(Stat::Candidates);
+    let matches = stats::get(Stat::Matches);
+    let mut matched_lines = 0;
+    let mut decorated = Vec::new();
+    let started = Instant::now();
+    for line in input.split_inclusive(|&b| b == b'\n') {
+        decorated.clear();
+        let mut last = 0;
+        for m in fsed.find_iter(line) {
+            decorated.extend_from_slice(&line[last..m.start]);
+            decorated.extend_from_slice(fsed.get_match(&m).render().as_bytes());
+            last = m.end;
+        }
+        if last > 0 {
+            matched_lines += 1;
+        }
+        decorated.extend_from_slice(&line[last..]);
+        std::hint::black_box(&decorated);
+    }
+    Pass {
+        elapsed: started.elapsed(),
+        candidates: stats::get(Stat::Candidates) - candidates,
+        matches: stats::get(Stat::Matches) - matches,
+        matched_lines,
+    }
+}
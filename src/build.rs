@@ -1,87 +1,1103 @@
-use anyhow::{Error, Result};
+use crate::charset::KeyType;
+use crate::diagnostics;
+use crate::fstsed::{
+    meta, meta_entry, mmap_fst, META_COMPRESSION, META_KEY_TYPE, META_NORMALIZE, META_ZSTD_DICT,
+};
+use crate::hashed::{KeyHasher, META_MAX_KEY_LEN, META_SALT};
+use crate::stix;
+use crate::transform::ValueTransform;
+use crate::valuestore::{sidecar_path, Layout, ValueStore, ValueStoreWriter, META_LAYOUT};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use bstr::io::BufReadExt;
 use camino::Utf8PathBuf;
-use fst::SetBuilder;
+use clap::ValueEnum;
+use fst::{map, set, Map, MapBuilder, Set, SetBuilder, Streamer};
+use serde::Serialize;
 use serde_json::Value;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+use unicode_normalization::UnicodeNormalization;
 
 const SENTINEL: u8 = 0;
+const BUFFERSIZE: usize = 64 * 1024;
+// zstd's own defaults for dictionary training: a 110 KiB dictionary is trained on about 100
+// times its size in samples
+const DICT_SIZE: usize = 110 * 1024;
+const DICT_SAMPLES: usize = 100_000;
 
-pub fn build_fstsed<R>(
+/// A key and its compressed value
+type Entry = (Vec<u8>, Vec<u8>);
+
+/// Where to find the fst key(s) of each json record. A record gets one fst entry per distinct
+/// key found across all of the build's key specs, all pointing at the same value. A spec's
+/// field may hold a string or an array of them, e.g. a threat actor's aliases
+pub enum KeySpec {
+    /// A json field name or jsonpointer holding a string
+    Field(String),
+    /// A json field name or jsonpointer holding a STIX pattern. Every value compared in the
+    /// pattern is a key
+    StixPattern(String),
+}
+
+impl KeySpec {
+    fn name(&self) -> &str {
+        match self {
+            Self::Field(name) | Self::StixPattern(name) => name,
+        }
+    }
+
+    /// Add the keys this spec finds in the record to `keys`
+    fn extract(&self, record: &Value, keys: &mut Vec<String>) {
+        let name = self.name();
+        let found = if name.starts_with('/') {
+            record.pointer(name)
+        } else {
+            record.get(name)
+        };
+        // non-string items of an array are skipped like a non-string field
+        let found = match found {
+            Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+            Some(Value::String(s)) => vec![s.as_str()],
+            _ => return,
+        };
+        for found in found {
+            match self {
+                Self::Field(_) => keys.push(found.to_string()),
+                Self::StixPattern(_) => keys.extend(stix::pattern_values(found)),
+            }
+        }
+    }
+
+    /// Take the spec's field out of the record, so that a hashed db doesn't store the key in
+    /// the clear in its value
+    fn remove(&self, record: &mut Value) {
+        let name = self.name();
+        let (parent, field) = match name.strip_prefix('/') {
+            Some(_) => {
+                let (parent, field) = name.rsplit_once('/').unwrap_or_default();
+                (
+                    record.pointer_mut(parent),
+                    field.replace("~1", "/").replace("~0", "~"),
+                )
+            }
+            None => (Some(record), name.to_string()),
+        };
+        if let Some(Value::Object(object)) = parent {
+            object.shift_remove(&field);
+        }
+    }
+}
+
+/// A way of canonicalizing keys before they go into the fst, so that a feed's variations of a
+/// key all end up as one
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum KeyNormalization {
+    /// Lowercase the key. Searches then match the key regardless of (ascii) case
+    Lowercase,
+    /// Strip leading and trailing whitespace
+    Trim,
+    /// Compose accented letters the unicode NFC way, so that both spellings of é are the same
+    Nfc,
+}
+
+impl KeyNormalization {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Lowercase => "lowercase",
+            Self::Trim => "trim",
+            Self::Nfc => "nfc",
+        }
+    }
+
+    fn apply(&self, key: &str) -> String {
+        match self {
+            Self::Lowercase => key.to_lowercase(),
+            Self::Trim => key.trim().to_string(),
+            Self::Nfc => key.nfc().collect(),
+        }
+    }
+}
+
+/// What to store for a key read from more than one record
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnDuplicate {
+    /// The value of the first record read with the key
+    First,
+    /// The value of the last record read with the key
+    Last,
+    /// Fail the build, naming the key
+    Error,
+    /// A json array of the distinct values of all the records with the key, in the order read
+    Merge,
+}
+
+// Sort the entries by key and leave one per key, chosen or merged per the policy. The sort is
+// stable, so the values of a key stay in the order they were read
+fn resolve_duplicates(
+    mut vals: Vec<Entry>,
+    policy: OnDuplicate,
+    options: &BuildOptions,
+) -> Result<Vec<Entry>> {
+    vals.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut resolved: Vec<Entry> = Vec::with_capacity(vals.len());
+    let mut vals = vals.into_iter().peekable();
+    while let Some((key, value)) = vals.next() {
+        let mut values = vec![value];
+        while let Some((_, value)) = vals.next_if(|(next, _)| *next == key) {
+            values.push(value);
+        }
+        let value = match policy {
+            _ if values.len() == 1 => values.swap_remove(0),
+            OnDuplicate::First => values.swap_remove(0),
+            OnDuplicate::Last => values.pop().unwrap_or_default(),
+            OnDuplicate::Error => bail!(
+                "key {:?} is in {} records (--on-duplicate error)",
+                key.escape_ascii().to_string(),
+                values.len()
+            ),
+            OnDuplicate::Merge => merge_values(&values, options)?,
+        };
+        resolved.push((key, value));
+    }
+    Ok(resolved)
+}
+
+// Combine the values of a key into a json array of the distinct ones. Values waiting for a
+// dictionary to be trained aren't compressed yet, and the array is left so too
+fn merge_values(values: &[Vec<u8>], options: &BuildOptions) -> Result<Vec<u8>> {
+    let level = match options.compression {
+        Compression::Zstd(level) if !options.train_dict => Some(level),
+        _ => None,
+    };
+    let mut merged: Vec<Value> = Vec::with_capacity(values.len());
+    for value in values {
+        let record: Value = match level {
+            Some(_) => serde_json::from_slice(&zstd::stream::decode_all(value.as_slice())?)?,
+            None => serde_json::from_slice(value)?,
+        };
+        if !merged.contains(&record) {
+            merged.push(record);
+        }
+    }
+    let json = serde_json::to_vec(&merged)?;
+    Ok(match level {
+        Some(level) => zstd::stream::encode_all(json.as_slice(), level)?,
+        None => json,
+    })
+}
+
+// Record the keys of a record as they were before normalizing changed them, in the stored
+// value's "original_keys" array
+fn keep_original_keys(record: &mut Value, originals: &[String]) {
+    if let (Value::Object(members), false) = (record, originals.is_empty()) {
+        members.insert("original_keys".to_string(), originals.into());
+    }
+}
+
+/// Tallies of the records read for a build
+#[derive(Default)]
+struct Counts {
+    lines: usize,
+    errors: usize,
+    blanks: usize,
+    entries: usize,
+    /// Length of the longest key, recorded in hashed dbs
+    max_key_len: usize,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.lines += other.lines;
+        self.errors += other.errors;
+        self.blanks += other.blanks;
+        self.entries += other.entries;
+        self.max_key_len = self.max_key_len.max(other.max_key_len);
+    }
+
+    fn report(&self, options: &BuildOptions) {
+        progress(
+            options,
+            format_args!(
+                "Processed {} lines successfully into {} keys with {} errors and {} blank lines...",
+                self.lines, self.entries, self.errors, self.blanks
+            ),
+        );
+    }
+}
+
+/// What a build read and wrote, for --build-summary-json and --stats-json
+#[derive(Serialize, Debug)]
+pub struct BuildSummary {
+    /// Lines of input read, whether they were records, errors or blank
+    pub lines: usize,
+    /// Records stored under at least one key
+    pub records: usize,
+    /// Records skipped for bad json, a missing key or a failure to compress them
+    pub errors: usize,
+    pub blank_lines: usize,
+    /// Keys read from the records, counting every repeat of a key
+    pub keys_read: usize,
+    /// Distinct keys in the fst
+    pub keys: usize,
+    /// Keys read again after their first record
+    pub duplicate_keys: usize,
+    /// How the duplicate keys were resolved, if --on-duplicate was given
+    pub on_duplicate: Option<OnDuplicate>,
+    pub fst_bytes: u64,
+    /// Size of the value store of a map layout db
+    pub value_store_bytes: Option<u64>,
+    pub elapsed_secs: f64,
+    /// The metadata entries of the db by name. The value dictionary is given as its size
+    pub metadata: serde_json::Map<String, Value>,
+}
+
+impl BuildSummary {
+    fn new(
+        counts: &Counts,
+        keys: usize,
+        meta: &[Vec<u8>],
+        output: &Utf8PathBuf,
+        options: &BuildOptions,
+        started: Instant,
+    ) -> Result<Self> {
+        let size = |path: &Utf8PathBuf| {
+            fs::metadata(path)
+                .map(|m| m.len())
+                .with_context(|| format!("Error reading the size of {path}"))
+        };
+        let mut metadata = serde_json::Map::new();
+        for entry in meta {
+            // each entry is the sentinel, the name, = and the value
+            let mut parts = entry[1..].splitn(2, |&b| b == b'=');
+            let name = String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+            let value = parts.next().unwrap_or_default();
+            let value = if name == META_ZSTD_DICT {
+                Value::from(value.len())
+            } else {
+                Value::from(String::from_utf8_lossy(value))
+            };
+            metadata.insert(name, value);
+        }
+        Ok(Self {
+            lines: counts.lines + counts.errors + counts.blanks,
+            records: counts.lines,
+            errors: counts.errors,
+            blank_lines: counts.blanks,
+            keys_read: counts.entries,
+            keys,
+            duplicate_keys: counts.entries - keys,
+            on_duplicate: options.on_duplicate,
+            fst_bytes: size(output)?,
+            value_store_bytes: match options.layout {
+                Layout::Map => Some(size(&sidecar_path(output))?),
+                Layout::Sentinel => None,
+            },
+            elapsed_secs: started.elapsed().as_secs_f64(),
+            metadata,
+        })
+    }
+}
+
+/// How the keys of the build input are ordered
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum InputOrder {
+    Unsorted,
+    /// Presorted by key, trusted as is
+    Sorted,
+    /// Presorted by key, checked while reading so that out of order input fails the build
+    Verified,
+}
+
+/// How the values are stored in the fst
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Compression {
+    /// zstd at the given level, 1 to 22
+    Zstd(i32),
+    /// Not compressed at all, for the fastest lookups of small values
+    None,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::Zstd(3)
+    }
+}
+
+/// How keys and values are rewritten on the way into the fst
+#[derive(Default)]
+pub struct BuildOptions<'a> {
+    /// Reshape each record before storing it as the value
+    pub transform: Option<&'a ValueTransform>,
+    /// Store salted hashes of the keys instead of the keys, and leave the key fields out of
+    /// the stored records
+    pub hasher: Option<&'a KeyHasher>,
+    /// The declared type of the keys, recorded for searches to pick their matching rules from
+    pub key_type: Option<KeyType>,
+    /// Canonicalize the keys with these steps, in order. The stored value keeps the keys
+    /// they changed as they were
+    pub normalize: &'a [KeyNormalization],
+    /// Keep one value per key, chosen or merged this way. Without it every value of a key is
+    /// stored, though searches only find the first in sort order
+    pub on_duplicate: Option<OnDuplicate>,
+    /// Train a zstd dictionary on the values, store it in the fst and compress the values with
+    /// it. Pays off for many small values that look alike
+    pub train_dict: bool,
+    pub compression: Compression,
+    pub layout: Layout,
+    /// Leave out the progress messages on stderr
+    pub quiet: bool,
+}
+
+// Tell the user how the build is going, unless it's quiet
+fn progress(options: &BuildOptions, message: std::fmt::Arguments) {
+    if !options.quiet {
+        eprintln!("{message}");
+    }
+}
+
+// Tracks the last key read, to verify that presorted input really is in order
+#[derive(Default)]
+struct OrderCheck {
+    last: String,
+    line: usize,
+}
+
+impl OrderCheck {
+    // Check the sorted keys of the record on `lineno` against those of the records before it
+    fn check(&mut self, lineno: usize, keys: &[String]) -> io::Result<()> {
+        let (Some(first), Some(last)) = (keys.first(), keys.last()) else {
+            return Ok(());
+        };
+        if self.line > 0 && *first < self.last {
+            return Err(io::Error::other(format!(
+                "line {lineno}: key {first:?} sorts before key {:?} on line {}, so the input \
+                 isn't sorted (--verify-order)",
+                self.last, self.line
+            )));
+        }
+        self.last.clone_from(last);
+        self.line = lineno;
+        Ok(())
+    }
+}
+
+// Read the json records of input into unsorted entries: each key and the compressed value.
+// With train_dict the values are left uncompressed, for compress_with_dict
+fn read_entries<R>(
     mut input: R,
-    key: &str,
-    output: &Utf8PathBuf,
-    sorted: bool,
+    keyspecs: &[KeySpec],
+    options: &BuildOptions,
+    order: Option<&mut OrderCheck>,
+    vals: &mut Vec<Entry>,
+    counts: &mut Counts,
 ) -> Result<(), Error>
 where
     R: BufReadExt,
 {
-    let mut vals: Vec<Vec<u8>> = Vec::new();
-    let mut num_errors = 0;
-    let mut num_blanks = 0;
-
-    // Preprocess key to determine if it's a JSON pointer and define the function
-    // outside of the per-line loop. Clippy says this is too complex, but i can't
-    // figure out lifetimes in other functions
-    #[allow(clippy::type_complexity)]
-    let value_extractor: Box<dyn Fn(&Value) -> Option<&str>> = if key.starts_with('/') {
-        Box::new(|jsonline| jsonline.pointer(key).and_then(Value::as_str))
-    } else {
-        Box::new(|jsonline| jsonline.get(key).and_then(Value::as_str))
-    };
+    let mut reader = EntryReader::new(keyspecs, options, order, vals, counts);
+    let mut lineno: usize = 0;
 
     // for this loop, we omit the line terminators
     input.for_byte_line(|line| {
+        lineno += 1;
         if line.is_empty() {
-            num_blanks += 1;
+            reader.counts.blanks += 1;
             return Ok(true);
         }
-        let jsonline = serde_json::from_slice(line).unwrap_or_default();
-        if let Some(keyvalue) = value_extractor(&jsonline) {
-            // conservative sizing - allocate enough memory for key plus full uncompressed line
-            let mut tuple: Vec<u8> = Vec::with_capacity(keyvalue.len() + line.len());
+        let jsonline = match serde_json::from_slice(line) {
+            Ok(jsonline) => jsonline,
+            Err(e) => {
+                diagnostics::warn_quiet(
+                    "bad_json",
+                    format!("line {lineno}: {e}"),
+                    serde_json::json!({ "line": lineno, "error": e.to_string() }),
+                );
+                Value::default()
+            }
+        };
+        reader.add(lineno, line, jsonline)?;
+        Ok(true)
+    })?;
+    Ok(())
+}
+
+/// Turns records read one at a time into entries, counting what it read
+struct EntryReader<'r, 'o> {
+    keyspecs: &'r [KeySpec],
+    options: &'r BuildOptions<'o>,
+    order: Option<&'r mut OrderCheck>,
+    vals: &'r mut Vec<Entry>,
+    counts: &'r mut Counts,
+    // reused from record to record
+    keys: Vec<String>,
+    originals: Vec<String>,
+}
 
-            // start with the fst key itself
-            tuple.extend_from_slice(keyvalue.as_bytes());
-            // then add the sentinel to delineate key from data
-            tuple.push(SENTINEL);
+impl<'r, 'o> EntryReader<'r, 'o> {
+    fn new(
+        keyspecs: &'r [KeySpec],
+        options: &'r BuildOptions<'o>,
+        order: Option<&'r mut OrderCheck>,
+        vals: &'r mut Vec<Entry>,
+        counts: &'r mut Counts,
+    ) -> Self {
+        Self {
+            keyspecs,
+            options,
+            order,
+            vals,
+            counts,
+            keys: Vec::new(),
+            originals: Vec::new(),
+        }
+    }
 
-            // zstd compress the line and write directly into output tuple
-            if zstd::stream::copy_encode(line, &mut tuple, 3).is_err() {
-                num_errors += 1;
-            } else {
-                // push the assembled tuple to our vector of vectors
-                vals.push(tuple);
+    // Add the entries of the record numbered `lineno`, `line` being its json text and
+    // `jsonline` its parsed value. A record that can't be added is counted as an error
+    fn add(&mut self, lineno: usize, line: &[u8], mut jsonline: Value) -> io::Result<()> {
+        let keyspecs = self.keyspecs;
+        let (keys, originals) = (&mut self.keys, &mut self.originals);
+        let (vals, counts) = (&mut *self.vals, &mut *self.counts);
+        let BuildOptions {
+            transform,
+            hasher,
+            key_type,
+            normalize,
+            train_dict,
+            compression,
+            ..
+        } = *self.options;
+        keys.clear();
+        for spec in keyspecs {
+            spec.extract(&jsonline, keys);
+        }
+        originals.clear();
+        for key in keys.iter_mut() {
+            let normalized = normalize
+                .iter()
+                .fold(key.clone(), |key, step| step.apply(&key));
+            if normalized != *key {
+                originals.push(std::mem::replace(key, normalized));
+            }
+        }
+        // keys of case-insensitive types are stored in lowercase, the form searches fold to
+        if key_type.is_some_and(KeyType::folds_case) {
+            keys.iter_mut().for_each(|k| k.make_ascii_lowercase());
+        }
+        // aliases often repeat the primary key, an empty key could never match, and one
+        // starting with NUL would read as a metadata entry
+        keys.retain(|k| !k.is_empty() && !k.starts_with('\0'));
+        keys.sort_unstable();
+        keys.dedup();
+        if let Some(order) = self.order.as_deref_mut() {
+            order.check(lineno, keys)?;
+        }
+
+        if !keys.is_empty() {
+            if hasher.is_some() {
+                for spec in keyspecs {
+                    spec.remove(&mut jsonline);
+                }
+                // nor the keys in the clear as they were before normalizing
+                originals.clear();
+            }
+            originals.sort_unstable();
+            originals.dedup();
+            // the stored value is either the line as is or the reshaped record
+            let transformed;
+            let line = match transform {
+                Some(t) => {
+                    let mut reshaped = t.apply(&jsonline);
+                    keep_original_keys(&mut reshaped, originals);
+                    transformed = serde_json::to_vec(&reshaped)?;
+                    transformed.as_slice()
+                }
+                None if hasher.is_some() || !originals.is_empty() => {
+                    keep_original_keys(&mut jsonline, originals);
+                    transformed = serde_json::to_vec(&jsonline)?;
+                    transformed.as_slice()
+                }
+                None => line,
+            };
+
+            // zstd compress the line once and share it between all the record's keys. Lines
+            // waiting for the dictionary to be trained are kept as they are
+            let compressed = match compression {
+                Compression::Zstd(_) if train_dict => Ok(line.to_vec()),
+                Compression::Zstd(level) => zstd::stream::encode_all(line, level),
+                Compression::None => Ok(line.to_vec()),
+            };
+            let compressed = match compressed {
+                Ok(compressed) => compressed,
+                Err(e) => {
+                    diagnostics::warn_quiet(
+                        "compression",
+                        format!("line {lineno}: {e}"),
+                        serde_json::json!({ "line": lineno, "key": keys[0], "error": e.to_string() }),
+                    );
+                    counts.errors += 1;
+                    return Ok(());
+                }
+            };
+
+            for keyvalue in keys.iter() {
+                counts.max_key_len = counts.max_key_len.max(keyvalue.len());
+                let hashed;
+                let keyvalue = match hasher {
+                    Some(hasher) => {
+                        hashed = hasher.hash(keyvalue.as_bytes());
+                        hashed.as_slice()
+                    }
+                    None => keyvalue.as_bytes(),
+                };
+                vals.push((keyvalue.to_vec(), compressed.clone()));
             }
+            counts.lines += 1;
+            counts.entries += keys.len();
         } else {
-            num_errors += 1;
+            if !jsonline.is_null() {
+                let fields: Vec<&str> = keyspecs.iter().map(KeySpec::name).collect();
+                diagnostics::warn_quiet(
+                    "missing_key",
+                    format!(
+                        "line {lineno}: no string value for key {}",
+                        fields.join(", ")
+                    ),
+                    serde_json::json!({ "line": lineno, "field": fields }),
+                );
+            }
+            counts.errors += 1;
         }
-        Ok(true)
-    })?;
+        Ok(())
+    }
+}
 
-    eprintln!(
-        "Processed {} lines successfully with {num_errors} errors and {num_blanks} blank lines...",
-        vals.len()
+// The metadata entries of a db, in order: uncompressed values, the salt of hashed keys, the
+// declared key type, the layout, the longest hashed key, the key normalization steps and the
+// value dictionary
+fn meta_entries(options: &BuildOptions, counts: &Counts, dict: Option<&[u8]>) -> Vec<Vec<u8>> {
+    let BuildOptions {
+        hasher,
+        key_type,
+        compression,
+        layout,
+        normalize,
+        ..
+    } = *options;
+    let mut entries = Vec::new();
+    if compression == Compression::None {
+        entries.push(meta_entry(META_COMPRESSION, b"none"));
+    }
+    if let Some(hasher) = hasher {
+        entries.push(meta_entry(META_SALT, hasher.salt()));
+    }
+    if let Some(key_type) = key_type {
+        entries.push(meta_entry(META_KEY_TYPE, key_type.name().as_bytes()));
+    }
+    if layout == Layout::Map {
+        entries.push(meta_entry(META_LAYOUT, b"map"));
+    }
+    if hasher.is_some() {
+        entries.push(meta_entry(
+            META_MAX_KEY_LEN,
+            counts.max_key_len.to_string().as_bytes(),
+        ));
+    }
+    if !normalize.is_empty() {
+        let steps: Vec<&str> = normalize.iter().map(KeyNormalization::name).collect();
+        entries.push(meta_entry(META_NORMALIZE, steps.join(",").as_bytes()));
+    }
+    if let Some(dict) = dict {
+        entries.push(meta_entry(META_ZSTD_DICT, dict));
+    }
+    entries
+}
+
+// Train a zstd dictionary on a sample of the uncompressed values of the entries, then compress
+// every value with it. Returns the dictionary, or None if there was too little to train on, in
+// which case the values are compressed without one
+fn compress_with_dict(
+    vals: &mut [Entry],
+    level: i32,
+    options: &BuildOptions,
+) -> Result<Option<Vec<u8>>> {
+    let stride = (vals.len() / DICT_SAMPLES).max(1);
+    let samples: Vec<&[u8]> = vals
+        .iter()
+        .step_by(stride)
+        .map(|(_, value)| value.as_slice())
+        .collect();
+    progress(
+        options,
+        format_args!("Training a dictionary on {} values...", samples.len()),
     );
+    let dict = match zstd::dict::from_samples(&samples, DICT_SIZE) {
+        Ok(dict) => Some(dict),
+        Err(e) => {
+            eprintln!("Not using a dictionary, training it failed: {e}");
+            None
+        }
+    };
+    let mut compressor = match &dict {
+        Some(dict) => zstd::bulk::Compressor::with_dictionary(level, dict)?,
+        None => zstd::bulk::Compressor::new(level)?,
+    };
+    for (_, value) in vals.iter_mut() {
+        *value = compressor.compress(value)?;
+    }
+    Ok(dict)
+}
+
+// The sentinel layout's fst entry: the key, the sentinel to delineate key from data, and the
+// compressed value
+fn sentinel_entry((key, value): &Entry) -> Vec<u8> {
+    let mut tuple = Vec::with_capacity(key.len() + 1 + value.len());
+    tuple.extend_from_slice(key);
+    tuple.push(SENTINEL);
+    tuple.extend_from_slice(value);
+    tuple
+}
 
-    if !sorted {
-        eprintln!("Sorting keys to build the fst...");
+// Write the metadata entries and the sorted entries out in the layout of the options,
+// returning the number of distinct keys. A map keeps the first of the values of a key, the one
+// a search of the sentinel layout finds
+fn write_fst(
+    meta: &[Vec<u8>],
+    vals: &[Entry],
+    output: &Utf8PathBuf,
+    layout: Layout,
+) -> Result<usize, Error> {
+    let wtr = io::BufWriter::new(File::create(output)?);
+    let mut keys = 0;
+    match layout {
+        Layout::Sentinel => {
+            let mut set = SetBuilder::new(wtr)?;
+            // metadata entries start with the sentinel, so they sort ahead of every key
+            set.extend_iter(meta)?;
+            let mut last: Option<&[u8]> = None;
+            for entry in vals {
+                if last != Some(entry.0.as_slice()) {
+                    keys += 1;
+                    last = Some(&entry.0);
+                }
+                set.insert(sentinel_entry(entry))?;
+            }
+            set.finish()?;
+        }
+        Layout::Map => {
+            let mut map = MapBuilder::new(wtr)?;
+            let mut store = ValueStoreWriter::default();
+            for entry in store.stamp(meta) {
+                map.insert(entry, 0)?;
+            }
+            let mut last: Option<&[u8]> = None;
+            for (key, value) in vals {
+                if last == Some(key.as_slice()) {
+                    continue;
+                }
+                last = Some(key);
+                keys += 1;
+                map.insert(key, store.push(value)?)?;
+            }
+            map.finish()?;
+            store.save(&sidecar_path(output))?;
+        }
+    }
+    Ok(keys)
+}
+
+/// Build a fst from the json records of `input`, with its keys and values rewritten as the
+/// options say
+pub fn build_fstsed<R>(
+    input: R,
+    keyspecs: &[KeySpec],
+    output: &Utf8PathBuf,
+    order: InputOrder,
+    options: &BuildOptions,
+) -> Result<BuildSummary, Error>
+where
+    R: BufReadExt,
+{
+    let started = Instant::now();
+    let mut vals: Vec<Entry> = Vec::new();
+    let mut counts = Counts::default();
+    let mut check = OrderCheck::default();
+    let verify = (order == InputOrder::Verified).then_some(&mut check);
+    read_entries(input, keyspecs, options, verify, &mut vals, &mut counts)?;
+    assemble(vals, counts, output, order, options, started)
+}
+
+/// Build a fst from records handed over one at a time, e.g. the rows of a database query,
+/// rather than read from ndjson lines. `records` calls the function it's given with each
+/// record, whose json is stored as its value
+pub fn build_fstsed_records<F>(
+    records: F,
+    keyspecs: &[KeySpec],
+    output: &Utf8PathBuf,
+    order: InputOrder,
+    options: &BuildOptions,
+) -> Result<BuildSummary, Error>
+where
+    F: FnOnce(&mut dyn FnMut(Value) -> Result<()>) -> Result<()>,
+{
+    let started = Instant::now();
+    let mut vals: Vec<Entry> = Vec::new();
+    let mut counts = Counts::default();
+    let mut check = OrderCheck::default();
+    let verify = (order == InputOrder::Verified).then_some(&mut check);
+    let mut reader = EntryReader::new(keyspecs, options, verify, &mut vals, &mut counts);
+    let mut lineno: usize = 0;
+    records(&mut |record| {
+        lineno += 1;
+        let line = serde_json::to_vec(&record)?;
+        Ok(reader.add(lineno, &line, record)?)
+    })?;
+    assemble(vals, counts, output, order, options, started)
+}
+
+// Write out the fst of the unsorted entries of a build
+fn assemble(
+    mut vals: Vec<Entry>,
+    counts: Counts,
+    output: &Utf8PathBuf,
+    order: InputOrder,
+    options: &BuildOptions,
+    started: Instant,
+) -> Result<BuildSummary, Error> {
+    counts.report(options);
+    if let Some(policy) = options.on_duplicate {
+        vals = resolve_duplicates(vals, policy, options)?;
+    }
+    let dict = match options.compression {
+        Compression::Zstd(level) if options.train_dict => {
+            compress_with_dict(&mut vals, level, options)?
+        }
+        _ => None,
+    };
+    let meta = meta_entries(options, &counts, dict.as_deref());
+
+    // records with several keys can't keep presorted input in order, nor can hashed or
+    // lowercased keys, nor values compressed with a dictionary. Resolving duplicates already
+    // sorted the keys, which are unique after it
+    let reordered = options.hasher.is_some()
+        || options.key_type.is_some_and(KeyType::folds_case)
+        || !options.normalize.is_empty()
+        || dict.is_some();
+    if options.on_duplicate.is_none()
+        && (order == InputOrder::Unsorted || vals.len() != counts.lines || reordered)
+    {
+        progress(options, format_args!("Sorting keys to build the fst..."));
         // sort the vector for fst
         vals.sort_unstable();
     }
 
-    // create file
-    let wtr = io::BufWriter::new(File::create(output)?);
-    let mut set = SetBuilder::new(wtr)?;
+    progress(options, format_args!("Assembling the fst..."));
+    let keys = write_fst(&meta, &vals, output, options.layout)?;
+    BuildSummary::new(&counts, keys, &meta, output, options, started)
+}
 
-    eprintln!("Assembling the fst...");
-    // insert into set builder
-    vals.iter().for_each(|line| {
-        set.insert(line).expect("error building fstsed database");
+/// A worker's sorted share of a parallel build, in the layout being built
+enum Shard {
+    Sentinel(Set<Vec<u8>>),
+    /// Each key with the number of its value among the shard's values
+    Map(Map<Vec<u8>>, Vec<Vec<u8>>),
+}
+
+impl Shard {
+    // deduplicate and compact the sorted entries of a worker
+    fn new(vals: Vec<Entry>, layout: Layout) -> Result<Self> {
+        Ok(match layout {
+            Layout::Sentinel => {
+                let mut shard = SetBuilder::memory();
+                shard.extend_iter(vals.iter().map(sentinel_entry))?;
+                Self::Sentinel(shard.into_set())
+            }
+            Layout::Map => {
+                let mut shard = MapBuilder::memory();
+                let mut values = Vec::new();
+                let mut last: Option<Vec<u8>> = None;
+                for (key, value) in vals {
+                    // the entries are sorted, so the first value of a key comes first
+                    if last.as_ref() == Some(&key) {
+                        continue;
+                    }
+                    shard.insert(&key, values.len() as u64)?;
+                    values.push(value);
+                    last = Some(key);
+                }
+                Self::Map(shard.into_map(), values)
+            }
+        })
+    }
+}
+
+/// Build one fst from many input files using `threads` workers. Each worker takes the next
+/// unread file until none are left, then sorts everything it read into an in-memory shard.
+/// The shards, being much smaller than the raw entries, are merged into the output at the end
+pub fn build_fstsed_parallel(
+    inputs: &[Utf8PathBuf],
+    keyspecs: &[KeySpec],
+    output: &Utf8PathBuf,
+    threads: usize,
+    options: &BuildOptions,
+) -> Result<BuildSummary, Error> {
+    // the shards' values would all need the same dictionary, trained before any is compressed
+    if options.train_dict {
+        bail!("--train-dict needs a single build input read by one thread");
+    }
+    let started = Instant::now();
+    let next = AtomicUsize::new(0);
+    let worker = || -> Result<(Shard, Counts)> {
+        let mut vals: Vec<Entry> = Vec::new();
+        let mut counts = Counts::default();
+        while let Some(path) = inputs.get(next.fetch_add(1, Ordering::Relaxed)) {
+            let reader: Box<dyn BufReadExt> = if path.as_os_str() == "-" {
+                Box::new(io::BufReader::with_capacity(BUFFERSIZE, io::stdin()))
+            } else {
+                let file = File::open(path)
+                    .with_context(|| format!("Error opening build input {path}"))?;
+                Box::new(io::BufReader::with_capacity(BUFFERSIZE, file))
+            };
+            read_entries(reader, keyspecs, options, None, &mut vals, &mut counts)
+                .with_context(|| format!("Error reading build input {path}"))?;
+        }
+        match options.on_duplicate {
+            Some(policy) => vals = resolve_duplicates(vals, policy, options)?,
+            None => vals.sort_unstable(),
+        }
+        Ok((Shard::new(vals, options.layout)?, counts))
+    };
+
+    let threads = threads.clamp(1, inputs.len().max(1));
+    // which of a key's records comes first depends on which worker read them
+    if options.on_duplicate.is_some() && threads > 1 {
+        bail!("--on-duplicate needs the build inputs read by one thread");
+    }
+    let results: Vec<Result<(Shard, Counts)>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|_| scope.spawn(worker)).collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("build worker panicked"))
+            .collect()
     });
 
-    // close the fst
-    set.finish().map_err(From::from)
+    let mut sets = Vec::new();
+    let mut maps = Vec::new();
+    let mut counts = Counts::default();
+    for result in results {
+        let (shard, worker_counts) = result?;
+        counts.add(&worker_counts);
+        match shard {
+            Shard::Sentinel(set) => sets.push(set),
+            Shard::Map(map, values) => maps.push((map, values)),
+        }
+    }
+    counts.report(options);
+    let meta = meta_entries(options, &counts, None);
+
+    let wtr = io::BufWriter::new(File::create(output)?);
+    progress(
+        options,
+        format_args!("Merging {} shards into the fst...", sets.len() + maps.len()),
+    );
+    let mut keys = 0;
+    if options.layout == Layout::Sentinel {
+        let mut set = SetBuilder::new(wtr)?;
+        // metadata entries start with the sentinel, so they sort ahead of every key
+        set.extend_iter(&meta)?;
+        // the union yields every distinct entry once, in order
+        let mut union = sets.iter().collect::<set::OpBuilder>().union();
+        let mut last: Vec<u8> = Vec::new();
+        while let Some(entry) = union.next() {
+            let key = entry.split(|&b| b == SENTINEL).next().unwrap_or_default();
+            if keys == 0 || key != last.as_slice() {
+                keys += 1;
+                last.clear();
+                last.extend_from_slice(key);
+            }
+            set.insert(entry)?;
+        }
+        set.finish()?;
+        return BuildSummary::new(&counts, keys, &meta, output, options, started);
+    }
+
+    let mut map = MapBuilder::new(wtr)?;
+    let mut store = ValueStoreWriter::default();
+    for entry in store.stamp(&meta) {
+        map.insert(entry, 0)?;
+    }
+    // the union yields every distinct key once, in order, with its value in each shard that
+    // has it. The smallest value is the first, which a single threaded build would keep
+    let mut union = maps
+        .iter()
+        .map(|(map, _)| map)
+        .collect::<map::OpBuilder>()
+        .union();
+    while let Some((key, found)) = union.next() {
+        let value = found
+            .iter()
+            .map(|found| maps[found.index].1[found.value as usize].as_slice())
+            .min()
+            .unwrap_or_default();
+        keys += 1;
+        map.insert(key, store.push(value)?)?;
+    }
+    map.finish()?;
+    store.save(&sidecar_path(output))?;
+    BuildSummary::new(&counts, keys, &meta, output, options, started)
+}
+
+/// Write the db at `db` to `output` with the json records of `input` added. A key already in
+/// the db gets the value of the last new record with it in place of its old values. The new
+/// keys are hashed, lowercased and normalized the db's way and their values compressed its
+/// way, so the result reads as if it had been built from all the records. The db's own entries
+/// are streamed across as they are
+pub fn update_fstsed<R>(
+    input: R,
+    keyspecs: &[KeySpec],
+    db: &Utf8PathBuf,
+    output: &Utf8PathBuf,
+    transform: Option<&ValueTransform>,
+    level: i32,
+) -> Result<(), Error>
+where
+    R: BufReadExt,
+{
+    let fst = unsafe {
+        mmap_fst(db.clone()).with_context(|| format!("Error opening fst database {db}"))?
+    };
+    let hasher = meta(&fst, META_SALT).map(|salt| KeyHasher::new(&salt));
+    let normalize = match meta(&fst, META_NORMALIZE) {
+        Some(steps) => str::from_utf8(&steps)?
+            .split(',')
+            .map(|step| {
+                KeyNormalization::from_str(step, false)
+                    .map_err(|_| anyhow!("unknown key normalization {step:?} in {db}"))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    let dict = meta(&fst, META_ZSTD_DICT);
+    let options = BuildOptions {
+        transform,
+        hasher: hasher.as_ref(),
+        key_type: meta(&fst, META_KEY_TYPE).and_then(|name| KeyType::from_name(&name)),
+        normalize: &normalize,
+        on_duplicate: Some(OnDuplicate::Last),
+        // leaves the new values uncompressed, to compress with the db's dictionary below
+        train_dict: dict.is_some(),
+        compression: if meta(&fst, META_COMPRESSION).is_some_and(|c| c == b"none") {
+            Compression::None
+        } else {
+            Compression::Zstd(level)
+        },
+        layout: Layout::of(&fst),
+        quiet: false,
+    };
+
+    let mut vals: Vec<Entry> = Vec::new();
+    let mut counts = Counts::default();
+    read_entries(input, keyspecs, &options, None, &mut vals, &mut counts)?;
+    counts.report(&options);
+    let mut vals = resolve_duplicates(vals, OnDuplicate::Last, &options)?;
+    if let (Some(dict), Compression::Zstd(level)) = (&dict, options.compression) {
+        let mut compressor = zstd::bulk::Compressor::with_dictionary(level, dict)?;
+        for (_, value) in vals.iter_mut() {
+            *value = compressor.compress(value)?;
+        }
+    }
+    // the longest key of a hashed db is the longest of the old and the new
+    if let Some(len) = meta(&fst, META_MAX_KEY_LEN) {
+        let len: usize = str::from_utf8(&len)?.parse()?;
+        counts.max_key_len = counts.max_key_len.max(len);
+    }
+    let meta = meta_entries(&options, &counts, dict.as_deref());
+
+    let wtr = io::BufWriter::new(File::create(output)?);
+    let mut added = 0;
+    let mut replaced = 0;
+    let mut new = vals.iter().peekable();
+    eprintln!("Merging {} keys into the fst...", vals.len());
+    match options.layout {
+        Layout::Sentinel => {
+            let mut set = SetBuilder::new(wtr)?;
+            set.extend_iter(&meta)?;
+            let mut skip: Option<&[u8]> = None;
+            let mut stream = fst.stream();
+            while let Some((entry, _)) = stream.next() {
+                // the metadata entries were written afresh above
+                if entry.first() == Some(&SENTINEL) {
+                    continue;
+                }
+                let key = entry.split(|&b| b == SENTINEL).next().unwrap_or_default();
+                // the new entries sorting before this key go first, and one for the same key
+                // takes the place of all of its entries
+                while let Some(next) = new.next_if(|(k, _)| k.as_slice() <= key) {
+                    if next.0 == key {
+                        replaced += 1;
+                        skip = Some(&next.0);
+                    } else {
+                        added += 1;
+                    }
+                    set.insert(sentinel_entry(next))?;
+                }
+                if skip != Some(key) {
+                    set.insert(entry)?;
+                }
+            }
+            for next in new {
+                added += 1;
+                set.insert(sentinel_entry(next))?;
+            }
+            set.finish()?;
+        }
+        Layout::Map => {
+            let store = ValueStore::open_for(&fst, &sidecar_path(db))?;
+            let mut map = MapBuilder::new(wtr)?;
+            let mut values = ValueStoreWriter::default();
+            for entry in values.stamp(&meta) {
+                map.insert(entry, 0)?;
+            }
+            let keys = Map::from(fst);
+            let mut stream = keys.stream();
+            while let Some((key, index)) = stream.next() {
+                if key.first() == Some(&SENTINEL) {
+                    continue;
+                }
+                let mut found = false;
+                while let Some((k, value)) = new.next_if(|(k, _)| k.as_slice() <= key) {
+                    if k == key {
+                        replaced += 1;
+                        found = true;
+                    } else {
+                        added += 1;
+                    }
+                    map.insert(k, values.push(value)?)?;
+                }
+                if !found {
+                    let value = store.get(index).ok_or_else(|| {
+                        anyhow!(
+                            "key {}: value {index} is missing from {}",
+                            key.escape_ascii(),
+                            sidecar_path(db)
+                        )
+                    })?;
+                    map.insert(key, values.push(value)?)?;
+                }
+            }
+            for (k, value) in new {
+                added += 1;
+                map.insert(k, values.push(value)?)?;
+            }
+            map.finish()?;
+            values.save(&sidecar_path(output))?;
+        }
+    }
+    eprintln!("Added {added} keys and replaced {replaced} keys of {db} into {output}");
+    Ok(())
 }
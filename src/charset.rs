@@ -0,0 +1,100 @@
+use crate::fstsed::at_boundary;
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+
+/// The shape of the keys of a db. Candidates whose leading token can't have that shape are
+/// skipped without walking the fst, which saves most of the work on prose-heavy input
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum TokenCharset {
+    /// Hex digits only, e.g. file hashes
+    Hex,
+    /// Ascii letters and digits only
+    Alnum,
+    /// Letters, digits, hyphens and dots, with at least one dot
+    Domain,
+    /// Ipv4 or ipv6 addresses: hex digits, dots and colons, with at least one dot or colon
+    Ip,
+}
+
+impl TokenCharset {
+    #[inline]
+    fn allows(self, b: u8) -> bool {
+        match self {
+            Self::Hex => b.is_ascii_hexdigit(),
+            Self::Alnum => b.is_ascii_alphanumeric(),
+            Self::Domain => b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_',
+            Self::Ip => b.is_ascii_hexdigit() || b == b'.' || b == b':',
+        }
+    }
+
+    /// Whether a key of this shape may start at the beginning of `text`. The run of allowed
+    /// bytes there is the longest key possible. When `bounded`, as with the default word
+    /// boundaries, hex and alnum keys must take up the whole run: what follows it must not be
+    /// a word character
+    #[inline]
+    pub fn admits(self, text: &[u8], bounded: bool) -> bool {
+        let run = text.iter().take_while(|&&b| self.allows(b)).count();
+        if run == 0 {
+            return false;
+        }
+        let token = &text[..run];
+        match self {
+            Self::Hex | Self::Alnum => !bounded || at_boundary(&text[run..]),
+            Self::Domain => token[0].is_ascii_alphanumeric() && token.contains(&b'.'),
+            Self::Ip => token.iter().any(|&b| b == b'.' || b == b':'),
+        }
+    }
+}
+
+/// What kind of keys a db holds, declared when it's built and recorded in its metadata so
+/// that searches pick the matching normalizations and candidate filters by themselves
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum KeyType {
+    /// Domain names: case-insensitive, and also matched as the parent of longer names. A match
+    /// can't stop before a further label, so evil.com isn't found in evil.com.au. Keys are
+    /// matched as given, not reduced to registrable domains by the public suffix list
+    Domain,
+    /// Ipv4 or ipv6 addresses, case-insensitive
+    Ip,
+    /// Hex digests such as md5 or sha256, case-insensitive
+    Hash,
+    /// Urls, matched as written
+    Url,
+    /// Anything else, matched as written
+    Text,
+}
+
+impl KeyType {
+    const ALL: [Self; 5] = [Self::Domain, Self::Ip, Self::Hash, Self::Url, Self::Text];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Domain => "domain",
+            Self::Ip => "ip",
+            Self::Hash => "hash",
+            Self::Url => "url",
+            Self::Text => "text",
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name().as_bytes() == name)
+    }
+
+    /// The candidate filter for keys of this type
+    pub fn charset(self) -> Option<TokenCharset> {
+        match self {
+            Self::Domain => Some(TokenCharset::Domain),
+            Self::Ip => Some(TokenCharset::Ip),
+            Self::Hash => Some(TokenCharset::Hex),
+            Self::Url | Self::Text => None,
+        }
+    }
+
+    /// Whether keys are stored in ascii lowercase and text is folded to match them
+    pub fn folds_case(self) -> bool {
+        matches!(self, Self::Domain | Self::Ip | Self::Hash)
+    }
+}
@@ -0,0 +1,116 @@
+use crate::fstsed::{decompress, mmap_fst, ValueCodec, SENTINEL};
+use crate::ttl::Expiry;
+use crate::valuestore::{sidecar_path, Layout, ValueStore, ValueStoreWriter};
+use anyhow::{anyhow, bail, Context, Result};
+use camino::Utf8PathBuf;
+use fst::{IntoStreamer, Map, MapBuilder, SetBuilder, Streamer};
+use std::fs::File;
+use std::io;
+
+/// Rebuild a fstsed database without its expired entries. Entries are copied over as they are,
+/// so this is much faster than building from the source records again
+#[derive(clap::Args, Debug)]
+pub struct CompactArgs {
+    /// The fst db to compact
+    #[clap(short = 'f', value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    fst: Utf8PathBuf,
+
+    /// Where to write the compacted fst db. Must not exist yet
+    #[clap(short = 'o', long, value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    output: Utf8PathBuf,
+
+    /// Field name or jsonpointer of the records' expiration time
+    #[clap(long, value_name = "FIELD", default_value = "expires")]
+    ttl_field: String,
+}
+
+pub fn run_compact(args: &CompactArgs) -> Result<()> {
+    if args.output.exists() {
+        bail!(
+            "fst path {} already exists. Please specify an alternate path or rename/delete existing fst.",
+            args.output
+        );
+    }
+    let fst = unsafe {
+        mmap_fst(args.fst.clone())
+            .with_context(|| format!("Error opening fst database {}", args.fst))?
+    };
+    let expiry = Expiry::new(&args.ttl_field);
+    let codec = ValueCodec::of(&fst);
+    let layout = Layout::of(&fst);
+
+    let wtr = io::BufWriter::new(File::create(&args.output)?);
+    let mut kept: u64 = 0;
+    let mut dropped: u64 = 0;
+
+    if layout == Layout::Map {
+        let store_path = sidecar_path(&args.fst);
+        let store = ValueStore::open_for(&fst, &store_path)?;
+        let mut map = MapBuilder::new(wtr)?;
+        let mut values = ValueStoreWriter::default();
+        let keys = Map::from(fst);
+        // metadata entries carry over as they are, but for the id of the new value store
+        let meta = keys
+            .range()
+            .lt([SENTINEL + 1])
+            .into_stream()
+            .into_byte_keys();
+        for entry in values.stamp(&meta) {
+            map.insert(entry, 0)?;
+        }
+        let mut stream = keys.stream();
+        while let Some((key, index)) = stream.next() {
+            if key.first() == Some(&SENTINEL) {
+                continue;
+            }
+            let compressed = store.get(index).ok_or_else(|| {
+                anyhow!(
+                    "key {}: value {index} is missing from {store_path}",
+                    key.escape_ascii()
+                )
+            })?;
+            let value = decompress(compressed, &codec).with_context(|| {
+                format!("key {}: can't decompress its value", key.escape_ascii())
+            })?;
+            if expiry.is_expired(&value) {
+                dropped += 1;
+                continue;
+            }
+            map.insert(key, values.push(compressed)?)?;
+            kept += 1;
+        }
+        map.finish()?;
+        values.save(&sidecar_path(&args.output))?;
+        eprintln!(
+            "Kept {kept} entries and dropped {dropped} expired entries into {}",
+            args.output
+        );
+        return Ok(());
+    }
+
+    let mut set = SetBuilder::new(wtr)?;
+    // entries come out of the stream sorted, so they can go straight into the builder
+    let mut stream = fst.stream();
+    while let Some((entry, _)) = stream.next() {
+        let compressed = match entry.iter().position(|&b| b == SENTINEL) {
+            Some(i) => &entry[i + 1..],
+            None => &[][..],
+        };
+        let key = entry.split(|&b| b == SENTINEL).next().unwrap_or_default();
+        let value = decompress(compressed, &codec)
+            .with_context(|| format!("key {}: can't decompress its value", key.escape_ascii()))?;
+        if expiry.is_expired(&value) {
+            dropped += 1;
+            continue;
+        }
+        set.insert(entry)?;
+        kept += 1;
+    }
+    set.finish()?;
+
+    eprintln!(
+        "Kept {kept} entries and dropped {dropped} expired entries into {}",
+        args.output
+    );
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// User configuration, read from $FSTSED_CONFIG or else config.toml in the fstsed directory
+/// of $XDG_CONFIG_HOME (~/.config). A missing file is the same as an empty one.
+///
+/// ```toml
+/// [defaults]
+/// fst = "/srv/intel/iocs.fst"
+/// template = "@triage"
+/// boundaries = "unicode"
+/// color = "always"
+/// threads = 4
+///
+/// [templates]
+/// triage = "{key} [{actor|default:unattributed}]"
+/// redact = "<redacted {type}>"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Defaults for options not given on the command line
+    #[serde(default)]
+    pub defaults: Defaults,
+    /// Named templates, selected with -t @name
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+}
+
+/// The [defaults] table. Each one only applies when its option isn't given on the command
+/// line, so a team can share settings without long shell aliases
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    /// The fst db searched without -f or $FSTSED_DB. Never used as the output of --build
+    pub fst: Option<String>,
+    /// The template without -t or $FSTSED_TEMPLATE, which may name one of the [templates] as
+    /// @name
+    pub template: Option<String>,
+    /// Where matches may start without --unicode or --no-boundary
+    pub boundaries: Option<Boundaries>,
+    /// auto, always or never, without -C
+    pub color: Option<String>,
+    /// Threads for the default decorating mode without --threads. Runs in other modes, or
+    /// with a template that numbers matches, stay single threaded
+    pub threads: Option<usize>,
+}
+
+/// The boundary set of [defaults]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Boundaries {
+    /// After ascii delimiters, as without any flag
+    Ascii,
+    /// After any unicode non-word character, as --unicode
+    Unicode,
+    /// Anywhere, as --no-boundary
+    Off,
+}
+
+impl Config {
+    /// Where the config file is expected to be, if there is a home directory to look in
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("FSTSED_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(base.join("fstsed").join("config.toml"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("Error reading config file {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(e).with_context(|| format!("Error reading config file {}", path.display()))
+            }
+        }
+    }
+
+    /// Look up a named template from the [templates] table
+    pub fn template(&self, name: &str) -> Result<&str> {
+        self.templates.get(name).map(String::as_str).ok_or_else(|| {
+            let mut known: Vec<&str> = self.templates.keys().map(String::as_str).collect();
+            known.sort_unstable();
+            anyhow!(
+                "no template named {name:?} in the config file, known templates: {}",
+                if known.is_empty() {
+                    "(none)".to_string()
+                } else {
+                    known.join(", ")
+                }
+            )
+        })
+    }
+}
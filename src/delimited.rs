@@ -0,0 +1,154 @@
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+/// A column picked with --columns: a 1-based position or a header name
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Column {
+    Index(usize),
+    Name(String),
+}
+
+/// The layout of delimited input: its field separator, and which columns are searched.
+/// Records are one per line; a quoted field can't span lines
+pub struct Delimited {
+    delimiter: u8,
+    columns: Vec<Column>,
+    /// The 0-based positions of the columns, once known. None means every column
+    selected: Option<Vec<usize>>,
+}
+
+impl Delimited {
+    /// `columns` is a comma separated list of 1-based positions and header names, e.g.
+    /// "3,7" or "url,referrer". An empty list searches every column
+    pub fn new(delimiter: u8, columns: &str) -> Result<Self> {
+        let mut parsed = Vec::new();
+        for column in columns.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            parsed.push(match column.parse::<usize>() {
+                Ok(0) => bail!("--columns counts from 1, got 0"),
+                Ok(n) => Column::Index(n),
+                Err(_) => Column::Name(column.to_string()),
+            });
+        }
+        let selected = if parsed.is_empty() {
+            None
+        } else if parsed.iter().all(|c| matches!(c, Column::Index(_))) {
+            Some(Self::indexes(&parsed, &[])?)
+        } else {
+            Some(Vec::new())
+        };
+        Ok(Self {
+            delimiter,
+            columns: parsed,
+            selected,
+        })
+    }
+
+    /// Whether the first line of each input is a header, which is the case when columns are
+    /// picked by name. The header is copied through and never searched
+    pub fn has_header(&self) -> bool {
+        self.columns.iter().any(|c| matches!(c, Column::Name(_)))
+    }
+
+    /// Resolve the column names against the header line of an input
+    pub fn read_header(&mut self, line: &[u8]) -> Result<()> {
+        let names: Vec<Cow<'_, [u8]>> = self
+            .fields(line)
+            .into_iter()
+            .map(|(start, end)| unquote(&line[start..end]))
+            .collect();
+        self.selected = Some(Self::indexes(&self.columns, &names)?);
+        Ok(())
+    }
+
+    fn indexes(columns: &[Column], names: &[Cow<'_, [u8]>]) -> Result<Vec<usize>> {
+        columns
+            .iter()
+            .map(|column| match column {
+                Column::Index(n) => Ok(n - 1),
+                Column::Name(name) => match names.iter().position(|n| n[..] == *name.as_bytes()) {
+                    Some(i) => Ok(i),
+                    None => bail!("column {name:?} is not in the header"),
+                },
+            })
+            .collect()
+    }
+
+    /// Whether the field at 0-based position `index` is searched
+    #[inline]
+    pub fn searched(&self, index: usize) -> bool {
+        self.selected
+            .as_ref()
+            .is_none_or(|selected| selected.contains(&index))
+    }
+
+    /// The byte ranges of the fields of a line without its terminator, quotes included.
+    /// A quote only opens a quoted field at the start of the field
+    pub fn fields(&self, line: &[u8]) -> Vec<(usize, usize)> {
+        let mut fields = Vec::new();
+        let mut start = 0;
+        let mut quoted = false;
+        for (i, &b) in line.iter().enumerate() {
+            if b == b'"' && (quoted || i == start) {
+                quoted = !quoted;
+            } else if b == self.delimiter && !quoted {
+                fields.push((start, i));
+                start = i + 1;
+            }
+        }
+        fields.push((start, line.len()));
+        fields
+    }
+
+    /// Write a field value, quoting it if it holds the delimiter, a quote or a line break.
+    /// Quotes inside a quoted field are doubled
+    pub fn write_field<W: Write>(&self, out: &mut W, value: &[u8]) -> io::Result<()> {
+        if !value
+            .iter()
+            .any(|&b| b == self.delimiter || b == b'"' || b == b'\n' || b == b'\r')
+        {
+            return out.write_all(value);
+        }
+        out.write_all(b"\"")?;
+        for (i, part) in value.split(|&b| b == b'"').enumerate() {
+            if i > 0 {
+                out.write_all(b"\"\"")?;
+            }
+            out.write_all(part)?;
+        }
+        out.write_all(b"\"")
+    }
+}
+
+/// The value of a raw field: without its surrounding quotes and with doubled quotes undone
+pub fn unquote(field: &[u8]) -> Cow<'_, [u8]> {
+    match field {
+        [b'"', inner @ .., b'"'] => {
+            if inner.contains(&b'"') {
+                let mut value = Vec::with_capacity(inner.len());
+                let mut quote = false;
+                for &b in inner {
+                    if b == b'"' && quote {
+                        quote = false;
+                        continue;
+                    }
+                    quote = b == b'"';
+                    value.push(b);
+                }
+                Cow::Owned(value)
+            } else {
+                Cow::Borrowed(inner)
+            }
+        }
+        _ => Cow::Borrowed(field),
+    }
+}
+
+/// The line without its \n or \r\n terminator. Unlike trimming whitespace, this keeps the
+/// empty last field of a line ending in a tab
+pub fn strip_terminator(line: &[u8]) -> &[u8] {
+    match line.strip_suffix(b"\n") {
+        Some(line) => line.strip_suffix(b"\r").unwrap_or(line),
+        None => line,
+    }
+}
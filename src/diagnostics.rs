@@ -0,0 +1,89 @@
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// The error channel is process wide so warnings can be raised from deep inside the build and
+// match code without threading a writer through every call. None means no --errors was given
+lazy_static! {
+    static ref CHANNEL: Mutex<Option<File>> = Mutex::new(None);
+}
+
+// While muted, warnings are dropped rather than reported anywhere
+static MUTED: AtomicBool = AtomicBool::new(false);
+
+/// Drop or report warnings from now on, returning whether they were dropped before. For work
+/// that is done again for real, so its warnings would be reported twice
+pub fn set_muted(muted: bool) -> bool {
+    MUTED.swap(muted, Ordering::Relaxed)
+}
+
+/// Open the structured error channel. The spec is either a file path, which is created (or
+/// truncated), or a bare number naming an already open file descriptor, e.g. 3 for `3>errs.json`
+pub fn open_channel(spec: &str) -> Result<()> {
+    let file = match spec.parse::<i32>() {
+        Ok(fd) => from_fd(fd)?,
+        Err(_) => File::create(spec).with_context(|| format!("creating error channel {spec}"))?,
+    };
+    *CHANNEL.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn from_fd(fd: i32) -> Result<File> {
+    use std::os::fd::BorrowedFd;
+    // the user promises the descriptor is open for writing, as with any shell redirection. The
+    // channel writes to a duplicate, so closing it leaves the descriptor open for the next
+    // recipe job, or for stderr itself with --errors 2
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let owned = borrowed
+        .try_clone_to_owned()
+        .with_context(|| format!("opening error channel on file descriptor {fd}"))?;
+    Ok(File::from(owned))
+}
+
+#[cfg(not(unix))]
+fn from_fd(fd: i32) -> Result<File> {
+    anyhow::bail!("writing the error channel to file descriptor {fd} is only supported on unix")
+}
+
+/// Write one ndjson record to the channel if it is open. Returns whether it was written
+fn emit(kind: &str, message: &dyn Display, fields: Value) -> bool {
+    if MUTED.load(Ordering::Relaxed) {
+        return true;
+    }
+    let mut channel = CHANNEL.lock().unwrap();
+    let Some(file) = channel.as_mut() else {
+        return false;
+    };
+    let mut record = Map::new();
+    record.insert("level".into(), "warning".into());
+    record.insert("kind".into(), kind.into());
+    record.insert("message".into(), message.to_string().into());
+    if let Value::Object(extra) = fields {
+        record.extend(extra);
+    }
+    let mut line = serde_json::to_vec(&record).unwrap_or_default();
+    line.push(b'\n');
+    // nowhere left to complain to if the error channel itself fails
+    let _ = file.write_all(&line);
+    true
+}
+
+/// Report a warning to the error channel, or as plain text on stderr without one. `fields` is
+/// a json object of extra context (file, line, key...) merged into the record
+pub fn warn(kind: &str, message: impl Display, fields: Value) {
+    if !emit(kind, &message, fields) {
+        eprintln!("fstsed: {message}");
+    }
+}
+
+/// Report a warning only to the error channel. For per-record problems that would flood
+/// stderr and are otherwise just tallied or replaced with a placeholder
+pub fn warn_quiet(kind: &str, message: impl Display, fields: Value) {
+    emit(kind, &message, fields);
+}
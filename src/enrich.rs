@@ -0,0 +1,77 @@
+use crate::fstsed::FstSed;
+use crate::timings::{self, Phase};
+use serde_json::Value;
+use std::io::{self, Write};
+
+/// Collect the distinct matches in the json strings of `text` as (key, entry) pairs, decoding
+/// the value of each new key once. The entry is the match's member of the enrichment array
+#[inline]
+pub fn collect_enrichment(text: &[u8], fsed: &FstSed, found: &mut Vec<(String, Value)>) {
+    for (start, end) in fsed.json_strings(text) {
+        let started = timings::start();
+        let decoded = serde_json::from_slice::<String>(&text[start..end]);
+        timings::record(Phase::JsonParse, started);
+        if let Ok(s) = decoded {
+            collect_text_enrichment(s.as_bytes(), fsed, found);
+        }
+    }
+}
+
+/// Collect the distinct matches in plain `text`, like collect_enrichment does in json strings
+#[inline]
+pub fn collect_text_enrichment(text: &[u8], fsed: &FstSed, found: &mut Vec<(String, Value)>) {
+    for m in fsed.find_iter(text) {
+        // only decompress the first occurrence of each key
+        if found.iter().any(|(k, _)| k.as_bytes() == m.key()) {
+            continue;
+        }
+        let m = fsed.get_match(&m);
+        let value: Value =
+            serde_json::from_str(m.value()).unwrap_or_else(|_| Value::from(m.value()));
+        let mut entry = serde_json::json!({ "key": m.key(), "value": value });
+        if let Some(link) = m.link() {
+            entry["link"] = link.into();
+        }
+        found.push((m.key().to_string(), entry));
+    }
+}
+
+/// The enrichment array for the collected matches
+pub fn enrichment_array(found: &[(String, Value)]) -> Value {
+    found.iter().map(|(_, entry)| entry.clone()).collect()
+}
+
+/// Write the line with the enrichment array spliced in as the last member of its json object,
+/// whose closing brace is the last byte of `line[..body_len]`. The original bytes are kept
+pub fn splice_enrichment<W: Write>(
+    out: &mut W,
+    line: &[u8],
+    body_len: usize,
+    found: &[(String, Value)],
+) -> io::Result<()> {
+    let closing = body_len - 1;
+    out.write_all(&line[..closing])?;
+    // any string match means the object has at least one member already
+    out.write_all(b",\"fstsed\":")?;
+    serde_json::to_writer(&mut *out, &enrichment_array(found))?;
+    out.write_all(&line[closing..])
+}
+
+/// Enrich one ndjson line as --enrich does by default. Lines that aren't json objects, or
+/// have no matches, are written unchanged
+pub fn enrich_line<W: Write>(
+    out: &mut W,
+    line: &[u8],
+    fsed: &FstSed,
+    found: &mut Vec<(String, Value)>,
+) -> io::Result<()> {
+    found.clear();
+    let body = line.trim_ascii_end();
+    let is_object = body.trim_ascii_start().starts_with(b"{") && body.ends_with(b"}");
+    collect_enrichment(line, fsed, found);
+    if found.is_empty() || !is_object {
+        out.write_all(line)
+    } else {
+        splice_enrichment(out, line, body.len(), found)
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+
+/// Column layouts of well known delimited indicator feeds, for building without a conversion
+/// script. Each names its key column "key"
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum FeedPreset {
+    /// abuse.ch URLhaus csv exports, keyed by url
+    Urlhaus,
+    /// abuse.ch ThreatFox csv exports, keyed by ioc value
+    Threatfox,
+    /// abuse.ch Feodo Tracker botnet C2 ip blocklist csv, keyed by ip
+    Feodo,
+    /// AlienVault ip reputation data, # separated, keyed by ip
+    Alienvault,
+}
+
+impl FeedPreset {
+    /// The field separator of the feed
+    pub fn delimiter(&self) -> u8 {
+        match self {
+            Self::Alienvault => b'#',
+            Self::Urlhaus | Self::Threatfox | Self::Feodo => b',',
+        }
+    }
+
+    /// Which column goes in which field of the records, in --map syntax
+    pub fn map(&self) -> &'static str {
+        match self {
+            Self::Urlhaus => {
+                "id=1,date_added=2,key=3,url_status=4,last_online=5,threat=6,tags=7,reference=8,\
+                 reporter=9"
+            }
+            Self::Threatfox => {
+                "first_seen=1,id=2,key=3,ioc_type=4,threat_type=5,malware_alias=7,malware=8,\
+                 last_seen=9,confidence_level=10,reference=11,tags=12,reporter=14"
+            }
+            Self::Feodo => "first_seen=1,key=2,port=3,status=4,last_online=5,malware=6",
+            Self::Alienvault => "key=1,reliability=2,risk=3,activity=4,country=5,city=6,location=7",
+        }
+    }
+
+    pub fn column_map(&self) -> ColumnMap {
+        ColumnMap::parse(self.map(), self.delimiter()).expect("presets are valid maps")
+    }
+}
+
+/// Which column of a delimited feed goes in which field of the json record built from each
+/// of its lines
+pub struct ColumnMap {
+    delimiter: u8,
+    /// Field names with the 0-based position of their column
+    fields: Vec<(String, usize)>,
+}
+
+impl ColumnMap {
+    /// `spec` is a comma separated list of NAME=COLUMN, COLUMN a 1-based position, e.g.
+    /// "key=1,first_seen=3"
+    pub fn parse(spec: &str, delimiter: u8) -> Result<Self> {
+        let mut fields = Vec::new();
+        for mapping in spec.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            let Some((name, column)) = mapping.split_once('=') else {
+                bail!("--map wants NAME=COLUMN, got {mapping:?}");
+            };
+            let column: usize = column
+                .trim()
+                .parse()
+                .with_context(|| format!("--map column of {name:?} must be a number"))?;
+            if column == 0 {
+                bail!("--map counts columns from 1, got 0 for {name:?}");
+            }
+            fields.push((name.trim().to_string(), column - 1));
+        }
+        if fields.is_empty() {
+            bail!("--map needs at least one NAME=COLUMN");
+        }
+        Ok(Self { delimiter, fields })
+    }
+
+    /// The records of delimited `input`, one json object per line. Blank lines and comment
+    /// lines starting with #, where feeds keep their notes and header, are skipped, as are
+    /// empty fields
+    pub fn records(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut records = Vec::with_capacity(input.len() * 2);
+        for line in input.split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.trim_ascii().is_empty() || line.starts_with(b"#") {
+                continue;
+            }
+            let columns = split_fields(line, self.delimiter);
+            let record: Map<String, Value> = self
+                .fields
+                .iter()
+                .filter_map(|(name, i)| {
+                    let value = columns.get(*i).filter(|v| !v.is_empty())?;
+                    Some((name.clone(), Value::from(value.as_str())))
+                })
+                .collect();
+            serde_json::to_writer(&mut records, &record)?;
+            records.push(b'\n');
+        }
+        Ok(records)
+    }
+}
+
+// The unquoted, trimmed fields of a line. Unlike --csv input, a quote may open a field after
+// spaces, as in the ", "-separated ThreatFox exports
+fn split_fields(line: &[u8], delimiter: u8) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    let mut quoted = false;
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'"' if quoted => {
+                // a doubled quote is a literal one
+                if bytes.next_if_eq(&b'"').is_some() {
+                    field.push(b'"');
+                } else {
+                    quoted = false;
+                }
+            }
+            b'"' if field.trim_ascii().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            _ if b == delimiter && !quoted => {
+                fields.push(String::from_utf8_lossy(field.trim_ascii()).into_owned());
+                field.clear();
+            }
+            _ => field.push(b),
+        }
+    }
+    fields.push(String::from_utf8_lossy(field.trim_ascii()).into_owned());
+    fields
+}
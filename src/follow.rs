@@ -0,0 +1,62 @@
+use camino::Utf8PathBuf;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::thread::sleep;
+use std::time::Duration;
+
+// how long to wait for a followed file to grow before checking it again
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A reader that never reaches the end of a file, like `tail -f`. At the end it waits for more
+/// to be written. A file truncated in place is read again from the start, and one that was
+/// rotated away is drained and then replaced by the new file at the same path
+pub struct Follow {
+    path: Utf8PathBuf,
+    file: File,
+    pos: u64,
+}
+
+impl Follow {
+    pub fn open(path: Utf8PathBuf) -> io::Result<Self> {
+        let file = File::open(&path)?;
+        Ok(Self { path, file, pos: 0 })
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+// without inode numbers rotation can't be told apart from truncation, which is handled by size
+#[cfg(not(unix))]
+fn same_file(_: &Metadata, _: &Metadata) -> bool {
+    true
+}
+
+impl Read for Follow {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            // everything written so far has been read. only now check the path, so the tail of
+            // a rotated file isn't lost
+            match fs::metadata(&self.path) {
+                Ok(meta) if !same_file(&meta, &self.file.metadata()?) => {
+                    self.file = File::open(&self.path)?;
+                    self.pos = 0;
+                }
+                Ok(meta) if meta.len() < self.pos => {
+                    self.file.seek(SeekFrom::Start(0))?;
+                    self.pos = 0;
+                }
+                // unchanged, or moved away and not recreated yet
+                _ => sleep(POLL_INTERVAL),
+            }
+        }
+    }
+}
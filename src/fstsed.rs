@@ -1,32 +1,175 @@
-use anyhow::{Error, Result};
+use crate::charset::{KeyType, TokenCharset};
+#[cfg(feature = "json")]
+use crate::diagnostics;
+use crate::hashed::{KeyHasher, META_MAX_KEY_LEN, META_SALT};
+
+/// Name of the metadata entry holding the declared KeyType of the db
+pub const META_KEY_TYPE: &str = "key_type";
+/// Name of the metadata entry holding the zstd dictionary the values are compressed with
+pub const META_ZSTD_DICT: &str = "zstd_dict";
+/// Name of the metadata entry saying how values are stored, present when they aren't zstd
+/// compressed
+pub const META_COMPRESSION: &str = "compression";
+/// Name of the metadata entry listing the --normalize-keys steps the keys went through
+pub const META_NORMALIZE: &str = "normalize";
+#[cfg(feature = "json")]
+use crate::jsonquotes::{jsonquotes_range_iter, JsonFields, JsonValues};
+use crate::stats::{self, Stat};
+#[cfg(feature = "color")]
+use crate::style::ColorStyles;
+use crate::template::{Context, Template};
+use crate::timings::{self, Phase};
+#[cfg(feature = "json")]
+use crate::ttl::Expiry;
+use crate::valuestore::Layout;
+#[cfg(feature = "values")]
+use crate::valuestore::{sidecar_path, ValueStore};
+use anyhow::{Context as _, Error, Result};
 use camino::Utf8PathBuf;
-use fst::raw::Fst;
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use fst::raw::{CompiledAddr, Fst, Node, Output};
+use fst::{IntoStreamer, Streamer};
+#[cfg(feature = "unicode")]
 use lazy_static::lazy_static;
+use lru::LruCache;
 use memmap2::Mmap;
-use microtemplate::{render, Context};
+#[cfg(feature = "unicode")]
 use regex::bytes::Regex;
+#[cfg(feature = "json")]
 use serde_json::Value;
-use std::cell::RefCell;
+use sha2::Digest;
+use std::borrow::Cow;
 use std::fs::File;
+#[cfg(feature = "values")]
+use std::io;
 use std::iter::Peekable;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+#[cfg(feature = "color")]
 use termcolor::ColorChoice;
+#[cfg(feature = "values")]
+use zstd::dict::DecoderDictionary;
 
-const SENTINEL: u8 = 0;
+pub const SENTINEL: u8 = 0;
 
-// RE_START and RE_NONWORD are used to find candidate positions
-// to evaluate for fst keyword matches
-// Note how these disable unicode matching (?i-u). key perf improvement
-lazy_static! {
-    static ref RE_START: Regex = Regex::new(r"(?i-u)^").unwrap();
+// how far past a token start to look for hashed keys when the db doesn't record its longest key
+const DEFAULT_MAX_KEY_LEN: usize = 256;
+
+// number of leading candidate bytes that identify a cached fst miss
+const MISS_PREFIX: usize = 8;
+
+/// Key of the negative-result cache: the first MISS_PREFIX bytes of a candidate and how many
+/// of them there were, in case the haystack ended sooner
+type MissKey = ([u8; MISS_PREFIX], usize);
+
+#[inline]
+fn miss_key(value: &[u8]) -> MissKey {
+    let n = value.len().min(MISS_PREFIX);
+    let mut key = [0; MISS_PREFIX];
+    key[..n].copy_from_slice(&value[..n]);
+    (key, n)
 }
-lazy_static! {
-    static ref RE_NONWORD: Regex = Regex::new(r#"(?m)(?i-u)[, \t\a\n:="]"#).unwrap();
+
+// Candidate positions to evaluate for fst keyword matches are the start of the text and the
+// position after each boundary byte in these tables. A table lookup per byte finds a handful
+// of ascii delimiters as fast as a regex would
+const fn byte_table(bytes: &[u8]) -> [bool; 256] {
+    let mut table = [false; 256];
+    let mut i = 0;
+    while i < bytes.len() {
+        table[bytes[i] as usize] = true;
+        i += 1;
+    }
+    table
 }
-// RE_UNICODE_BOUNDARY is used within the fstmatch algorithm to validate
-// that the end of the match is a boundary and therefore we are not inside
-// a word
+static NONWORD: [bool; 256] = byte_table(b", \t\x07\n:=\"");
+// NONWORD_DOT adds the dots between domain labels, so that keys also match as the parent
+// domains of longer names
+static NONWORD_DOT: [bool; 256] = byte_table(b", \t\x07\n:=\".");
+// ANYBYTE makes every position a candidate, for matching keys inside of larger tokens
+static ANYBYTE: [bool; 256] = [true; 256];
+// ASCII_NONWORD marks the ascii bytes that aren't word characters. The unicode boundaries look
+// bytes up here and only decode the characters beyond ascii, which is most of the speed of a
+// scan over mostly ascii text
+static ASCII_NONWORD: [bool; 256] = {
+    let mut table = [false; 256];
+    let mut b = 0;
+    while b < 0x80 {
+        table[b] = !((b as u8).is_ascii_alphanumeric() || b == b'_' as usize);
+        b += 1;
+    }
+    table
+};
+// RE_GRAPHEME_EXTEND and RE_GRAPHEME_JOINED find the characters that belong to the character
+// before or after them: combining marks, variation selectors, skin tones, zero width joiners
+// and the second of a pair of regional indicators (a flag)
+#[cfg(feature = "unicode")]
 lazy_static! {
-    static ref RE_UNICODE_BOUNDARY: Regex = Regex::new(r"^\W").unwrap();
+    static ref RE_GRAPHEME_EXTEND: Regex =
+        Regex::new(r"^[\p{gcb=Extend}\p{gcb=ZWJ}\p{gcb=SpacingMark}]").unwrap();
+    static ref RE_GRAPHEME_JOINED: Regex = Regex::new(r"(?:\p{gcb=ZWJ}|\p{gcb=RI})$").unwrap();
+    static ref RE_REGIONAL_INDICATOR: Regex = Regex::new(r"^\p{gcb=RI}").unwrap();
+}
+
+// The character at the start of `text`, if it is a valid one
+fn first_char(text: &[u8]) -> Option<char> {
+    let len = match *text.first()? {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => return None,
+    };
+    std::str::from_utf8(text.get(..len)?).ok()?.chars().next()
+}
+
+// Word characters are those of the regex crate's unicode \w
+#[cfg(feature = "unicode")]
+fn is_word_char(c: char) -> bool {
+    regex_syntax::is_word_character(c)
+}
+
+// Without the unicode feature, word characters are those std calls alphanumeric, _ and the
+// common joining characters. Close to the regex crate's \w, though rarer marks aren't word
+// characters and other numerals are
+#[cfg(not(feature = "unicode"))]
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || is_joining(c)
+}
+
+// Without the unicode feature, the characters that join the one before them: the combining
+// diacritical mark blocks, variation selectors, emoji skin tones and the zero width joiner
+#[cfg(not(feature = "unicode"))]
+fn is_joining(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036F}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{200D}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FE20}'..='\u{FE2F}'
+            | '\u{1F3FB}'..='\u{1F3FF}'
+    )
+}
+
+/// Where the rendered template goes relative to the matched text
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Decoration {
+    /// Insert the rendered template ahead of the matched text
+    Before,
+    /// Insert the rendered template right after the matched text
+    After,
+    /// Substitute the rendered template for the matched text
+    Replace,
+    /// Substitute the rendered template for the matched text. The default template
+    /// "<{key}|{value}>" keeps the matched text inside the decoration
+    #[default]
+    Wrap,
 }
 
 /// FstMatch represents a single match of a fst key in a haystack
@@ -37,36 +180,351 @@ lazy_static! {
 pub struct FstMatch<'f> {
     //start: usize,
     key: String,
+    matched: String,
     value: String,
-    template: &'f str,
+    template: &'f Template,
+    decoration: Decoration,
+    #[cfg(feature = "json")]
     jsonvalue: Option<Value>,
+    line_score: Option<f64>,
+    match_id: Option<u64>,
+    normalized_by: Option<&'static str>,
+    link: Option<&'f Template>,
+    hyperlink: bool,
 }
 
 impl<'f> FstMatch<'f> {
+    /// Render the text that takes the place of the match in the output. Before and after
+    /// decorations keep the matched text next to the template
     pub fn render(&self) -> String {
-        render(self.template, self)
+        let started = timings::start();
+        let rendered = self.template.render(self);
+        let rendered = match self.decoration {
+            Decoration::Before => rendered + &self.matched,
+            Decoration::After => self.matched.clone() + &rendered,
+            Decoration::Replace | Decoration::Wrap => rendered,
+        };
+        let rendered = match self.link().filter(|_| self.hyperlink) {
+            // an OSC 8 escape makes the whole decoration a terminal hyperlink to the url
+            Some(url) => format!("\x1b]8;;{url}\x1b\\{rendered}\x1b]8;;\x1b\\"),
+            None => rendered,
+        };
+        timings::record(Phase::Render, started);
+        rendered
+    }
+
+    /// The match's pivot url, its --link-template rendered. None without a link template or
+    /// when it renders empty
+    pub fn link(&self) -> Option<String> {
+        let url = self.link?.render(self);
+        (!url.is_empty()).then_some(url)
+    }
+
+    /// Make the summed --score of the match's line available to the template as {line_score}
+    pub fn with_line_score(mut self, line_score: Option<f64>) -> Self {
+        self.line_score = line_score;
+        self
+    }
+
+    /// Make the number of the match in the run, from FstSed::next_match_id, available to the
+    /// template as {match_id}
+    pub fn with_match_id(mut self, match_id: u64) -> Self {
+        self.match_id = Some(match_id);
+        self
+    }
+
+    /// The fst key that matched
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The text of the input that matched, verbatim. This is the key as it was spelled in the
+    /// input, which may differ from the canonical key when matching normalizes the input
+    pub fn matched(&self) -> &str {
+        &self.matched
+    }
+
+    /// The decompressed value stored with the key. Always empty without the values feature
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// How the matched text was normalized to find the key, see Match::normalized_by
+    pub fn normalized_by(&self) -> Option<&'static str> {
+        self.normalized_by
     }
 }
 
-impl Context for &FstMatch<'_> {
-    fn get_field(&self, field_name: &str) -> &str {
+#[cfg(feature = "json")]
+/// Text form of a json value for templates. Strings are unquoted, null is empty, arrays of
+/// scalars are joined with commas and any other array or object is written as json
+pub(crate) fn value_text(value: &Value) -> Cow<'_, str> {
+    match value {
+        Value::Null => Cow::Borrowed(""),
+        Value::String(s) => Cow::Borrowed(s),
+        Value::Bool(_) | Value::Number(_) => Cow::Owned(value.to_string()),
+        Value::Array(items) if !items.iter().any(|v| v.is_array() || v.is_object()) => {
+            Cow::Owned(items.iter().map(value_text).collect::<Vec<_>>().join(","))
+        }
+        Value::Array(_) | Value::Object(_) => Cow::Owned(value.to_string()),
+    }
+}
+
+impl Context for FstMatch<'_> {
+    fn get_field(&self, field_name: &str) -> Cow<'_, str> {
         match field_name {
-            "key" => self.key.as_str(),
-            "value" => self.value.as_str(),
-            _ => {
-                self.jsonvalue
-                    .as_ref()
-                    .and_then(|jv| {
-                        if field_name.starts_with('/') {
-                            // Field name starts with "/", use JSON pointer
-                            jv.pointer(field_name)
-                        } else {
-                            // Just regular field retrieval
-                            jv.get(field_name)
+            "key" => Cow::Borrowed(&self.key),
+            "match" => Cow::Borrowed(&self.matched),
+            "value" => Cow::Borrowed(&self.value),
+            "line_score" => self
+                .line_score
+                .map_or(Cow::Borrowed(""), |score| Cow::Owned(score.to_string())),
+            "match_id" => self
+                .match_id
+                .map_or(Cow::Borrowed(""), |id| Cow::Owned(id.to_string())),
+            "normalized_by" => Cow::Borrowed(self.normalized_by.unwrap_or_default()),
+            // the form of the text the key was found by, i.e. the key itself
+            "normalized" if self.normalized_by.is_some() => Cow::Borrowed(&self.key),
+            "normalized" => Cow::Borrowed(""),
+            #[cfg(not(feature = "json"))]
+            _ => Cow::Borrowed(""),
+            #[cfg(feature = "json")]
+            _ => self
+                .jsonvalue
+                .as_ref()
+                .and_then(|jv| {
+                    if field_name.starts_with('/') {
+                        // Field name starts with "/", use JSON pointer
+                        jv.pointer(field_name)
+                    } else {
+                        // Just regular field retrieval
+                        jv.get(field_name)
+                    }
+                })
+                .map(value_text)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Match is a match found by the fst walk: its span in the searched text, the key, and the
+/// value. It owns its data rather than leaving it in the FstSed, so that one FstSed can be
+/// searched from many threads at once. The value stays compressed until it's asked for with
+/// FstSed::value, and isn't read from the fst at all without the values feature
+pub struct Match {
+    /// Offset of the first byte of the match in the searched text
+    pub start: usize,
+    /// Offset just past the last byte of the match
+    pub end: usize,
+    key: Vec<u8>,
+    matched: Vec<u8>,
+    #[cfg(feature = "values")]
+    value: Vec<u8>,
+}
+
+impl Match {
+    /// The fst key that matched
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The text that matched, verbatim
+    pub fn matched(&self) -> &[u8] {
+        &self.matched
+    }
+
+    /// Byte range of the match in the searched text
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    /// The normalization that made the text match a key it doesn't literally equal, if any.
+    /// The only one is "case-fold", for dbs whose keys are matched regardless of case. A
+    /// prefix match is longer than its key, so only the part the key spans is compared
+    pub fn normalized_by(&self) -> Option<&'static str> {
+        (!self.matched.starts_with(&self.key)).then_some("case-fold")
+    }
+}
+
+/// How the values of a db are stored, per its metadata
+#[cfg(feature = "values")]
+pub(crate) enum ValueCodec {
+    /// As they are, built with --no-compress
+    Plain,
+    /// zstd compressed, the default
+    Zstd,
+    /// zstd compressed with a dictionary, built with --train-dict
+    ZstdDict(DecoderDictionary<'static>),
+}
+
+#[cfg(feature = "values")]
+impl ValueCodec {
+    pub(crate) fn of<D: AsRef<[u8]>>(fst: &Fst<D>) -> Self {
+        if meta(fst, META_COMPRESSION).is_some_and(|c| c == b"none") {
+            Self::Plain
+        } else if let Some(dict) = meta(fst, META_ZSTD_DICT) {
+            Self::ZstdDict(DecoderDictionary::copy(&dict))
+        } else {
+            Self::Zstd
+        }
+    }
+}
+
+/// Limits on the values read at query time, so a corrupt or hostile db can't make a single
+/// match allocate gigabytes or parse without end
+#[cfg(feature = "values")]
+#[derive(Copy, Clone, Debug)]
+pub struct ValueLimits {
+    /// The most bytes a value may decompress to
+    pub max_value_bytes: usize,
+    /// The deepest a json value may nest its arrays and objects
+    pub max_json_depth: usize,
+}
+
+#[cfg(feature = "values")]
+impl Default for ValueLimits {
+    fn default() -> Self {
+        Self {
+            max_value_bytes: 16 << 20,
+            max_json_depth: 64,
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+#[inline]
+pub(crate) fn decompress(compressed: &[u8], codec: &ValueCodec) -> Result<Vec<u8>> {
+    decompress_bounded(compressed, codec, usize::MAX)
+}
+
+/// Decompress a value, failing once it passes `max_len` bytes rather than reading it all
+#[cfg(feature = "values")]
+#[inline]
+pub(crate) fn decompress_bounded(
+    compressed: &[u8],
+    codec: &ValueCodec,
+    max_len: usize,
+) -> Result<Vec<u8>> {
+    let started = timings::start();
+    // one byte past the limit tells a value at the limit from a longer one
+    let limit = max_len.saturating_add(1) as u64;
+    let mut value = Vec::new();
+    let decoded = match codec {
+        ValueCodec::Plain => {
+            io::Read::read_to_end(&mut io::Read::take(compressed, limit), &mut value)
+        }
+        ValueCodec::Zstd => zstd::stream::Decoder::with_buffer(compressed).and_then(|decoder| {
+            io::Read::read_to_end(&mut io::Read::take(decoder, limit), &mut value)
+        }),
+        ValueCodec::ZstdDict(dict) => {
+            zstd::stream::Decoder::with_prepared_dictionary(compressed, dict).and_then(|decoder| {
+                io::Read::read_to_end(&mut io::Read::take(decoder, limit), &mut value)
+            })
+        }
+    };
+    timings::record(Phase::Decompress, started);
+    if decoded? > max_len {
+        anyhow::bail!("value decompresses to more than {max_len} bytes");
+    }
+    Ok(value)
+}
+
+/// Whether the arrays and objects of json `text` nest deeper than `max_depth`, without parsing
+/// it. Brackets inside strings don't count
+#[cfg(feature = "values")]
+fn nests_deeper(text: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in text {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// The positions after each boundary of a text, where a match may start
+enum Candidates<'a> {
+    /// After each byte marked in the table
+    Bytes {
+        haystack: &'a [u8],
+        table: &'static [bool; 256],
+        pos: usize,
+    },
+    /// After each character that isn't a word character. Invalid utf-8 is neither
+    Unicode { haystack: &'a [u8], pos: usize },
+}
+
+impl<'a> Candidates<'a> {
+    fn new(boundaries: Boundaries, haystack: &'a [u8]) -> Self {
+        let table = match boundaries {
+            Boundaries::Ascii => &NONWORD,
+            Boundaries::Labels => &NONWORD_DOT,
+            Boundaries::Off => &ANYBYTE,
+            Boundaries::Unicode => return Self::Unicode { haystack, pos: 0 },
+        };
+        Self::Bytes {
+            haystack,
+            table,
+            pos: 0,
+        }
+    }
+}
+
+impl Iterator for Candidates<'_> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        match self {
+            Self::Bytes {
+                haystack,
+                table,
+                pos,
+            } => {
+                let i = haystack[*pos..].iter().position(|&b| table[b as usize])?;
+                *pos += i + 1;
+                Some(*pos)
+            }
+            Self::Unicode { haystack, pos } => {
+                while let Some(&b) = haystack.get(*pos) {
+                    if b.is_ascii() {
+                        *pos += 1;
+                        if ASCII_NONWORD[b as usize] {
+                            return Some(*pos);
                         }
-                    })
-                    .and_then(Value::as_str)
-                    .unwrap_or("")
+                        continue;
+                    }
+                    match first_char(&haystack[*pos..]) {
+                        Some(c) => {
+                            *pos += c.len_utf8();
+                            if !is_word_char(c) {
+                                return Some(*pos);
+                            }
+                        }
+                        // invalid utf-8 is neither a word nor a non-word character
+                        None => *pos += 1,
+                    }
+                }
+                None
             }
         }
     }
@@ -75,13 +533,8 @@ impl Context for &FstMatch<'_> {
 pub struct FstMatches<'f, 'a> {
     fstsed: &'f FstSed,
     haystack: &'a [u8],
-    skip: usize,
-    // chain the two regexes iters together to ensure we can search for matches at the beginning of
-    // a line as well as when word boundaries occur at beginning of line. both might match at pos
-    // 0, but operate in different modes
-    // TODO: would be better to use a Generic here...
-    reiter:
-        Peekable<std::iter::Chain<regex::bytes::Matches<'f, 'a>, regex::bytes::Matches<'f, 'a>>>,
+    // the start of the line is always a candidate, ahead of those after the boundaries
+    reiter: Peekable<std::iter::Chain<std::iter::Once<usize>, Candidates<'a>>>,
 }
 
 impl<'f, 'a> FstMatches<'f, 'a> {
@@ -89,150 +542,653 @@ impl<'f, 'a> FstMatches<'f, 'a> {
         Self {
             fstsed,
             haystack,
-            skip: 0,
-            reiter: RE_START
-                .find_iter(haystack)
-                .chain(RE_NONWORD.find_iter(haystack))
+            reiter: std::iter::once(0)
+                .chain(Candidates::new(fstsed.boundaries, haystack))
                 .peekable(),
         }
     }
 }
 
-// ideally this iterator would return a custom Match object with the true start offset of the text
-// match, plus the text of the match itself, but the constructor is private. Could not
-// overcome lifetime issues with returning a FstMatch directly from this.
 impl<'f, 'a> Iterator for FstMatches<'f, 'a> {
-    type Item = usize;
+    type Item = Match;
 
-    fn next(&mut self) -> Option<usize> {
+    fn next(&mut self) -> Option<Match> {
+        let started = timings::start();
         let mut m = self.reiter.next();
+        timings::record(Phase::Scan, started);
 
-        // self.skip will be 0 only for the very first iteration. this is because matching at the
-        // beginning of the line is a slightly different operation: we want to test that very first
-        // byte if it is in the fst. for all other iterations, we are looking for word boundaries
-        // and thus want to test if the NEXT byte is in the fst
-        while m.is_some()
-            && self
-                .fstsed
-                .longest_match_at(self.haystack, m.unwrap().start() + self.skip)
-                .is_none()
-        {
+        // candidates are the positions to test: the very beginning of the line, where we want
+        // to test that first byte itself, and the byte after each boundary char
+        let mut found = None;
+        while let Some(candidate) = m {
+            stats::count(Stat::Candidates);
+            let started = timings::start();
+            if self.fstsed.admits(&self.haystack[candidate..]) {
+                found = self.fstsed.longest_match_at(self.haystack, candidate);
+            }
+            timings::record(Phase::Walk, started);
+            if found.is_some() {
+                break;
+            }
             // advance loop until we find a fstsed match or exhaust the iterator
+            let started = timings::start();
             m = self.reiter.next();
-            // avoid branching of testing "is this the first loop" and just set
-            // to 1 over and over again.
-            self.skip = 1;
+            timings::record(Phase::Scan, started);
         }
 
-        // we have two circumstances here: we've run out of reiter match positions
-        // or we have a real match. for the former, we are done: return None and break
-        // our iterator. (Clippy thinks this should just be m? but that seems really
-        // hard to comprehend what's happening)
-        #[allow(clippy::question_mark)]
-        if m.is_none() {
-            return None;
-        }
+        // either we've run out of reiter match positions and the iterator is done, or we
+        // have a real match
+        let found = found?;
 
-        // when we have a match, we must advance the reiter position
-        // past the point of the last match length before we can resume searching
-        while self.reiter.peek().is_some()
-            && (self.reiter.peek().unwrap().start())
-                <= (self.fstsed.get_match_start() + self.fstsed.get_match_len())
-        {
+        // when we have a match, we must advance the reiter position past the candidates
+        // inside the match before we can resume searching. a candidate testing the position
+        // right at the end of the match is kept, it's where the next match may start
+        while self.reiter.peek().is_some_and(|&next| next < found.end) {
             self.reiter.next();
         }
 
-        // return just position of the match start
-        Some(self.fstsed.get_match_start())
+        stats::count(Stat::Matches);
+        Some(found)
+    }
+}
+
+/// Which positions may start a match, and whether a match must end at a word boundary
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Boundaries {
+    /// Start of line or after one of a few ascii delimiters, the fast default
+    Ascii,
+    /// Like Ascii, and also after dots, for domain names
+    Labels,
+    /// Start of line or after any unicode non-word character
+    Unicode,
+    /// Anywhere, with no boundary check at the end of the key
+    Off,
+}
+
+impl Boundaries {
+    // How far the token at the start of `text` runs: up to the next byte that would start a
+    // candidate, or with unicode boundaries the next non-word character
+    fn token_len(self, text: &[u8]) -> usize {
+        let table = match self {
+            Self::Ascii => &NONWORD,
+            Self::Labels => &NONWORD_DOT,
+            Self::Off => return 0,
+            Self::Unicode => {
+                return (0..text.len())
+                    .find(|&i| at_boundary(&text[i..]))
+                    .unwrap_or(text.len())
+            }
+        };
+        text.iter()
+            .position(|&b| table[b as usize])
+            .unwrap_or(text.len())
+    }
+
+    // Whether a key may end right before `rest`. A domain name goes on through a dot followed
+    // by another label, so evil.com doesn't match in evil.com.au, though it does at the end of
+    // a sentence
+    #[inline]
+    fn ends_key(self, rest: &[u8]) -> bool {
+        let is_label = |b: &u8| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_';
+        at_boundary(rest)
+            && !(self == Self::Labels
+                && rest.first() == Some(&b'.')
+                && rest.get(1).is_some_and(is_label))
+    }
+}
+
+/// Where the value of a key found in the fst is kept
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(not(feature = "values"), allow(dead_code))]
+enum ValueRef {
+    /// In the fst, along the path past the sentinel transition at this address
+    Sentinel(CompiledAddr),
+    /// In the value store, under this number
+    Stored(u64),
+}
+
+/// The bytes of a fst database: memory mapped from a file, or held in memory where there is no
+/// file to map, e.g. a database fetched over the network into a browser
+pub enum FstData {
+    Mmap(Mmap),
+    Bytes(Vec<u8>),
+}
+
+impl AsRef<[u8]> for FstData {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Self::Mmap(mmap) => mmap,
+            Self::Bytes(bytes) => bytes,
+        }
     }
 }
 
 pub struct FstSed {
-    fst: Fst<Mmap>,
+    fst: Fst<FstData>,
+    #[cfg(feature = "color")]
     pub color: ColorChoice,
     pub template: String,
-    keycache: RefCell<Vec<u8>>,
-    valuecache: RefCell<Vec<u8>>,
-    startcache: RefCell<usize>,
+    compiled: Template,
+    /// The template of each match's pivot url
+    link: Option<Template>,
+    /// Make each rendered match a terminal hyperlink to its url
+    hyperlinks: bool,
+    decoration: Decoration,
+    boundaries: Boundaries,
+    charset: Option<TokenCharset>,
+    /// Walk the fst with the text in ascii lowercase, for dbs of case-insensitive keys
+    fold_case: bool,
+    /// The bytes keys start with, from the transitions out of the root node
+    first_bytes: [bool; 256],
+    /// Keys match as prefixes of longer tokens
+    prefix: bool,
+    /// Render keys and values that aren't utf-8 with replacement characters
+    lossy: bool,
+    deterministic: bool,
+    #[cfg(feature = "json")]
     has_json_keys: bool,
+    misscache: Option<Mutex<LruCache<MissKey, ()>>>,
+    #[cfg(feature = "values")]
+    decompressedcache: Option<Mutex<LruCache<Vec<u8>, Vec<u8>>>>,
+    #[cfg(feature = "json")]
+    expiry: Option<Expiry>,
+    #[cfg(feature = "json")]
+    json_fields: Option<JsonFields>,
+    /// Set for databases built with hashed keys, which are matched token by token
+    hasher: Option<KeyHasher>,
+    max_key_len: usize,
+    layout: Layout,
+    /// The values of a map layout db
+    #[cfg(feature = "values")]
+    store: Option<ValueStore>,
+    /// The last match id handed out
+    match_ids: AtomicU64,
+    #[cfg(feature = "values")]
+    codec: ValueCodec,
+    #[cfg(feature = "values")]
+    limits: ValueLimits,
 }
 
+// FstSed is shared by reference between worker threads, so it must stay Sync
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FstSed>();
+};
+
 // from https://github.com/BurntSushi/fst/blob/master/fst-bin/src/util.rs
+/// Memory map a fst database
+///
+/// # Safety
+///
+/// The file must not be modified or truncated while the fst is in use
 #[inline]
-unsafe fn mmap_fst(path: Utf8PathBuf) -> Result<Fst<Mmap>, Error> {
+pub unsafe fn mmap_fst(path: Utf8PathBuf) -> Result<Fst<Mmap>, Error> {
     let mmap = Mmap::map(&File::open(path)?)?;
     let fst = Fst::new(mmap)?;
     Ok(fst)
 }
 
-/// Helper function to determine if user-specified template string will require
-/// json deserialization. Returns true if the template contains a {var} beyond
-/// {key} and [value}
-fn test_for_json_keys(template: &str) -> bool {
-    template
-        .split('{')
-        .skip(1)
-        .any(|c| !(c.starts_with("key}") || c.starts_with("value}")))
+/// Metadata entries hold database wide settings. They start with the sentinel, i.e. have an
+/// empty key, which no search can match since the walk only looks for the sentinel after a
+/// byte of key. The rest of the entry is name=value. Map layout dbs keep them the same way,
+/// as keys of their own that searches never start with
+pub fn meta_entry(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut entry = vec![SENTINEL];
+    entry.extend_from_slice(name.as_bytes());
+    entry.push(b'=');
+    entry.extend_from_slice(value);
+    entry
 }
 
-impl<'a> FstSed {
-    pub fn new(fstpath: Utf8PathBuf, user_template: Option<String>, color: ColorChoice) -> Self {
-        let mut template = user_template.unwrap_or_else(|| "<{key}|{value}>".to_string());
-        let has_json_keys = test_for_json_keys(&template);
+/// The value of a metadata entry of the database, if it has one
+pub fn meta<D: AsRef<[u8]>>(fst: &Fst<D>, name: &str) -> Option<Vec<u8>> {
+    let prefix = meta_entry(name, b"");
+    let mut stream = fst.range().ge(&prefix).into_stream();
+    let (entry, _) = stream.next()?;
+    entry.strip_prefix(prefix.as_slice()).map(<[u8]>::to_vec)
+}
 
-        if color == ColorChoice::Always {
-            // if we are printing color, bookend the template with ansi red escapes
-            template = format!("\x1b[1;31m{template}\x1b[0;0m");
+/// Whether `text` can be cut at byte `i` without splitting a utf-8 character or a grapheme,
+/// i.e. what displays as one character, such as an emoji sequence or a letter and its accents.
+/// Judged from the characters on either side, so long runs of flags may still be split
+pub fn is_grapheme_boundary(text: &[u8], i: usize) -> bool {
+    if i == 0 || i >= text.len() {
+        return true;
+    }
+    let (before, after) = text.split_at(i);
+    // cheap answers for the common cases: ascii on both sides, or inside a utf-8 character
+    if after[0] & 0xC0 == 0x80 {
+        return false;
+    }
+    if after[0].is_ascii() && before[i - 1].is_ascii() {
+        // only \r\n is a grapheme among ascii characters
+        return !(before[i - 1] == b'\r' && after[0] == b'\n');
+    }
+    !joins_graphemes(before, after)
+}
+
+// Whether the character starting `after` belongs to the grapheme ending `before`
+#[cfg(feature = "unicode")]
+fn joins_graphemes(before: &[u8], after: &[u8]) -> bool {
+    if RE_GRAPHEME_EXTEND.is_match(after) {
+        return true;
+    }
+    match RE_GRAPHEME_JOINED.find(before) {
+        // a zero width joiner joins whatever follows, a regional indicator only another one
+        Some(m) if m.as_bytes() == "\u{200D}".as_bytes() => true,
+        Some(_) => RE_REGIONAL_INDICATOR.is_match(after),
+        None => false,
+    }
+}
+
+// Without the unicode feature's grapheme tables, only the common joining characters are kept
+// with their neighbours, and flags may be split
+#[cfg(not(feature = "unicode"))]
+fn joins_graphemes(before: &[u8], after: &[u8]) -> bool {
+    first_char(after).is_some_and(is_joining) || before.ends_with("\u{200D}".as_bytes())
+}
+
+/// The last grapheme boundary of `text` at or before byte `i`, see is_grapheme_boundary
+pub fn floor_grapheme_boundary(text: &[u8], i: usize) -> usize {
+    let mut i = i.min(text.len());
+    while !is_grapheme_boundary(text, i) {
+        i -= 1;
+    }
+    i
+}
+
+/// Whether `b` is one of the ascii delimiters that candidate matches start after by default
+pub fn is_delimiter(b: u8) -> bool {
+    NONWORD[b as usize]
+}
+
+/// Whether a key may end right before `rest`: at the end of the text or a non-word character
+#[inline]
+pub(crate) fn at_boundary(rest: &[u8]) -> bool {
+    match rest.first() {
+        None => true,
+        Some(&b) if b.is_ascii() => ASCII_NONWORD[b as usize],
+        Some(_) => first_char(rest).is_some_and(|c| !is_word_char(c)),
+    }
+}
+
+/// Helper function to determine if user-specified template will require json
+/// deserialization. Returns true if the template contains a {var} beyond {key}, {match},
+/// {value}, {line_score}, {match_id}, {normalized_by} and {normalized}
+#[cfg(feature = "json")]
+fn test_for_json_keys(template: &Template) -> bool {
+    template.fields().any(|name| {
+        !matches!(
+            name,
+            "key" | "match" | "value" | "line_score" | "match_id" | "normalized_by" | "normalized"
+        )
+    })
+}
+
+impl<'a> FstSed {
+    /// Memory map the fst database at `fstpath`, and the value store next to it if it has one
+    pub fn new(fstpath: Utf8PathBuf, user_template: Option<String>) -> Result<Self> {
+        let fst = unsafe {
+            mmap_fst(fstpath.clone())
+                .and_then(|fst| Ok(fst.map_data(FstData::Mmap)?))
+                .with_context(|| format!("Error opening fst database {fstpath}"))?
+        };
+        let fsed = Self::from_fst(fst, user_template)?;
+        #[cfg(feature = "values")]
+        if fsed.layout == Layout::Map {
+            let store = ValueStore::open_for(&fsed.fst, &sidecar_path(&fstpath))?;
+            return Ok(fsed.with_value_store(store));
         }
+        Ok(fsed)
+    }
 
-        let fst = unsafe { mmap_fst(fstpath).expect("Error opening fst database") };
+    /// Search a fst database already read into memory. The values of a map layout db are
+    /// in its separate value store, see with_value_store
+    pub fn from_bytes(bytes: Vec<u8>, user_template: Option<String>) -> Result<Self> {
+        let fst = Fst::new(FstData::Bytes(bytes)).context("Error loading fst database")?;
+        Self::from_fst(fst, user_template)
+    }
 
-        Self {
+    fn from_fst(fst: Fst<FstData>, user_template: Option<String>) -> Result<Self> {
+        let template = user_template.unwrap_or_else(|| "<{key}|{value}>".to_string());
+        let compiled = Template::parse(&template)?;
+        #[cfg(feature = "json")]
+        let has_json_keys = test_for_json_keys(&compiled);
+        let hasher = meta(&fst, META_SALT).map(|salt| KeyHasher::new(&salt));
+        let max_key_len = meta(&fst, META_MAX_KEY_LEN)
+            .and_then(|n| std::str::from_utf8(&n).ok()?.parse().ok())
+            .unwrap_or(DEFAULT_MAX_KEY_LEN);
+        // the declared key type picks the defaults, which the builders below can override
+        let key_type = meta(&fst, META_KEY_TYPE).and_then(|name| KeyType::from_name(&name));
+        // lowercased keys can only be found by text folded the same way
+        let lowercased = meta(&fst, META_NORMALIZE)
+            .is_some_and(|steps| steps.split(|&b| b == b',').any(|s| s == b"lowercase"));
+        #[cfg(feature = "values")]
+        let codec = ValueCodec::of(&fst);
+        let layout = Layout::of(&fst);
+        let mut first_bytes = [false; 256];
+        for t in fst.root().transitions() {
+            first_bytes[t.inp as usize] = true;
+        }
+        // metadata entries aren't keys
+        first_bytes[SENTINEL as usize] = false;
+
+        Ok(Self {
             fst,
-            color,
+            #[cfg(feature = "color")]
+            color: ColorChoice::Never,
             template,
-            keycache: RefCell::new(Vec::with_capacity(256)),
-            valuecache: RefCell::new(Vec::with_capacity(2048)),
-            startcache: RefCell::new(0),
+            compiled,
+            link: None,
+            hyperlinks: false,
+            decoration: Decoration::default(),
+            boundaries: match key_type {
+                Some(KeyType::Domain) => Boundaries::Labels,
+                _ => Boundaries::Ascii,
+            },
+            charset: key_type.and_then(KeyType::charset),
+            fold_case: lowercased || key_type.is_some_and(KeyType::folds_case),
+            first_bytes,
+            prefix: false,
+            lossy: false,
+            deterministic: false,
+            #[cfg(feature = "json")]
             has_json_keys,
+            misscache: None,
+            #[cfg(feature = "values")]
+            decompressedcache: None,
+            #[cfg(feature = "json")]
+            expiry: None,
+            #[cfg(feature = "json")]
+            json_fields: None,
+            hasher,
+            max_key_len,
+            layout,
+            #[cfg(feature = "values")]
+            store: None,
+            match_ids: AtomicU64::new(0),
+            #[cfg(feature = "values")]
+            codec,
+            #[cfg(feature = "values")]
+            limits: ValueLimits::default(),
+        })
+    }
+
+    /// Read the values of a map layout db from `store`
+    #[cfg(feature = "values")]
+    pub fn with_value_store(mut self, store: ValueStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Highlight rendered matches in bold red when color is Always
+    #[cfg(feature = "color")]
+    pub fn with_color(self, color: ColorChoice) -> Self {
+        self.with_color_styles(color, &ColorStyles::default())
+            .expect("the default styles name no fields")
+    }
+
+    /// Highlight rendered matches, and fields within them, in `styles` when color is Always.
+    /// Fails if a style names a field the template doesn't use
+    #[cfg(feature = "color")]
+    pub fn with_color_styles(mut self, color: ColorChoice, styles: &ColorStyles) -> Result<Self> {
+        if color == ColorChoice::Always {
+            styles.apply(&mut self.compiled)?;
+        }
+        self.color = color;
+        Ok(self)
+    }
+
+    /// Remember up to `capacity` recently failed candidate prefixes so that repeats of the same
+    /// non-matching token skip the fst walk. Only misses decided entirely within the first
+    /// MISS_PREFIX bytes are cached, so this never changes what matches
+    pub fn with_miss_cache(mut self, capacity: usize) -> Self {
+        if self.deterministic {
+            return self;
+        }
+        self.misscache = NonZeroUsize::new(capacity).map(|n| Mutex::new(LruCache::new(n)));
+        self
+    }
+
+    /// Squeeze runs of spaces and tabs in rendered templates down to one space
+    pub fn with_collapsed_whitespace(mut self) -> Self {
+        self.compiled.collapse_whitespace();
+        self
+    }
+
+    /// Render `template` for every match as its pivot url, e.g. a link to the record in an
+    /// intel platform. Match records and enrichment entries carry it as "link"
+    pub fn with_link_template(mut self, template: &str) -> Result<Self> {
+        let link = Template::parse(template)?;
+        #[cfg(feature = "json")]
+        {
+            self.has_json_keys |= test_for_json_keys(&link);
         }
+        self.link = Some(link);
+        Ok(self)
+    }
+
+    /// Wrap each rendered match in an OSC 8 escape, so terminals that support them show it as
+    /// a hyperlink to its url from with_link_template
+    pub fn with_hyperlinks(mut self) -> Self {
+        self.hyperlinks = true;
+        self
+    }
+
+    /// Place the rendered template before or after the matched text instead of in its place
+    pub fn with_decoration(mut self, decoration: Decoration) -> Self {
+        self.decoration = decoration;
+        self
     }
 
+    /// Reference mode for property tests and for checking other matchers against this one.
+    /// Disables every cache whose contents depend on what was searched before, such as the
+    /// miss cache, so each lookup is a plain fst walk
+    pub fn deterministic(mut self) -> Self {
+        self.deterministic = true;
+        self.misscache = None;
+        #[cfg(feature = "values")]
+        {
+            self.decompressedcache = None;
+        }
+        self
+    }
+
+    /// Keep the decompressed values of up to `capacity` recently matched keys, so input that
+    /// hits the same few keys over and over only decompresses each of them once
+    #[cfg(feature = "values")]
+    pub fn with_value_cache(mut self, capacity: usize) -> Self {
+        if self.deterministic {
+            return self;
+        }
+        self.decompressedcache = NonZeroUsize::new(capacity).map(|n| Mutex::new(LruCache::new(n)));
+        self
+    }
+
+    /// Replace the default limits on the size and json nesting of the values read for matches
+    #[cfg(feature = "values")]
+    pub fn with_value_limits(mut self, limits: ValueLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Ignore keys whose record has expired, as though they weren't in the fst. Checking a
+    /// match means decompressing its value, so pair this with the value cache
+    #[cfg(feature = "json")]
+    pub fn with_expiry(mut self, expiry: Expiry) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+
+    /// In json mode, only search the string values under the given member names
+    #[cfg(feature = "json")]
+    pub fn with_json_fields(mut self, fields: JsonFields) -> Self {
+        self.json_fields = Some(fields);
+        self
+    }
+
+    /// The ranges of the json strings of `text` to search, quotes included: all of them, or
+    /// the values selected by with_json_fields
+    #[cfg(feature = "json")]
+    #[inline]
+    pub fn json_strings<'t>(
+        &'t self,
+        text: &'t [u8],
+    ) -> Box<dyn Iterator<Item = (usize, usize)> + 't> {
+        match &self.json_fields {
+            Some(fields) => Box::new(JsonValues::new(text, fields)),
+            None => jsonquotes_range_iter(text),
+        }
+    }
+
+    /// Match keys anywhere, including inside larger tokens, e.g. a known hash fragment inside a
+    /// longer string. Every byte is a candidate start and the end of the key needs no boundary
+    pub fn without_boundaries(mut self) -> Self {
+        self.boundaries = Boundaries::Off;
+        self
+    }
+
+    /// Match keys as prefixes: a key matches any token that starts with it, and the match runs
+    /// to the end of that token, e.g. the key cmd.exe /c in cmd.exe /cwhoami. Not for dbs of
+    /// hashed keys, which are matched whole
+    pub fn with_prefix_matching(mut self) -> Self {
+        self.prefix = true;
+        self
+    }
+
+    /// Render keys and values that aren't valid utf-8 with replacement characters rather than
+    /// as <keyerror> and <valueerror>, for binary and mixed input
+    pub fn with_lossy_utf8(mut self) -> Self {
+        self.lossy = true;
+        self
+    }
+
+    /// Only walk the fst from candidates whose leading token has the shape of the db's keys
+    pub fn with_token_charset(mut self, charset: TokenCharset) -> Self {
+        self.charset = Some(charset);
+        self
+    }
+
+    /// Whether the token charset, if any, admits a candidate match at the start of `text`
+    #[inline]
+    fn admits(&self, text: &[u8]) -> bool {
+        self.charset
+            .is_none_or(|charset| charset.admits(text, self.boundaries != Boundaries::Off))
+    }
+
+    /// Start candidate matches after any unicode non-word character rather than just the ascii
+    /// delimiters, for CJK and other text with its own whitespace and punctuation. Slower
+    pub fn with_unicode_boundaries(mut self) -> Self {
+        self.boundaries = Boundaries::Unicode;
+        self
+    }
+
+    /// Decompress the value of the match, or fetch it from the value cache
+    #[cfg(feature = "values")]
     #[inline]
-    pub fn get_match(&self) -> FstMatch {
+    fn decompressed_value(&self, m: &Match) -> Vec<u8> {
+        let key = &m.key;
+        if let Some(cache) = &self.decompressedcache {
+            if let Some(value) = cache.lock().unwrap().get(key) {
+                return value.clone();
+            }
+        }
+
+        let value = match self.read_value(&m.value) {
+            Ok(value) => value,
+            #[cfg_attr(not(feature = "json"), allow(unused_variables))]
+            Err(e) => {
+                #[cfg(feature = "json")]
+                let key = String::from_utf8_lossy(key.as_slice()).to_string();
+                #[cfg(feature = "json")]
+                diagnostics::warn_quiet(
+                    "decompression",
+                    format!("failed to read value of {key}: {e}"),
+                    serde_json::json!({ "key": key, "error": e.to_string() }),
+                );
+                // don't cache failures, so each one is reported
+                return "<decompressionerror>".as_bytes().to_vec();
+            }
+        };
+        if let Some(cache) = &self.decompressedcache {
+            cache.lock().unwrap().put(key.clone(), value.clone());
+        }
+        value
+    }
+
+    /// Decompress the value stored with the key of the match, however the db stores its
+    /// values. This bypasses the value cache, see get_match for decoding many matches
+    #[cfg(feature = "values")]
+    pub fn value(&self, m: &Match) -> Result<Vec<u8>> {
+        self.read_value(&m.value)
+    }
+
+    /// Decompress a stored value within the value limits
+    #[cfg(feature = "values")]
+    fn read_value(&self, compressed: &[u8]) -> Result<Vec<u8>> {
+        let value = decompress_bounded(compressed, &self.codec, self.limits.max_value_bytes)?;
+        let max_depth = self.limits.max_json_depth;
+        if nests_deeper(&value, max_depth) {
+            anyhow::bail!("value nests deeper than {max_depth} levels");
+        }
+        Ok(value)
+    }
+
+    // Bytes as text for rendering. Invalid utf-8 is the placeholder, or decoded lossily
+    fn text(&self, bytes: &[u8], placeholder: &str) -> String {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) if self.lossy => String::from_utf8_lossy(bytes).into_owned(),
+            Err(_) => placeholder.to_string(),
+        }
+    }
+
+    /// Decompress and decode the match for rendering
+    #[inline]
+    pub fn get_match(&self, m: &Match) -> FstMatch<'_> {
         // Decompress the value
-        let decompressed_value = zstd::stream::decode_all(self.valuecache.borrow().as_slice())
-            .unwrap_or("<decompressionerror>".as_bytes().to_vec());
+        #[cfg(feature = "values")]
+        let decompressed_value = self.decompressed_value(m);
+        #[cfg(not(feature = "values"))]
+        let decompressed_value = Vec::new();
 
         // instantiate object directly. i tried using a new constructor, but had lifetime/scoping
         // issues passing references created in this function
         FstMatch {
-            key: std::str::from_utf8(self.keycache.borrow().as_slice())
-                .unwrap_or("<keyerror>")
-                .to_string(),
-            value: std::str::from_utf8(&decompressed_value)
-                .unwrap_or("<valueerror>")
-                .to_string(),
-            template: &self.template,
+            key: self.text(&m.key, "<keyerror>"),
+            matched: String::from_utf8_lossy(&m.matched).into_owned(),
+            value: self.text(&decompressed_value, "<valueerror>"),
+            template: &self.compiled,
+            decoration: self.decoration,
+            #[cfg(feature = "json")]
             jsonvalue: if self.has_json_keys {
-                Some(
-                    serde_json::from_slice(&decompressed_value)
-                        .unwrap_or_else(|_| Value::default()),
-                )
+                let started = timings::start();
+                let jsonvalue = serde_json::from_slice(&decompressed_value)
+                    .unwrap_or_else(|_| Value::default());
+                timings::record(Phase::JsonParse, started);
+                Some(jsonvalue)
             } else {
                 None
             },
+            line_score: None,
+            match_id: None,
+            normalized_by: m.normalized_by(),
+            link: self.link.as_ref(),
+            hyperlink: self.hyperlinks,
         }
     }
 
-    #[inline]
-    pub fn get_match_len(&self) -> usize {
-        self.keycache.borrow().len()
+    /// Whether the template renders {match_id}, so the order matches are numbered in shows
+    pub fn numbers_matches(&self) -> bool {
+        self.compiled.fields().any(|name| name == "match_id")
     }
 
+    /// Number the next match of the run, counting from 1. Modes that output matches number
+    /// them in input order, so the ids of a decorated run line up with the match records of
+    /// an --output-format json run over the same input
     #[inline]
-    pub fn get_match_start(&self) -> usize {
-        *self.startcache.borrow()
+    pub fn next_match_id(&self) -> u64 {
+        self.match_ids.fetch_add(1, Ordering::Relaxed) + 1
     }
 
     #[inline]
@@ -240,64 +1196,239 @@ impl<'a> FstSed {
         FstMatches::new(self, text)
     }
 
-    #[inline]
-    pub fn clear(&self) {
-        self.keycache.borrow_mut().clear();
-        self.valuecache.borrow_mut().clear();
-        *self.startcache.borrow_mut() = 0;
-    }
-
     // adapted from https://github.com/BurntSushi/fst/pull/104/files
     #[inline]
-    pub fn longest_match_at(&self, text: &'a [u8], start: usize) -> Option<usize> {
-        let mut node = self.fst.root();
-        let mut last_match = None;
+    pub fn longest_match_at(&self, text: &'a [u8], start: usize) -> Option<Match> {
+        if let Some(hasher) = &self.hasher {
+            return self.unexpired(self.longest_hashed_match_at(hasher, text, start)?);
+        }
+        // most candidates in ordinary text are turned away by their first byte alone, a table
+        // lookup rather than a search of the root node
+        let first = *text.get(start)?;
+        let first = if self.fold_case {
+            first.to_ascii_lowercase()
+        } else {
+            first
+        };
+        if !self.first_bytes[first as usize] {
+            return None;
+        }
+        // without boundaries, candidates start anywhere, but a match must not split what
+        // displays as one character: decorating there would mangle it
+        let whole_graphemes = self.boundaries == Boundaries::Off;
+        if whole_graphemes && !is_grapheme_boundary(text, start) {
+            return None;
+        }
         let value = &text[start..];
+        // in the map layout the metadata entries are keys of their own, starting with NUL
+        if self.layout == Layout::Map && value.first() == Some(&SENTINEL) {
+            return None;
+        }
+        let mut node = self.fst.root();
+        let mut output = Output::zero();
+        // length of the longest key found so far, and where its value is
+        let mut longest = None;
+
+        if let Some(misscache) = &self.misscache {
+            if misscache.lock().unwrap().get(&miss_key(value)).is_some() {
+                return None;
+            }
+        }
+        // once the walk passes the end of any key, the outcome also depends on the boundary
+        // check of the following bytes, so it's no longer safe to cache a miss
+        let mut passed_key = false;
+        // index of the last byte examined
+        let mut last = value.len().saturating_sub(1);
 
         for (i, &b) in value.iter().enumerate() {
-            if let Some(trans_index) = node.find_input(b) {
-                let t = node.transition(trans_index);
-                node = self.fst.node(t.addr);
-
-                if let Some(sentinel_index) = node.find_input(SENTINEL) {
-                    // validate candidate match has nonword boundary char next
-                    // or is at the end of the line. we dont want matches inside other strings,
-                    // foo should not match inside foobar
-                    if i == value.len() - 1 || RE_UNICODE_BOUNDARY.is_match(&value[i + 1..]) {
-                        // we have a match!
-                        self.clear();
-                        last_match = Some(i + 1);
-                        self.keycache
-                            .borrow_mut()
-                            .extend_from_slice(&value[..i + 1]);
-                        *self.startcache.borrow_mut() = start;
-
-                        // find the sentinel node, then read to the the final node
-                        // to retrieve the "value"
-                        let sentinel = node.transition(sentinel_index);
-                        let mut snode = self.fst.node(sentinel.addr);
-                        while !snode.is_final() {
-                            if let Some(t) = snode.transitions().next() {
-                                // after the sentinel, we should not have any more
-                                // branching in the fst, so we just grab the first transition
-                                self.valuecache.borrow_mut().push(t.inp);
-                                snode = self.fst.node(t.addr);
-                            } else {
-                                // somehow ran out of nodes!
-                                break;
-                            }
-                        }
-                    }
+            let b = if self.fold_case {
+                b.to_ascii_lowercase()
+            } else {
+                b
+            };
+            let Some(trans_index) = node.find_input(b) else {
+                last = i;
+                break;
+            };
+            let t = node.transition(trans_index);
+            output = output.cat(t.out);
+            node = self.fst.node(t.addr);
+
+            if let Some(found) = self.key_end(&node, output) {
+                passed_key = true;
+                // validate candidate match has nonword boundary char next
+                // or is at the end of the line. we dont want matches inside other strings,
+                // foo should not match inside foobar, unless keys are prefixes
+                if self.prefix
+                    || (whole_graphemes && is_grapheme_boundary(value, i + 1))
+                    || self.boundaries.ends_key(&value[i + 1..])
+                {
+                    // we have a match! keep walking in case there is a longer one
+                    longest = Some((i + 1, found));
+                }
+            }
+        }
+        if !passed_key && !value.is_empty() {
+            self.remember_miss(value, last);
+        }
+
+        #[cfg_attr(not(feature = "values"), allow(unused_variables))]
+        let (len, found) = longest?;
+        // a prefix match takes in the rest of its token
+        let end = if self.prefix {
+            len + self.boundaries.token_len(&value[len..])
+        } else {
+            len
+        };
+
+        // the fst key is spelled as the input, or its lowercase when folding case. Keep the
+        // matched text on its own for templates that need it verbatim
+        let m = Match {
+            start,
+            end: start + end,
+            key: if self.fold_case {
+                value[..len].to_ascii_lowercase()
+            } else {
+                value[..len].to_vec()
+            },
+            matched: value[..end].to_vec(),
+            #[cfg(feature = "values")]
+            value: self.compressed_value(found),
+        };
+        self.unexpired(m)
+    }
+
+    /// Token matching for databases of hashed keys, where the fst can't be walked with the
+    /// text itself. Every span from `start` that ends at a boundary, up to the length of the
+    /// longest key, is hashed and looked up, and the longest one found wins
+    fn longest_hashed_match_at(
+        &self,
+        hasher: &KeyHasher,
+        text: &'a [u8],
+        start: usize,
+    ) -> Option<Match> {
+        let value = &text[start..];
+        let mut hashing = hasher.start();
+        let mut longest = None;
+
+        for (i, &b) in value.iter().take(self.max_key_len).enumerate() {
+            hashing.update([if self.fold_case {
+                b.to_ascii_lowercase()
+            } else {
+                b
+            }]);
+            if (self.boundaries == Boundaries::Off && is_grapheme_boundary(value, i + 1))
+                || self.boundaries.ends_key(&value[i + 1..])
+            {
+                let key = KeyHasher::finish(hashing.clone());
+                if let Some(found) = self.value_of(&key) {
+                    longest = Some((i + 1, found));
                 }
+            }
+        }
+
+        #[cfg_attr(not(feature = "values"), allow(unused_variables))]
+        let (len, found) = longest?;
+        // the match goes by the text rather than the hash, for templates and the value cache
+        Some(Match {
+            start,
+            end: start + len,
+            key: value[..len].to_vec(),
+            matched: value[..len].to_vec(),
+            #[cfg(feature = "values")]
+            value: self.compressed_value(found),
+        })
+    }
+
+    /// Where the value of the key ending at `node` is, if a key ends there. `output` is the
+    /// sum of the outputs of the transitions walked to reach it
+    #[inline]
+    fn key_end(&self, node: &Node<'_>, output: Output) -> Option<ValueRef> {
+        match self.layout {
+            Layout::Map => node
+                .is_final()
+                .then(|| ValueRef::Stored(output.cat(node.final_output()).value())),
+            Layout::Sentinel => node
+                .find_input(SENTINEL)
+                .map(|i| ValueRef::Sentinel(node.transition(i).addr)),
+        }
+    }
+
+    /// Walk the fst along all of `key`. Returns where its value is, if `key` is in the db
+    #[inline]
+    fn value_of(&self, key: &[u8]) -> Option<ValueRef> {
+        let mut node = self.fst.root();
+        let mut output = Output::zero();
+        for &b in key {
+            let t = node.transition(node.find_input(b)?);
+            output = output.cat(t.out);
+            node = self.fst.node(t.addr);
+        }
+        self.key_end(&node, output)
+    }
+
+    /// Drop the match if --respect-ttl says its record has expired
+    #[inline]
+    fn unexpired(&self, m: Match) -> Option<Match> {
+        #[cfg(feature = "json")]
+        if let Some(expiry) = &self.expiry {
+            if expiry.is_expired(&self.decompressed_value(&m)) {
+                stats::count(Stat::Expired);
+                return None;
+            }
+        }
+        Some(m)
+    }
+
+    /// Retrieve the compressed "value": from the value store, or by reading from the sentinel
+    /// node to the final node
+    #[cfg(feature = "values")]
+    #[inline]
+    fn compressed_value(&self, found: ValueRef) -> Vec<u8> {
+        let sentinel = match found {
+            ValueRef::Sentinel(sentinel) => sentinel,
+            ValueRef::Stored(index) => {
+                let store = self.store.as_ref();
+                return store
+                    .and_then(|s| s.get(index))
+                    .unwrap_or_default()
+                    .to_vec();
+            }
+        };
+        let mut compressed = Vec::with_capacity(2048);
+        let mut snode = self.fst.node(sentinel);
+        while !snode.is_final() {
+            if let Some(t) = snode.transitions().next() {
+                // after the sentinel, we should not have any more
+                // branching in the fst, so we just grab the first transition
+                compressed.push(t.inp);
+                snode = self.fst.node(t.addr);
             } else {
-                return last_match;
+                // somehow ran out of nodes!
+                break;
             }
         }
-        last_match
+        compressed
     }
 
+    /// Cache a failed walk of `value` that stopped after examining byte `last`, provided every
+    /// byte examined is part of the cache key
     #[inline]
-    pub fn longest_match(&self, text: &'a [u8]) -> Option<usize> {
+    fn remember_miss(&self, value: &[u8], last: usize) {
+        if let Some(misscache) = &self.misscache {
+            if last < MISS_PREFIX {
+                misscache.lock().unwrap().put(miss_key(value), ());
+            }
+        }
+    }
+
+    #[inline]
+    pub fn longest_match(&self, text: &'a [u8]) -> Option<Match> {
         self.longest_match_at(text, 0)
     }
+
+    /// Look up a key exactly, rather than the longest key at the start of `key`
+    pub fn get(&self, key: &'a [u8]) -> Option<Match> {
+        self.longest_match(key).filter(|m| m.end == key.len())
+    }
 }
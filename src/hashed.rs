@@ -0,0 +1,68 @@
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Name of the metadata entry holding the salt of a database built with hashed keys
+pub const META_SALT: &str = "hash_salt";
+/// Name of the metadata entry holding the length in bytes of the longest original key, which
+/// bounds how far past a token start the matcher needs to look
+pub const META_MAX_KEY_LEN: &str = "max_key_len";
+
+// 16 bytes of the digest, hex encoded. Hex because a raw digest could contain the sentinel
+const DIGEST_BYTES: usize = 16;
+
+/// Salted hashing of keys, for databases of sensitive terms (employee names, customer ids)
+/// that are handed out to log hosts without revealing the terms themselves
+#[derive(Clone)]
+pub struct KeyHasher {
+    salt: Vec<u8>,
+    salted: Sha256,
+}
+
+impl KeyHasher {
+    pub fn new(salt: &[u8]) -> Self {
+        let mut salted = Sha256::new();
+        salted.update(salt);
+        Self {
+            salt: salt.to_vec(),
+            salted,
+        }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// A fresh random salt, hex encoded
+    pub fn random_salt() -> String {
+        // std seeds each RandomState from the os, which is random enough for a salt
+        (0..2)
+            .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+            .collect()
+    }
+
+    /// The fst key stored for `key`
+    pub fn hash(&self, key: &[u8]) -> Vec<u8> {
+        let mut hasher = self.start();
+        hasher.update(key);
+        Self::finish(hasher)
+    }
+
+    /// A hasher already fed the salt, for hashing several prefixes of the same text
+    /// incrementally: update it and clone it before each finish
+    #[inline]
+    pub fn start(&self) -> Sha256 {
+        self.salted.clone()
+    }
+
+    #[inline]
+    pub fn finish(hasher: Sha256) -> Vec<u8> {
+        let digest = hasher.finalize();
+        let mut hex = Vec::with_capacity(DIGEST_BYTES * 2);
+        for b in &digest[..DIGEST_BYTES] {
+            hex.push(b"0123456789abcdef"[(b >> 4) as usize]);
+            hex.push(b"0123456789abcdef"[(b & 0xf) as usize]);
+        }
+        hex
+    }
+}
@@ -0,0 +1,107 @@
+use crate::fstsed::{
+    meta, mmap_fst, META_COMPRESSION, META_KEY_TYPE, META_NORMALIZE, META_ZSTD_DICT, SENTINEL,
+};
+use crate::hashed::META_SALT;
+use crate::valuestore::{sidecar_path, Layout, ValueStore};
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use fst::Streamer;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Describe a fstsed database: its size, how many entries and distinct keys it holds, and
+/// optionally how the keys are spread over their leading bytes
+#[derive(clap::Args, Debug)]
+pub struct InfoArgs {
+    /// The fst db to describe
+    #[clap(short = 'f', value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    fst: Utf8PathBuf,
+
+    /// Count the distinct keys by their first N bytes, most common prefix first. Shows skew
+    /// when planning how to split a database into shards, e.g. most keys starting with "http"
+    #[clap(long, value_name = "N")]
+    prefix_histogram: Option<usize>,
+}
+
+pub fn run_info<W: Write>(args: &InfoArgs, out: &mut W) -> Result<()> {
+    let fst = unsafe {
+        mmap_fst(args.fst.clone())
+            .with_context(|| format!("Error opening fst database {}", args.fst))?
+    };
+
+    let mut entries: u64 = 0;
+    let mut keys: u64 = 0;
+    let mut histogram: HashMap<Vec<u8>, u64> = HashMap::new();
+    let mut lastkey: Vec<u8> = Vec::new();
+    let layout = Layout::of(&fst);
+
+    // entries are sorted, so all the values of a key are next to each other
+    let mut stream = fst.stream();
+    while let Some((entry, _)) = stream.next() {
+        // metadata entries, which have an empty key, aren't keys
+        if entry.first() == Some(&SENTINEL) {
+            continue;
+        }
+        entries += 1;
+        // a map's entries are just the keys, which may contain the sentinel themselves
+        let key = match layout {
+            Layout::Map => entry,
+            Layout::Sentinel => entry.split(|&b| b == SENTINEL).next().unwrap_or_default(),
+        };
+        if entries > 1 && key == lastkey.as_slice() {
+            continue;
+        }
+        keys += 1;
+        lastkey.clear();
+        lastkey.extend_from_slice(key);
+        if let Some(n) = args.prefix_histogram {
+            let prefix = &key[..key.len().min(n)];
+            *histogram.entry(prefix.to_vec()).or_default() += 1;
+        }
+    }
+
+    writeln!(out, "path: {}", args.fst)?;
+    writeln!(out, "size: {} bytes", fst.as_bytes().len())?;
+    writeln!(out, "entries: {entries}")?;
+    writeln!(out, "keys: {keys}")?;
+    if meta(&fst, META_SALT).is_some() {
+        writeln!(out, "hashed keys: yes")?;
+    }
+    if let Some(key_type) = meta(&fst, META_KEY_TYPE) {
+        writeln!(out, "key type: {}", key_type.escape_ascii())?;
+    }
+    if let Some(steps) = meta(&fst, META_NORMALIZE) {
+        writeln!(out, "key normalization: {}", steps.escape_ascii())?;
+    }
+    if let Some(compression) = meta(&fst, META_COMPRESSION) {
+        writeln!(out, "value compression: {}", compression.escape_ascii())?;
+    }
+    if let Some(dict) = meta(&fst, META_ZSTD_DICT) {
+        writeln!(out, "value dictionary: {} bytes", dict.len())?;
+    }
+    if layout == Layout::Map {
+        let path = sidecar_path(&args.fst);
+        let store = ValueStore::open_for(&fst, &path)?;
+        writeln!(
+            out,
+            "value store: {path}, {} values, {} bytes",
+            store.len(),
+            store.size()
+        )?;
+    }
+
+    if args.prefix_histogram.is_some() {
+        let mut buckets: Vec<(Vec<u8>, u64)> = histogram.into_iter().collect();
+        buckets.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        writeln!(out, "prefixes: {}", buckets.len())?;
+        for (prefix, count) in buckets {
+            writeln!(
+                out,
+                "{}\t{count}\t{:.2}%",
+                prefix.escape_ascii(),
+                count as f64 * 100.0 / keys.max(1) as f64
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -74,3 +74,83 @@ pub fn jsonquotes_range_iter<'a>(
         IterArrayChunks::array_chunks::<2>(JsonQuotes::new(haystack)).map(move |[a, b]| (a, b + 1)),
     )
 }
+
+/// Which json strings to search, by the member names on their path. A string is selected when
+/// one of the names enclosing it is included (or nothing is), and none is excluded. Member
+/// names themselves are never selected
+#[derive(Default)]
+pub struct JsonFields {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl JsonFields {
+    /// Whether a string is selected by the member names of the objects enclosing it
+    fn selects(&self, path: &[Option<&[u8]>]) -> bool {
+        let named = |names: &[String]| {
+            path.iter()
+                .flatten()
+                .any(|key| names.iter().any(|name| name.as_bytes() == *key))
+        };
+        (self.include.is_empty() || named(&self.include)) && !named(&self.exclude)
+    }
+}
+
+/// Like jsonquotes_range_iter, but only the string values selected by `fields`. Tracks just
+/// enough of the structure between strings, the brackets and colons, to know the path of
+/// member names leading to each string. Names are compared as written, without unescaping
+pub struct JsonValues<'a> {
+    haystack: &'a [u8],
+    ranges: Box<dyn Iterator<Item = (usize, usize)> + 'a>,
+    fields: &'a JsonFields,
+    /// The objects and arrays the scan is inside of, with the name of the current member of
+    /// each object
+    stack: Vec<Option<&'a [u8]>>,
+    pos: usize,
+}
+
+impl<'a> JsonValues<'a> {
+    pub fn new(haystack: &'a [u8], fields: &'a JsonFields) -> Self {
+        Self {
+            haystack,
+            ranges: jsonquotes_range_iter(haystack),
+            fields,
+            stack: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for JsonValues<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (start, end) in self.ranges.by_ref() {
+            // only structural characters, numbers and literals lie between strings
+            for &b in &self.haystack[self.pos..start] {
+                match b {
+                    b'{' | b'[' => self.stack.push(None),
+                    b'}' | b']' => {
+                        self.stack.pop();
+                    }
+                    _ => {}
+                }
+            }
+            self.pos = end;
+            let is_name = self.haystack[end..]
+                .iter()
+                .find(|b| !b.is_ascii_whitespace())
+                == Some(&b':');
+            if is_name {
+                if let Some(key) = self.stack.last_mut() {
+                    *key = Some(&self.haystack[start + 1..end - 1]);
+                }
+                continue;
+            }
+            if self.fields.selects(&self.stack) {
+                return Some((start, end));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,74 @@
+//! fstsed as a library: build fst databases from json records and search text for their keys.
+//! The command line tool and the python bindings are both thin layers over these modules.
+//!
+//! Cargo features trim what's compiled for embedders that only need key highlighting:
+//! - `values`: decompress the values stored with keys
+//! - `json`: template fields looked up in json values, and json search mode
+//! - `color`: ansi highlighting of rendered matches
+//! - `cli` (default): everything the fstsed command needs, including building databases
+//! - `unicode` (default): unicode word boundaries and graphemes with the regex crate. Without
+//!   it, std's character classes approximate them and the regex crate isn't linked
+//! - `parquet`: the command's --parquet input
+//! - `sqlite`: the command's --from-sqlite builds, with SQLite compiled in
+
+#[cfg(feature = "cli")]
+pub mod autotune;
+#[cfg(feature = "cli")]
+pub mod bench;
+#[cfg(feature = "cli")]
+pub mod build;
+pub mod charset;
+#[cfg(feature = "cli")]
+pub mod compact;
+#[cfg(feature = "cli")]
+pub mod config;
+#[cfg(feature = "cli")]
+pub mod delimited;
+#[cfg(feature = "json")]
+pub mod diagnostics;
+#[cfg(feature = "json")]
+pub mod enrich;
+#[cfg(feature = "cli")]
+pub mod feed;
+#[cfg(feature = "cli")]
+pub mod follow;
+pub mod fstsed;
+pub mod hashed;
+#[cfg(feature = "cli")]
+pub mod info;
+#[cfg(feature = "json")]
+pub mod jsonquotes;
+#[cfg(feature = "cli")]
+pub mod misp;
+#[cfg(feature = "parquet")]
+pub mod parquet_input;
+#[cfg(feature = "cli")]
+pub mod profile;
+#[cfg(feature = "cli")]
+pub mod recipe;
+#[cfg(feature = "cli")]
+pub mod reload;
+#[cfg(feature = "cli")]
+pub mod remote;
+#[cfg(feature = "cli")]
+pub mod serve;
+#[cfg(feature = "cli")]
+pub mod show;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+#[cfg(feature = "cli")]
+pub mod stix;
+#[cfg(feature = "color")]
+pub mod style;
+pub mod template;
+pub mod timings;
+#[cfg(feature = "cli")]
+pub mod transform;
+#[cfg(feature = "json")]
+pub mod ttl;
+#[cfg(feature = "cli")]
+pub mod update;
+pub mod valuestore;
+#[cfg(feature = "cli")]
+pub mod verify;
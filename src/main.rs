@@ -1,19 +1,28 @@
-use crate::jsonquotes::jsonquotes_range_iter;
-use anyhow::{bail, Error, Result};
+use ::fstsed::autotune::Tuning;
+use ::fstsed::fstsed::Decoration;
+use ::fstsed::jsonquotes::JsonFields;
+#[cfg(feature = "sqlite")]
+use ::fstsed::sqlite;
+use ::fstsed::{
+    bench, build, charset, compact, config, delimited, diagnostics, enrich, feed, follow, fstsed,
+    hashed, info, profile, recipe, reload, remote, serve, show, stats, style, timings, transform,
+    ttl, update, valuestore, verify,
+};
+use anyhow::{anyhow, bail, Context, Error, Result};
 use bstr::io::BufReadExt;
 use camino::Utf8PathBuf;
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use grep_cli::{self, stdout};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{self, BufReader, IsTerminal, Write};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
 use std::path::Path;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use termcolor::ColorChoice;
 
-pub mod build;
-pub mod fstsed;
-pub mod jsonquotes;
-
 const BUFFERSIZE: usize = 64 * 1024;
 
 // via https://github.com/sstadick/hck/blob/master/src/main.rs#L90
@@ -30,70 +39,1028 @@ fn is_broken_pipe(err: &Error) -> bool {
 
 // via https://github.com/sstadick/crabz/blob/ce0d69efe0628c56b1fb7a1de46798b95eef90aa/src/main.rs#L62
 /// Get a buffered input reader from stdin or a file
-fn get_input(path: Option<Utf8PathBuf>) -> Result<Box<dyn BufReadExt + Send + 'static>> {
+fn get_input(
+    path: Option<Utf8PathBuf>,
+    capacity: usize,
+) -> Result<Box<dyn BufReadExt + Send + 'static>> {
     let reader: Box<dyn BufReadExt + Send + 'static> = match path {
         Some(path) => {
             if path.as_os_str() == "-" {
-                Box::new(BufReader::with_capacity(BUFFERSIZE, io::stdin()))
+                Box::new(BufReader::with_capacity(capacity, io::stdin()))
             } else {
-                Box::new(BufReader::with_capacity(BUFFERSIZE, File::open(path)?))
+                Box::new(BufReader::with_capacity(capacity, File::open(path)?))
             }
         }
-        None => Box::new(BufReader::with_capacity(BUFFERSIZE, io::stdin())),
+        None => Box::new(BufReader::with_capacity(capacity, io::stdin())),
     };
     Ok(reader)
 }
 
+// Set when an input couldn't be opened and was skipped. The run goes on with the other inputs
+// but fails at the end, as grep does
+static SKIPPED_INPUT: AtomicBool = AtomicBool::new(false);
+
+/// Opens the inputs to search. With --auto-tune, the first chunk of the first input is
+/// sampled to choose the read buffer size used from then on, and the thread count to decorate
+/// with
+struct InputOpener {
+    bufsize: usize,
+    autotune: bool,
+    /// The thread count chosen by --auto-tune, once the first input is sampled
+    threads: Option<usize>,
+    verbose: bool,
+    /// With --follow, the input that is followed rather than read to its end
+    follow: Option<Utf8PathBuf>,
+}
+
+impl InputOpener {
+    fn new(args: &Args) -> Self {
+        Self {
+            bufsize: BUFFERSIZE,
+            autotune: args.auto_tune && !args.deterministic,
+            threads: None,
+            verbose: args.verbose,
+            follow: args
+                .input
+                .last()
+                .filter(|path| args.follow && path.as_os_str() != "-")
+                .cloned(),
+        }
+    }
+
+    /// Open the next input. Inputs that can't be opened are reported and skipped (None) so one
+    /// bad path doesn't abort a run over many files, though the run then exits 2
+    fn open(
+        &mut self,
+        path: Utf8PathBuf,
+        fsed: &fstsed::FstSed,
+    ) -> Result<Option<Box<dyn BufReadExt + Send + 'static>>> {
+        let opened = if self.follow.as_ref() == Some(&path) {
+            follow::Follow::open(path.clone())
+                .map(|f| Box::new(BufReader::with_capacity(self.bufsize, f)) as Box<_>)
+                .map_err(Error::from)
+        } else {
+            get_input(Some(path.clone()), self.bufsize)
+        };
+        let mut reader = match opened {
+            Ok(reader) => reader,
+            Err(e) => {
+                diagnostics::warn(
+                    "skipped_file",
+                    format!("{path}: {e}"),
+                    serde_json::json!({ "file": path.as_str(), "error": e.to_string() }),
+                );
+                SKIPPED_INPUT.store(true, Ordering::Relaxed);
+                return Ok(None);
+            }
+        };
+        if self.autotune {
+            self.autotune = false;
+            // fill_buf peeks at the chunk without consuming it, so this works for stdin too
+            let tuning = Tuning::sample(reader.fill_buf()?, fsed);
+            if self.verbose {
+                tuning.report();
+            }
+            if tuning.bufsize != self.bufsize {
+                self.bufsize = tuning.bufsize;
+                // the sampled chunk is still held by the inner reader and gets drained first
+                reader = Box::new(BufReader::with_capacity(self.bufsize, reader));
+            }
+            self.threads = Some(tuning.threads);
+        }
+        Ok(Some(reader))
+    }
+}
+
+/// Where the search modes write: standard output, or with --output a temporary file next to
+/// the destination that is renamed over it once the run has succeeded
+enum Sink {
+    Stdout(grep_cli::StandardStream),
+    File {
+        file: io::BufWriter<File>,
+        temp: Utf8PathBuf,
+        path: Utf8PathBuf,
+        done: bool,
+    },
+}
+
+impl Sink {
+    /// Flush the output and, for --output, move the finished file into place
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            Sink::Stdout(out) => out.flush()?,
+            Sink::File {
+                file,
+                temp,
+                path,
+                done,
+            } => {
+                file.flush()?;
+                file.get_ref().sync_all()?;
+                std::fs::rename(&*temp, &*path)
+                    .with_context(|| format!("Error renaming {temp} to {path}"))?;
+                *done = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for Sink {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Stdout(out) => out.write(buf),
+            Sink::File { file, .. } => file.write(buf),
+        }
+    }
+
+    #[inline]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Stdout(out) => out.write_all(buf),
+            Sink::File { file, .. } => file.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Stdout(out) => out.flush(),
+            Sink::File { file, .. } => file.flush(),
+        }
+    }
+}
+
+// a run that fails part way leaves the destination as it was, without a half written file
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if let Sink::File {
+            temp, done: false, ..
+        } = self
+        {
+            let _ = std::fs::remove_file(&*temp);
+        }
+    }
+}
+
+/// Marks the end of a line cut short by --max-output-line-bytes
+const TRUNCATED: &[u8] = "…".as_bytes();
+/// The escape termcolor ends colored text with
+const COLOR_RESET: &[u8] = b"\x1b[0m";
+
+/// The output of the search modes. With --max-output-line-bytes, each line is held back until
+/// it's complete so that it can be cut to the limit
+struct Output {
+    sink: Sink,
+    max_line: Option<usize>,
+    line: Vec<u8>,
+}
+
+impl Output {
+    fn finish(mut self) -> Result<()> {
+        if !self.line.is_empty() {
+            self.end_line(false)?;
+        }
+        self.sink.finish()
+    }
+
+    // Write out the held line, cut to the limit and marked if it's too long
+    fn end_line(&mut self, newline: bool) -> io::Result<()> {
+        let max = self.max_line.unwrap_or(usize::MAX);
+        if self.line.len() > max {
+            // colored lines keep room to turn the color off before the marker
+            let mut reserve = TRUNCATED.len();
+            if memchr::memchr(0x1b, &self.line).is_some() {
+                reserve += COLOR_RESET.len();
+            }
+            let mut cut = max.saturating_sub(reserve);
+            // don't split a utf-8 character, or an emoji or accented letter made of several
+            cut = fstsed::floor_grapheme_boundary(&self.line, cut);
+            // nor a color escape. A color left on would bleed into the marker and what follows
+            let kept = &self.line[..cut];
+            let mut colored = false;
+            if let Some(esc) = memchr::memrchr(0x1b, kept) {
+                match memchr::memchr(b'm', &kept[esc..]) {
+                    None => cut = esc,
+                    Some(end) => colored = &kept[esc..esc + end + 1] != COLOR_RESET,
+                }
+            }
+            self.sink.write_all(&self.line[..cut])?;
+            if colored {
+                self.sink.write_all(COLOR_RESET)?;
+            }
+            self.sink.write_all(TRUNCATED)?;
+            stats::count(stats::Stat::Truncated);
+        } else {
+            self.sink.write_all(&self.line)?;
+        }
+        if newline {
+            self.sink.write_all(b"\n")?;
+        }
+        self.line.clear();
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        let Some(max) = self.max_line else {
+            return self.sink.write_all(buf);
+        };
+        while !buf.is_empty() {
+            let end = memchr::memchr(b'\n', buf);
+            let piece = &buf[..end.unwrap_or(buf.len())];
+            // one byte past the limit is enough to know the line has to be cut
+            let room = (max + 1).saturating_sub(self.line.len());
+            self.line.extend_from_slice(&piece[..piece.len().min(room)]);
+            match end {
+                Some(i) => {
+                    self.end_line(true)?;
+                    buf = &buf[i + 1..];
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/// Open the output of the search modes. Following a file, each line is written out as soon as
+/// it's processed rather than when the buffer fills
+fn output(args: &Args, color: ColorChoice) -> Result<Output> {
+    let sink = match &args.output {
+        None if args.follow => Sink::Stdout(grep_cli::stdout_buffered_line(color)),
+        None => Sink::Stdout(stdout(color)),
+        Some(path) => {
+            // the temporary file goes in the same directory so the rename can't cross
+            // filesystems
+            let name = path.file_name().unwrap_or("output");
+            let temp = path.with_file_name(format!(".{name}.fstsed-{}.tmp", std::process::id()));
+            let file = File::create(&temp)
+                .with_context(|| format!("Error creating temporary output file {temp}"))?;
+            Sink::File {
+                file: io::BufWriter::with_capacity(BUFFERSIZE, file),
+                temp,
+                path: path.clone(),
+                done: false,
+            }
+        }
+    };
+    Ok(Output {
+        sink,
+        max_line: args.max_output_line_bytes,
+        line: Vec::new(),
+    })
+}
+
+// Build a glob set from the --include or --exclude patterns
+fn globset(patterns: &[String]) -> Result<globset::GlobSet> {
+    let mut builder = globset::GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(globset::Glob::new(pattern)?);
+    }
+    Ok(builder.build()?)
+}
+
+// Replace directories in the inputs with the files inside them, in name order, when searching
+// recursively. Without -r directories are reported and skipped like other unreadable inputs
+fn expand_inputs(args: &Args) -> Result<Vec<Utf8PathBuf>> {
+    let include = globset(&args.include)?;
+    let exclude = globset(&args.exclude)?;
+    let mut inputs = Vec::with_capacity(args.input.len());
+
+    for path in &args.input {
+        if !path.is_dir() {
+            inputs.push(path.clone());
+            continue;
+        }
+        if !args.recursive {
+            diagnostics::warn(
+                "skipped_file",
+                format!("{path}: is a directory, use -r to search it"),
+                serde_json::json!({ "file": path.as_str(), "error": "is a directory" }),
+            );
+            SKIPPED_INPUT.store(true, Ordering::Relaxed);
+            continue;
+        }
+        let walker = walkdir::WalkDir::new(path).sort_by_file_name();
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    diagnostics::warn(
+                        "skipped_file",
+                        format!("{path}: {e}"),
+                        serde_json::json!({ "file": path.as_str(), "error": e.to_string() }),
+                    );
+                    SKIPPED_INPUT.store(true, Ordering::Relaxed);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let Ok(file) = Utf8PathBuf::from_path_buf(entry.into_path()) else {
+                continue;
+            };
+            // globs match the path below the directory given, so *.log matches a/b.log too
+            let relative = file.strip_prefix(path).unwrap_or(&file);
+            if (!args.include.is_empty() && !include.is_match(relative))
+                || exclude.is_match(relative)
+            {
+                continue;
+            }
+            inputs.push(file);
+        }
+    }
+    Ok(inputs)
+}
+
+/// The -H and -n prefixes of the output lines of an input, like grep's file:line:
+struct LinePrefix {
+    /// The file name and colon, empty without -H
+    file: String,
+    line_number: bool,
+}
+
+impl LinePrefix {
+    fn new(with_filename: bool, line_number: bool, path: &Utf8PathBuf) -> Self {
+        Self {
+            file: if with_filename {
+                format!("{}:", input_name(path))
+            } else {
+                String::new()
+            },
+            line_number,
+        }
+    }
+
+    #[inline]
+    fn write<W: Write>(&self, out: &mut W, lineno: usize) -> io::Result<()> {
+        out.write_all(self.file.as_bytes())?;
+        if self.line_number {
+            write!(out, "{lineno}:")?;
+        }
+        Ok(())
+    }
+}
+
+/// Display name of an input for use in output
+fn input_name(path: &Utf8PathBuf) -> String {
+    if path.as_os_str() == "-" {
+        "(standard input)".to_string()
+    } else {
+        path.to_string()
+    }
+}
+
 #[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// Show only nonempty parts of lines that match
     #[clap(short, long)]
     only_matching: bool,
 
-    /// Use markers to highlight the matching strings
-    #[clap(short = 'C', long, value_enum, default_value_t = ArgsColorChoice::Auto)]
-    color: ArgsColorChoice,
+    /// With -o, the separator written between the matches of a line. Backslash escapes \t, \n,
+    /// \r, \0 and \\ are understood. Defaults to a newline, or a tab with --group-per-line
+    #[clap(long, value_name = "STR", requires = "only_matching")]
+    match_sep: Option<String>,
+
+    /// With -o, write all the matches of a line on one output line, separated by --match-sep
+    #[clap(long, requires = "only_matching")]
+    group_per_line: bool,
+
+    /// With -o, print each match after its input, line number and byte span, as
+    /// file:line:start-end:, where start and end are the offsets of its first byte and just
+    /// past its last byte from the beginning of the input, as with grep -b
+    #[clap(
+        short = 'b',
+        long,
+        requires = "only_matching",
+        conflicts_with = "group_per_line"
+    )]
+    byte_offset: bool,
+
+    /// Invert the sense of matching: print only the lines that contain no fst key at all
+    #[clap(short = 'v', long)]
+    invert_match: bool,
+
+    /// Suppress normal output and print the number of matching lines per input file. With
+    /// -v, count the lines without matches instead
+    #[clap(short = 'c', long)]
+    count: bool,
+
+    /// Print nothing, and stop reading at the first matching line (with -v, the first line
+    /// without a match). For shell conditionals, which test the exit status instead
+    #[clap(
+        short = 'q',
+        long,
+        conflicts_with_all = ["build", "count", "pairs", "output"]
+    )]
+    quiet: bool,
 
-    /// Specify fst db to use in search or to create in build mode
+    /// Stop reading an input after N selected lines: lines with matches or, with -v, lines
+    /// without. Decorated output ends with the Nth matching line
+    #[clap(short = 'm', long, value_name = "N")]
+    max_count: Option<usize>,
+
+    /// With -c, also print the total number of matches in each input file
+    #[clap(long)]
+    count_matches: bool,
+
+    /// Instead of decorating the input, count how often each pair of distinct keys occurs on
+    /// the same line and print the pairs, most frequent first, as count, key and key separated
+    /// by tabs. Useful for finding related infrastructure across many logs
+    #[clap(long, conflicts_with_all = ["count", "invert_match", "follow"])]
+    pairs: bool,
+
+    /// With --pairs, print only the N most frequent pairs
+    #[clap(long, value_name = "N", requires = "pairs")]
+    pairs_top: Option<usize>,
+
+    /// Use markers to highlight the matching strings. Defaults to auto, or to the color of the
+    /// config file's [defaults]
+    #[clap(short = 'C', long, value_enum)]
+    color: Option<ArgsColorChoice>,
+
+    /// How colored matches look, bold red by default. STYLE is a comma separated list of a
+    /// color name or ansi number, bg:COLOR, bold, dimmed, italic, underline, strikethrough,
+    /// intense or none, e.g. "yellow,underline". FIELD=STYLE styles one field of the template
+    /// within the match, e.g. "actor=cyan,bold". May be given more than once
+    #[clap(long, value_name = "STYLE")]
+    color_style: Vec<String>,
+
+    /// Specify fst db to use in search or to create in build mode. A search defaults to
+    /// $FSTSED_DB, else to the fst of the config file's [defaults]. A search may name an
+    /// http(s):// or s3:// url instead, which is downloaded to $FSTSED_CACHE (~/.cache/fstsed)
+    /// and revalidated by its ETag on later runs
     #[clap(short = 'f', value_name = "FST", value_hint = clap::ValueHint::FilePath)]
-    fst: Utf8PathBuf,
+    fst: Option<Utf8PathBuf>,
+
+    /// Run the jobs of a yaml recipe, each with its own db, inputs, template, options and
+    /// output, instead of a job given on the command line. See the recipe module docs for the
+    /// format. Exits 0 if any job matched
+    #[clap(long, value_name = "FILE", exclusive = true)]
+    recipe: Option<Utf8PathBuf>,
+
+    /// Print what this build of fstsed supports as json and exit: its version, compiled
+    /// features, subcommands, input formats, db layouts and value store versions, key
+    /// normalizations, key types and build profiles. For tooling that checks a host before
+    /// running a recipe on it. The members of the object are only ever added to
+    #[clap(long, exclusive = true)]
+    capabilities: bool,
 
     /// Build mode. Build a fst from json data instead of querying one. Specify output path with
-    /// the -f --fst parameter. Only first file input parameter or stdin is used to make
-    /// the fst
+    /// the -f --fst parameter. The records of all the file inputs, or of stdin, go into the fst
     #[clap(long)]
     build: bool,
 
+    /// When building a fst, read the input files with N threads, each sorting what it reads
+    /// into a shard, and merge the shards at the end. Speeds up builds from many feed files
+    #[clap(long, value_name = "N", requires = "build")]
+    build_threads: Option<usize>,
+
+    /// When building a fst, write a json summary of it to PATH, or to stdout for -: the records
+    /// stored, skipped and blank, the keys read, distinct and duplicated, the size of the db
+    /// and its value store, the time taken and the db's metadata. Lets a pipeline check a
+    /// build before deploying it
+    #[clap(long, value_name = "PATH", requires = "build")]
+    build_summary_json: Option<Utf8PathBuf>,
+
+    /// When building a fst, print the build summary of --build-summary-json as one json line
+    /// on stderr in place of the progress messages, for ci logs to assert on
+    #[clap(long, requires = "build")]
+    stats_json: bool,
+
     /// When building a fst, extract the given json field to use as the key in the fst database.
-    /// Key may also be provided as a jsonpointer, e.g. /obj/array/1/item
-    #[clap(short = 'k', long, value_name = "KEY", default_value = "key")]
-    key: Option<String>,
+    /// Key may also be provided as a jsonpointer, e.g. /obj/array/1/item. Repeat -k for
+    /// records with several key fields, e.g. -k domain -k /aliases. A field holding an array
+    /// adds each of its strings as a key. All of a record's keys store the same value.
+    /// Defaults to "key", or to the --profile's key fields
+    #[clap(short = 'k', long, value_name = "KEY")]
+    key: Vec<String>,
+
+    /// When building a fst, preset the key fields (including aliases) and --value-transform
+    /// for a well known feed format. -k and --value-transform override the preset
+    #[clap(long, value_enum, value_name = "PROFILE")]
+    profile: Option<profile::BuildProfile>,
+
+    /// When building a fst, read the inputs as a well known delimited indicator feed, each
+    /// line a record of the feed's columns under consistent names, keyed by its indicator
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FEED",
+        requires = "build",
+        conflicts_with = "profile"
+    )]
+    feed_preset: Option<feed::FeedPreset>,
+
+    /// When building a fst, read the inputs as delimited lines (comma separated, or tab with
+    /// --tsv) and make each a record of the given columns, e.g. key=1,first_seen=3: a comma
+    /// separated list of field names and 1-based column positions. Lines starting with # are
+    /// skipped. Overrides the columns of a --feed-preset
+    #[clap(
+        long,
+        value_name = "NAME=COLUMN,..",
+        requires = "build",
+        conflicts_with = "profile"
+    )]
+    map: Option<String>,
+
+    /// When building a fst, read the records from the rows of --query against this SQLite db
+    /// instead of from input files. Each row is a record of its columns by name, with columns
+    /// holding json text, e.g. from json(), nested as json. The key defaults to the first
+    /// column. Needs fstsed built with the sqlite feature
+    #[clap(
+        long,
+        value_name = "DB",
+        requires_all = ["build", "query"],
+        conflicts_with_all = ["input", "build_threads"]
+    )]
+    from_sqlite: Option<Utf8PathBuf>,
 
-    /// When building a fst, set this if the keys of input json are already lexicographically sorted.
-    /// This will make build construction much faster. If this is set but the keys are not sorted,
-    /// the fst creation will error
+    /// The query whose rows --from-sqlite builds from, e.g.
+    /// "SELECT indicator, json(details) AS details FROM iocs"
+    #[clap(long, value_name = "SQL", requires = "from_sqlite")]
+    query: Option<String>,
+
+    /// When building a fst, set this if the keys of input json are already lexicographically
+    /// sorted. This will make build construction much faster. If this is set but the keys are not
+    /// sorted, the fst creation will error
     #[clap(long)]
     sorted: bool,
 
-    /// Specify the format of the fstsed match decoration. Field names are enclosed in {},
-    /// for example "{field1} any fixed string {field2} & {field3}". Fields may be json keys
-    /// or jsonpointers {/obj/array/1/item}
+    /// With --sorted, check that the keys really are in order while reading, and fail with the
+    /// line number and the offending pair of keys at the first one that isn't
+    #[clap(long, requires = "sorted")]
+    verify_order: bool,
+
+    /// When building a fst, store salted hashes of the keys rather than the keys themselves,
+    /// and leave the key fields out of the stored records, so that a db of sensitive terms can
+    /// be handed out without revealing them. Such a db is searched token by token: a match
+    /// must start and end at word boundaries
+    #[clap(long, requires = "build")]
+    hash_keys: bool,
+
+    /// When building a fst, train a zstd dictionary on a sample of the values, store it in the
+    /// fst, and compress every value with it. Small json values that look alike barely
+    /// compress on their own but shrink a lot with a dictionary. Needs a single input, and
+    /// holds the uncompressed values in memory until the dictionary is trained
+    #[clap(long, requires = "build", conflicts_with = "build_threads")]
+    train_dict: bool,
+
+    /// When building a fst, the zstd level to compress values at, from 1 (fastest) to 22
+    /// (smallest). Higher levels suit cold archival dbs, and only slow down the build
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(i32).range(1..=22),
+        requires = "build"
+    )]
+    compress_level: i32,
+
+    /// When building a fst, store values uncompressed, for latency-sensitive lookups of small
+    /// values. The db records this, so searches need no option to read it
+    #[clap(long, requires = "build", conflicts_with_all = ["compress_level", "train_dict"])]
+    no_compress: bool,
+
+    /// With --hash-keys, the salt to hash with instead of a random one
+    #[clap(long, value_name = "SALT", requires = "hash_keys")]
+    hash_salt: Option<String>,
+
+    /// When building a fst, declare what kind of keys it holds. The type is stored in the db,
+    /// and searches with it pick their rules by themselves: domain, ip and hash keys are
+    /// stored in lowercase and matched regardless of case, candidates are filtered by the
+    /// type's charset, and domains also match as the parent of longer names but never end
+    /// before a further label (evil.com doesn't match in evil.com.au). Domain keys aren't
+    /// checked against the public suffix list. For those types {key} renders the stored
+    /// lowercase key and {match} the text as written
+    #[clap(long, value_enum, value_name = "TYPE", requires = "build")]
+    key_type: Option<charset::KeyType>,
+
+    /// When building a fst, canonicalize the keys with these comma separated steps, in order:
+    /// lowercase, trim (surrounding whitespace) and nfc (unicode composed form). Keys changed
+    /// by them are kept as they were in the stored record's "original_keys" array. Lowercased
+    /// dbs are searched regardless of case
+    #[clap(
+        long,
+        value_enum,
+        value_name = "STEPS",
+        value_delimiter = ',',
+        requires = "build"
+    )]
+    normalize_keys: Vec<build::KeyNormalization>,
+
+    /// When building a fst, what to store for a key found in more than one record: the value
+    /// of the first or last of them, a json array of their distinct values (merge), or fail
+    /// the build (error). Without it every value is stored, though searches only find the one
+    /// that sorts first. Needs the inputs read by a single thread
+    #[clap(long, value_enum, value_name = "POLICY", requires = "build")]
+    on_duplicate: Option<build::OnDuplicate>,
+
+    /// When building a fst, how to store the values. map writes them to a value store file
+    /// next to the fst, FST.values, which must be kept with it. sentinel keeps everything in
+    /// one file, which older versions of fstsed can read too. Searches read either layout
+    #[clap(
+        long,
+        value_enum,
+        value_name = "LAYOUT",
+        default_value_t,
+        requires = "build"
+    )]
+    layout: valuestore::Layout,
+
+    /// When building a fst, reshape each json record before storing it as the value. A comma
+    /// separated projection list of fields (name), jsonpointers (/obj/item), renames
+    /// (newname=/obj/item) and object lifts (/obj/*), e.g. "actor=/attribution/name,/meta/*"
+    #[clap(long, value_name = "EXPR")]
+    value_transform: Option<String>,
+
+    /// When building a fst, store only these comma separated top level fields of each json
+    /// record as the value, e.g. actor,confidence,source, leaving out large fields searches
+    /// don't use. Overrides the --profile's value transform
+    #[clap(
+        long,
+        value_name = "FIELDS",
+        value_delimiter = ',',
+        requires = "build",
+        conflicts_with = "value_transform"
+    )]
+    keep_fields: Vec<String>,
+
+    /// Specify the format of the fstsed match decoration. Field names are enclosed in {}, for
+    /// example "{field1} any fixed string {field2} & {field3}". Fields may be json keys or
+    /// jsonpointers {/obj/array/1/item}, or {match} for the matched text verbatim, or {match_id}
+    /// for the number of the match in the run. When the text only matched once normalized, e.g.
+    /// case folded for a db of lowercase keys, {normalized_by} names how ("case-fold") and
+    /// {normalized} is the normalized form; both are empty otherwise. Numbers and booleans render
+    /// as written, arrays of them are joined with commas, and nested objects render as json. Fields
+    /// may be followed by filters, e.g. {name|upper}, {org|trim|default:unknown}; the filters are
+    /// upper, lower, trim and default:TEXT for empty or missing values. A - inside the braces, as
+    /// in {-tag} or {tag-}, removes the whitespace on that side of the field when it renders empty.
+    /// Use @name for a template from the [templates] table of the config file
+    /// (~/.config/fstsed/config.toml or $FSTSED_CONFIG). Defaults to $FSTSED_TEMPLATE, then the
+    /// template of the config file's [defaults], else "<{key}|{value}>" for wrap decorations,
+    /// "<{value}>" for before and after, and "{value}" for replace
     #[clap(short, long)]
     template: Option<String>,
 
-    /// Json search mode. Fstsed will treat input as json, searching only inside quoted json strings.
-    /// All strings are deserialized/decoded before json before searching, and all template
+    /// Squeeze each run of spaces and tabs in rendered templates down to a single space, so
+    /// that empty optional fields don't leave double spaces behind
+    #[clap(long)]
+    collapse_whitespace: bool,
+
+    /// Render this template for every match as its pivot url, e.g.
+    /// "https://intel.example/indicator/{key}", with the same fields as --template. Match
+    /// records and --enrich entries carry it as "link", and with --color always each
+    /// decorated match becomes a terminal hyperlink (OSC 8) to it
+    #[clap(long, value_name = "URL")]
+    link_template: Option<String>,
+
+    /// Where to put the rendered template relative to the matched text. "wrap" and "replace"
+    /// put it in place of the matched text (include {key} to keep it), "before" and "after"
+    /// insert it next to the matched text
+    #[clap(long, value_enum, value_name = "POSITION", default_value_t = Decoration::Wrap)]
+    decorate: Decoration,
+
+    /// Substitution mode, shorthand for --decorate replace. Replace each matched key with its
+    /// rendered template. Without a --template, matches are replaced by their stored value
+    #[clap(long)]
+    replace: bool,
+
+    /// Json search mode. Fstsed will treat input as json, searching only inside quoted json
+    /// strings. All strings are deserialized/decoded before json before searching, and all template
     /// decorations are properly json-encoded in the output for subsequent processing
     #[clap(short, long)]
     json: bool,
 
+    /// Csv search mode. Only search inside the fields of comma separated input, each unquoted
+    /// before searching, and quote decorated fields as needed so the output is still valid
+    /// csv. Records must be one per line
+    #[clap(long, conflicts_with_all = ["json", "tsv"])]
+    csv: bool,
+
+    /// Like --csv, for tab separated input
+    #[clap(long, conflicts_with = "json")]
+    tsv: bool,
+
+    /// Read parquet files instead of text, search the string --column of each row, and write
+    /// the rows as ndjson, enriched as with --enrich. Needs fstsed built with the parquet
+    /// feature
+    #[clap(
+        long,
+        requires = "column",
+        conflicts_with_all = ["build", "json", "csv", "tsv", "output_format"]
+    )]
+    parquet: bool,
+
+    /// With --parquet, the name of the column to search
+    #[clap(long, value_name = "NAME", requires = "parquet")]
+    column: Option<String>,
+
+    /// With --csv or --tsv, only search these columns: a comma separated list of 1-based
+    /// positions or header names, e.g. 3,7 or url,referrer. Naming a column makes the first
+    /// line of each input a header, which is copied through unsearched
+    #[clap(long, value_name = "COLUMNS", default_value = "")]
+    columns: String,
+
+    /// In json mode, search only string values, skipping the member names of objects, so that
+    /// a key such as "status" doesn't match field names
+    #[clap(long)]
+    json_values_only: bool,
+
+    /// In json mode, only search the string values under these member names, at any depth,
+    /// e.g. user,url,message. Member names themselves are then never searched
+    #[clap(long, value_name = "NAMES", value_delimiter = ',')]
+    json_fields: Vec<String>,
+
+    /// In json mode, don't search the string values under these member names, e.g. raw,signature
+    #[clap(long, value_name = "NAMES", value_delimiter = ',')]
+    json_exclude_fields: Vec<String>,
+
+    /// Match keys anywhere, including inside larger tokens, rather than only as whole words.
+    /// Useful for hash fragments and indicators embedded in other strings
+    #[clap(long)]
+    no_boundary: bool,
+
+    /// Use unicode word boundaries: matches may start after any unicode non-word character,
+    /// such as ideographic spaces and CJK punctuation, not just the ascii delimiters. Note
+    /// this includes "." so evil.com also matches inside sub.evil.com. Slower
+    #[clap(long, conflicts_with = "no_boundary")]
+    unicode: bool,
+
+    /// Match keys as prefixes of longer tokens: a key matches any token that starts with it,
+    /// e.g. the key cmd.exe /c in cmd.exe /cwhoami, and the whole token is decorated
+    #[clap(long, conflicts_with = "no_boundary")]
+    prefix: bool,
+
+    /// Only try to match where the text has the shape of the db's keys: hex strings, ascii
+    /// alphanumerics, domain names or ip addresses. Cuts the work on prose-heavy input, but
+    /// keys of another shape are missed
+    #[clap(long, value_enum, value_name = "CHARSET")]
+    token_charset: Option<charset::TokenCharset>,
+
+    /// Guarantee that only matched text changes. In json mode, strings without matches are
+    /// copied byte for byte instead of being re-encoded, and fstsed stops with an error if
+    /// re-encoding a string with matches would alter its untouched bytes, e.g. \u escapes
+    #[clap(long)]
+    passthrough_strict: bool,
+
+    /// Search binary and mixed text, e.g. payloads extracted from a pcap. Bytes that aren't
+    /// utf-8 already pass through untouched outside of matches; this also renders keys and
+    /// values that aren't utf-8 with replacement characters rather than <keyerror> and
+    /// <valueerror>, and in json mode searches strings that aren't utf-8 as their raw bytes
+    #[clap(long)]
+    binary: bool,
+
+    /// Cache up to N recently failed candidate prefixes to skip repeated fst walks for
+    /// highly repetitive input, e.g. the same non-matching hostname millions of times
+    #[clap(long, value_name = "N")]
+    miss_cache: Option<usize>,
+
+    /// Cache the decompressed values of up to N recently matched keys, for input that hits
+    /// the same keys over and over
+    #[clap(long, value_name = "N")]
+    value_cache: Option<usize>,
+
+    /// Refuse stored values that decompress to more than N bytes, so a corrupt or hostile db
+    /// can't make one match allocate gigabytes. Refused values render as <decompressionerror>
+    /// and are reported to the --errors channel
+    #[clap(long, value_name = "N", default_value_t = fstsed::ValueLimits::default().max_value_bytes)]
+    max_value_bytes: usize,
+
+    /// Refuse stored values whose json arrays and objects nest deeper than N levels
+    #[clap(long, value_name = "N", default_value_t = fstsed::ValueLimits::default().max_json_depth)]
+    max_json_depth: usize,
+
+    /// Ignore keys whose record has expired per --ttl-field, as if they weren't in the fst. Use
+    /// `fstsed compact` to drop them from the fst for good
+    #[clap(long)]
+    respect_ttl: bool,
+
+    /// Field name or jsonpointer of the records' expiration time: seconds since the epoch, or
+    /// a utc date or rfc 3339 date time. Records without it never expire
+    #[clap(long, value_name = "FIELD", default_value = "expires")]
+    ttl_field: String,
+
+    /// Sample the first chunk of input to measure per-line cost and hit rate, then pick the read
+    /// buffer size and, unless --threads is given, how many threads decorate with it. The
+    /// candidate pre-filters, --token-charset and --miss-cache, are left as given. Use --verbose
+    /// to print the choices
+    #[clap(long)]
+    auto_tune: bool,
+
+    /// Decorate with N threads, each searching a batch of lines at a time. The output is still
+    /// written in input order, byte for byte what one thread writes. Only for the default
+    /// decorating mode, and templates can't use {match_id}
+    #[clap(
+        long,
+        value_name = "N",
+        conflicts_with_all = [
+            "build", "follow", "json", "enrich", "csv", "tsv", "parquet", "only_matching",
+            "count", "pairs", "quiet", "invert_match"
+        ]
+    )]
+    threads: Option<usize>,
+
+    /// With --threads, write each batch of lines as soon as it's decorated instead of in input
+    /// order, for the most throughput when the order of the lines doesn't matter
+    #[clap(long, requires = "threads", conflicts_with = "max_count")]
+    unordered: bool,
+
+    /// Only treat lines with at least N distinct matched keys as matching. Other lines are
+    /// dropped from the output (or, with -v, kept) and not counted by -c
+    #[clap(long, value_name = "N")]
+    min_matches: Option<usize>,
+
+    /// Sum the numeric FIELD (a field name or jsonpointer) of the values of all the matches on
+    /// a line into a line score, available to templates as {line_score} and added to the
+    /// --output-format json records
+    #[clap(long, value_name = "FIELD")]
+    score: Option<String>,
+
+    /// Only treat lines whose --score adds up to at least N as matching, like --min-matches
+    #[clap(long, value_name = "N", requires = "score")]
+    min_score: Option<f64>,
+
+    /// Report where the time of the search went (candidate scanning, fst walks, decompression,
+    /// json parsing, rendering, and the rest: io and bookkeeping) to stderr at the end. Timing
+    /// every step adds some overhead of its own
+    #[clap(long)]
+    timings: bool,
+
+    /// Keep reading the last input file as it grows, like tail -f, decorating new lines as
+    /// they're written. Truncated and rotated files are picked up again from the start. A fst
+    /// db replaced on disk, by renaming a new build over it, is reopened between lines
+    #[clap(long, conflicts_with_all = ["build", "count"])]
+    follow: bool,
+
+    /// Print counts of matches, of expired keys ignored with --respect-ttl, and of the
+    /// positions the fst was searched from, to stderr at the end
+    #[clap(long)]
+    stats: bool,
+
+    /// Disable caches and timing-based tuning so that every run does exactly the same work,
+    /// for testing fstsed itself
+    #[clap(long, hide = true)]
+    deterministic: bool,
+
+    /// Print diagnostic details, such as --auto-tune measurements, to stderr
+    #[clap(long)]
+    verbose: bool,
+
+    /// Output format. "text" decorates the input in place, "json" instead emits one json
+    /// record per match with the file, line number, byte offset, match id, key and decoded
+    /// value. Match ids count the matches of the run from 1 in input order, like {match_id}
+    /// in templates, so records can be joined to the decorations of a text run. Matches found
+    /// by normalizing the text also get its normalized_by and normalized fields
+    #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+
+    /// Cut output lines longer than N bytes down to N, ending them with "…". Protects systems
+    /// downstream with hard message size limits from lines that ballooned with decorations or
+    /// enrichment. --stats counts the truncated lines
+    #[clap(long, value_name = "N")]
+    max_output_line_bytes: Option<usize>,
+
+    /// Split the input into records at SEP rather than at newlines, e.g. "\r\n\r\n" for
+    /// blocks separated by blank lines. Backslash escapes \t, \n, \r, \0 and \\ are
+    /// understood. Each record keeps its separator in the output
+    #[clap(long, value_name = "SEP", conflicts_with = "max_output_line_bytes")]
+    record_sep: Option<String>,
+
+    /// Input records end with a NUL byte rather than a newline, as from find -print0. The
+    /// same as --record-sep '\0'
+    #[clap(
+        short = 'z',
+        long,
+        conflicts_with_all = ["record_sep", "max_output_line_bytes"]
+    )]
+    null_data: bool,
+
+    /// Guard against huge lines, e.g. a minified json dump on a single line: a line longer
+    /// than N bytes is handled as --long-lines says, without holding more than N bytes of it
+    #[clap(long, value_name = "N")]
+    max_line_bytes: Option<usize>,
+
+    /// What to do with a line longer than --max-line-bytes. Chunks are cut after the last
+    /// ascii delimiter, so only keys spanning a delimiter can be split across two of them.
+    /// Each chunk counts as a line, e.g. for -c and -n
+    #[clap(long, value_enum, value_name = "ACTION", default_value_t = LongLines::Chunk)]
+    long_lines: LongLines,
+
+    /// Write warnings (bad json lines, skipped files, decompression failures) as ndjson records
+    /// to this path, or to this file descriptor if given a number, instead of as text on stderr
+    #[clap(long, value_name = "PATH|FD")]
+    errors: Option<String>,
+
+    /// Json enrichment mode. Implies --json. Rather than decorating strings in place, leave them
+    /// untouched and add a "fstsed" array field to each json object line with matches, holding
+    /// the matched keys and their decoded values
+    #[clap(long)]
+    enrich: bool,
+
+    /// With --enrich, put the array at this jsonpointer instead of a top level "fstsed" field,
+    /// e.g. /threat/matches. Missing objects along the path are created. The line is then
+    /// re-serialized rather than spliced, so insignificant whitespace is not preserved
+    #[clap(long, value_name = "POINTER", requires = "enrich")]
+    enrich_path: Option<String>,
+
+    /// With --enrich, what to do with lines that already have the enrichment array from an
+    /// earlier pass: leave them as they are (skip), add new matches to the array (merge), or
+    /// recompute it (replace). The old array is never searched itself
+    #[clap(long, value_enum, default_value_t = EnrichMode::Replace, requires = "enrich")]
+    enrich_mode: EnrichMode,
+
+    /// Search the files in directories given as input, and in their subdirectories
+    #[clap(short = 'r', long)]
+    recursive: bool,
+
+    /// With -r, only search files whose path below the directory matches the glob, e.g.
+    /// '*.log'. May be given more than once
+    #[clap(long, value_name = "GLOB", requires = "recursive")]
+    include: Vec<String>,
+
+    /// With -r, skip files whose path below the directory matches the glob, e.g. '*.gz'. May
+    /// be given more than once, and wins over --include
+    #[clap(long, value_name = "GLOB", requires = "recursive")]
+    exclude: Vec<String>,
+
+    /// With --output-format json, leave out the matches already recorded in this ndjson file
+    /// of an earlier run's records, e.g. to report only what's new since yesterday's sweep of
+    /// the same archive. Matches are the same when their file, line, offset and key are
+    #[clap(long, value_name = "PATH")]
+    baseline: Option<Utf8PathBuf>,
+
+    /// Prefix each output line with the name of the input it came from and a colon
+    #[clap(short = 'H', long)]
+    with_filename: bool,
+
+    /// Prefix each output line with its line number in the input, counting from 1, and a
+    /// colon. Follows the file name with -H
+    #[clap(short = 'n', long)]
+    line_number: bool,
+
+    /// Write the output to this file instead of stdout. It's written to a temporary file in
+    /// the same directory first and renamed into place only when the run succeeds, so readers
+    /// never see a partial file and a failed run leaves an existing file untouched
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["build", "follow"])]
+    output: Option<Utf8PathBuf>,
+
     /// Input file(s) to process (either to search or to use to build the fst). Leave empty or
     /// use "-" to read from stdin
     #[clap(value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
     input: Vec<Utf8PathBuf>,
 }
 
+impl Args {
+    /// The -f fst path, which clap requires unless a subcommand is given
+    fn fst(&self) -> &Utf8PathBuf {
+        self.fst.as_ref().expect("-f is required")
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Describe a fst db: size, entry and key counts, and key prefix statistics
+    Info(info::InfoArgs),
+    /// Serve key lookups and ndjson enrichment from a fst db over http
+    Serve(serve::ServeArgs),
+    /// Copy a fst db without the entries whose records have expired
+    Compact(compact::CompactArgs),
+    /// Pretty-print the record of one key of a fst db, and the template fields it fills
+    Show(show::ShowArgs),
+    /// Copy a fst db with new json records added, replacing the values of keys already in it
+    Update(update::UpdateArgs),
+    /// Check a fst db for corrupt entries and values that don't decompress or parse
+    Verify(verify::VerifyArgs),
+    /// Time the matcher over an input: lines and MB per second, candidates tested and matches
+    Bench(bench::BenchArgs),
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
 enum ArgsColorChoice {
     Always,
@@ -101,20 +1068,262 @@ enum ArgsColorChoice {
     Auto,
 }
 
-fn main() -> Result<()> {
-    let mut args = Args::parse();
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+enum EnrichMode {
+    Skip,
+    Merge,
+    Replace,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+enum LongLines {
+    /// Leave the line out, with a warning
+    Skip,
+    /// Keep its first N bytes, with a warning
+    Truncate,
+    /// Search it in chunks of up to N bytes
+    Chunk,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+// Exit like grep: 0 when something matched, 1 when nothing did and 2 on errors. Commands that
+// don't search, such as --build, exit 0 on success
+/// How a run ended, which picks the exit code as in grep: 0 when a line matched, 1 when none
+/// did, and 2 when an input couldn't be read whether or not lines matched
+#[derive(Default)]
+struct Outcome {
+    matched: bool,
+    failed: bool,
+}
+
+fn main() {
+    match try_main() {
+        Ok(Outcome { failed: true, .. }) => exit(2),
+        Ok(Outcome { matched: true, .. }) => {}
+        Ok(_) => exit(1),
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            exit(2);
+        }
+    }
+}
+
+fn try_main() -> Result<Outcome> {
+    let args = Args::parse();
+    if args.capabilities {
+        println!("{}", capabilities());
+        return Ok(Outcome {
+            matched: true,
+            failed: false,
+        });
+    }
+    match &args.recipe {
+        Some(path) => run_recipe(path),
+        None => run_args(args),
+    }
+}
+
+// What this build supports, for --capabilities
+fn capabilities() -> serde_json::Value {
+    fn names<T: ValueEnum>() -> Vec<String> {
+        T::value_variants()
+            .iter()
+            .filter_map(|v| Some(v.to_possible_value()?.get_name().to_string()))
+            .collect()
+    }
+    let features: Vec<&str> = [
+        ("cli", cfg!(feature = "cli")),
+        ("json", cfg!(feature = "json")),
+        ("values", cfg!(feature = "values")),
+        ("color", cfg!(feature = "color")),
+        ("unicode", cfg!(feature = "unicode")),
+        ("parquet", cfg!(feature = "parquet")),
+        ("sqlite", cfg!(feature = "sqlite")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    let mut inputs = vec!["text", "json", "ndjson", "csv", "tsv"];
+    if cfg!(feature = "parquet") {
+        inputs.push("parquet");
+    }
+    let mut build_sources = vec!["ndjson", "delimited"];
+    if cfg!(feature = "sqlite") {
+        build_sources.push("sqlite");
+    }
+    let subcommands: Vec<String> = Args::command()
+        .get_subcommands()
+        .map(|command| command.get_name().to_string())
+        .collect();
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": features,
+        "subcommands": subcommands,
+        "server": subcommands.iter().any(|name| name == "serve"),
+        "inputs": inputs,
+        "build_sources": build_sources,
+        "db": {
+            "layouts": names::<valuestore::Layout>(),
+            "value_store_versions": [valuestore::FORMAT_VERSION],
+            "compression": ["none", "zstd", "zstd_dict"],
+            "url_schemes": ["http", "https", "s3"],
+        },
+        "key_normalizations": names::<build::KeyNormalization>(),
+        "key_types": names::<charset::KeyType>(),
+        "profiles": names::<profile::BuildProfile>(),
+        "feed_presets": names::<feed::FeedPreset>(),
+        "output_formats": names::<OutputFormat>(),
+    })
+}
+
+// Fill in the options not given on the command line from $FSTSED_DB and $FSTSED_TEMPLATE, then
+// from the [defaults] of the config file
+fn apply_defaults(args: &mut Args, defaults: &config::Defaults) -> Result<()> {
+    let var = |name| {
+        std::env::var(name)
+            .ok()
+            .filter(|value: &String| !value.is_empty())
+    };
+    if args.fst.is_none() && !args.build {
+        args.fst = var("FSTSED_DB")
+            .or_else(|| defaults.fst.clone())
+            .map(Utf8PathBuf::from);
+    }
+    if args.template.is_none() {
+        args.template = var("FSTSED_TEMPLATE").or_else(|| defaults.template.clone());
+    }
+    if !args.unicode && !args.no_boundary {
+        match defaults.boundaries {
+            Some(config::Boundaries::Unicode) => args.unicode = true,
+            Some(config::Boundaries::Off) => args.no_boundary = true,
+            Some(config::Boundaries::Ascii) | None => {}
+        }
+    }
+    if let (None, Some(color)) = (args.color, &defaults.color) {
+        args.color = Some(
+            ArgsColorChoice::from_str(color, true)
+                .map_err(|e| anyhow!("bad color {color:?} in the config file: {e}"))?,
+        );
+    }
+    Ok(())
+}
+
+// Decorate with the threads of the config file's [defaults], once the template is known. Only
+// the default decorating mode has threads, and they can't number matches in input order
+fn apply_default_threads(args: &mut Args, defaults: &config::Defaults) {
+    if args.threads.is_none() && threads_allowed(args) {
+        args.threads = defaults.threads;
+    }
+}
+
+// Whether the run decorates in the default mode with a template that doesn't number matches,
+// the only kind of run that can be split across threads
+fn threads_allowed(args: &Args) -> bool {
+    let decorating = !(args.build
+        || args.follow
+        || args.json
+        || args.enrich
+        || args.csv
+        || args.tsv
+        || args.parquet
+        || args.only_matching
+        || args.count
+        || args.pairs
+        || args.quiet
+        || args.invert_match)
+        && args.output_format == OutputFormat::Text
+        && args.command.is_none();
+    let numbers_matches = args
+        .template
+        .as_deref()
+        .is_some_and(|t| t.contains("match_id"));
+    decorating && !numbers_matches
+}
+
+// Run each job of a recipe in turn as if its command line had been given
+fn run_recipe(path: &Utf8PathBuf) -> Result<Outcome> {
+    let recipe = recipe::Recipe::load(path)?;
+    let base = match path.parent() {
+        Some(dir) if !dir.as_str().is_empty() => dir,
+        _ => camino::Utf8Path::new("."),
+    };
+    let mut outcome = Outcome::default();
+    for (i, job) in recipe.jobs.iter().enumerate() {
+        let context = || format!("{} of recipe {path}", job.describe(i));
+        let args =
+            Args::try_parse_from(job.args(base).with_context(context)?).with_context(context)?;
+        let job = run_args(args).with_context(context)?;
+        outcome.matched |= job.matched;
+        outcome.failed |= job.failed;
+    }
+    Ok(outcome)
+}
 
+fn run_args(mut args: Args) -> Result<Outcome> {
     // if no files specified, add stdin
     if args.input.is_empty() {
         args.input.push(Utf8PathBuf::from("-"));
     }
 
+    if let Some(spec) = &args.errors {
+        diagnostics::open_channel(spec)?;
+    }
+    let config = config::Config::load()?;
+    apply_defaults(&mut args, &config.defaults)?;
+    if args.fst.is_none() && args.command.is_none() {
+        bail!("no fst db given: use -f FST, set $FSTSED_DB, or set fst in the [defaults] of the config file");
+    }
+    // a db given as a url is searched from its copy in the cache
+    if let Some(url) = args
+        .fst
+        .clone()
+        .filter(|fst| remote::is_remote(fst.as_str()))
+    {
+        if args.build {
+            bail!("--build writes a local fst, not {url}");
+        }
+        args.fst = Some(remote::fetch(url.as_str())?);
+    }
+    if !args.build {
+        args.input = expand_inputs(&args)?;
+    }
+    if args.baseline.is_some() && args.output_format != OutputFormat::Json {
+        bail!("--baseline compares match records, so it needs --output-format json");
+    }
+    if args.threads.is_some() && args.output_format == OutputFormat::Json {
+        bail!("--threads only applies to the default decorating mode, not --output-format json");
+    }
+
+    if args.enrich {
+        args.json = true;
+    }
+    if args.replace {
+        args.decorate = Decoration::Replace;
+    }
+    // -t @name selects a named template from the config file
+    if let Some(name) = args.template.as_deref().and_then(|t| t.strip_prefix('@')) {
+        args.template = Some(config.template(name)?.to_string());
+    }
+    apply_default_threads(&mut args, &config.defaults);
+    if args.template.is_none() {
+        args.template = match args.decorate {
+            Decoration::Wrap => None,
+            Decoration::Before | Decoration::After => Some("<{value}>".to_string()),
+            Decoration::Replace => Some("{value}".to_string()),
+        };
+    }
+
     // determine appropriate colormode. auto simply
     // tests if stdout is a tty (if so, then yes color)
     // or otherwise don't color if it's to a file or another pipe
-    let colormode = match args.color {
+    let colormode = match args.color.unwrap_or(ArgsColorChoice::Auto) {
         ArgsColorChoice::Auto => {
-            if std::io::stdout().is_terminal() {
+            if args.output.is_none() && std::io::stdout().is_terminal() {
                 ColorChoice::Always
             } else {
                 ColorChoice::Never
@@ -124,127 +1333,1490 @@ fn main() -> Result<()> {
         ArgsColorChoice::Never => ColorChoice::Never,
     };
 
+    let timings = args.timings;
+    let started = std::time::Instant::now();
+    if timings {
+        timings::enable();
+    }
+    let show_stats = args.stats;
+    if show_stats {
+        stats::enable();
+    }
+    let quiet = args.quiet;
+
     // invoke the command!
-    if let Err(e) = if args.build {
-        run_build(args)
+    let matched = match if let Some(Command::Info(info)) = &args.command {
+        info::run_info(info, &mut stdout(ColorChoice::Never)).map(|_| true)
+    } else if let Some(Command::Serve(serve)) = &args.command {
+        serve::run_serve(serve).map(|_| true)
+    } else if let Some(Command::Compact(compact)) = &args.command {
+        compact::run_compact(compact).map(|_| true)
+    } else if let Some(Command::Show(show)) = &args.command {
+        show::run_show(show, &mut stdout(show.color()))
+    } else if let Some(Command::Update(update)) = &args.command {
+        update::run_update(update).map(|_| true)
+    } else if let Some(Command::Verify(verify)) = &args.command {
+        verify::run_verify(verify, &mut stdout(ColorChoice::Never))
+    } else if let Some(Command::Bench(bench)) = &args.command {
+        bench::run_bench(bench, &mut stdout(ColorChoice::Never)).map(|_| true)
+    } else if args.build {
+        run_build(args).map(|_| true)
+    } else if args.quiet {
+        run_quiet(args)
+    } else if args.pairs {
+        run_pairs(args)
+    } else if args.count {
+        run_count(args)
+    } else if args.invert_match {
+        run_invert(args)
+    } else if args.output_format == OutputFormat::Json {
+        run_structured(args)
     } else if args.only_matching {
         run_onlymatching(args, colormode)
+    } else if args.parquet {
+        run_parquet(args)
+    } else if args.enrich {
+        run_enrich(args)
     } else if args.json {
         runjson(args, colormode)
+    } else if args.csv || args.tsv {
+        run_delimited(args)
+    } else if args.threads.is_some()
+        || (args.auto_tune && !args.deterministic && threads_allowed(&args))
+    {
+        let threads = args.threads;
+        run_parallel(args, colormode, threads)
     } else {
         run(args, colormode)
     } {
+        Ok(matched) => matched,
         // safely ignore broken pipes, e.g. head
-        if is_broken_pipe(&e) {
-            exit(0);
-        }
-        return Err(e);
+        Err(e) if is_broken_pipe(&e) => exit(0),
+        Err(e) => return Err(e),
+    };
+    if timings {
+        timings::report(started.elapsed());
     }
-    Ok(())
+    if show_stats {
+        stats::report();
+    }
+    // like grep, -q that found a match succeeds even when some input couldn't be read
+    let skipped = SKIPPED_INPUT.swap(false, Ordering::Relaxed);
+    Ok(Outcome {
+        matched,
+        failed: skipped && !(quiet && matched),
+    })
 }
 
-#[inline]
+/// Open the fstsed db for searching with all of the matcher options in args
+fn open_fstsed(args: &Args, color: ColorChoice) -> Result<fstsed::FstSed> {
+    let styles = style::ColorStyles::parse(&args.color_style)?;
+    let mut fsed = fstsed::FstSed::new(args.fst().clone(), args.template.clone())?
+        .with_color_styles(color, &styles)?
+        .with_decoration(args.decorate);
+    if args.collapse_whitespace {
+        fsed = fsed.with_collapsed_whitespace();
+    }
+    if let Some(template) = &args.link_template {
+        fsed = fsed.with_link_template(template)?;
+        // cutting long lines could split a hyperlink escape
+        if color == ColorChoice::Always && args.max_output_line_bytes.is_none() {
+            fsed = fsed.with_hyperlinks();
+        }
+    }
+    // any field selection skips member names, values only just selects all the values
+    if args.json_values_only || !args.json_fields.is_empty() || !args.json_exclude_fields.is_empty()
+    {
+        fsed = fsed.with_json_fields(JsonFields {
+            include: args.json_fields.clone(),
+            exclude: args.json_exclude_fields.clone(),
+        });
+    }
+    if args.no_boundary {
+        fsed = fsed.without_boundaries();
+    }
+    if let Some(charset) = args.token_charset {
+        fsed = fsed.with_token_charset(charset);
+    }
+    if args.unicode {
+        fsed = fsed.with_unicode_boundaries();
+    }
+    if args.prefix {
+        fsed = fsed.with_prefix_matching();
+    }
+    if args.binary {
+        fsed = fsed.with_lossy_utf8();
+    }
+    if let Some(capacity) = args.miss_cache {
+        fsed = fsed.with_miss_cache(capacity);
+    }
+    if let Some(capacity) = args.value_cache {
+        fsed = fsed.with_value_cache(capacity);
+    }
+    fsed = fsed.with_value_limits(fstsed::ValueLimits {
+        max_value_bytes: args.max_value_bytes,
+        max_json_depth: args.max_json_depth,
+    });
+    if args.deterministic {
+        fsed = fsed.deterministic();
+    }
+    if args.respect_ttl {
+        fsed = fsed.with_expiry(ttl::Expiry::new(&args.ttl_field));
+    }
+    Ok(fsed)
+}
+
+#[inline]
 fn run_build(args: Args) -> Result<()> {
     // ensure the fst path does not already exist. don't want to overwrite
-    if Path::new(&args.fst).exists() {
-        bail!("fst path {} already exists. Please specify an alternate path or rename/delete existing fst.", &args.fst);
+    if Path::new(args.fst()).exists() {
+        bail!("fst path {} already exists. Please specify an alternate path or rename/delete existing fst.", args.fst());
+    }
+    // lines of a delimited feed are made json records of the mapped columns
+    let columns = match (&args.map, args.feed_preset) {
+        (Some(spec), preset) => {
+            let delimiter = match preset {
+                Some(preset) => preset.delimiter(),
+                None if args.tsv => b'\t',
+                None => b',',
+            };
+            Some(feed::ColumnMap::parse(spec, delimiter)?)
+        }
+        (None, Some(preset)) => Some(preset.column_map()),
+        (None, None) => None,
+    };
+    // the rows of a query stand in for the input, keyed by its first column by default
+    #[cfg(feature = "sqlite")]
+    let query = match (&args.from_sqlite, &args.query) {
+        (Some(db), Some(sql)) => Some(sqlite::Query::open(db, sql)?),
+        _ => None,
+    };
+    #[cfg(feature = "sqlite")]
+    let first_column = query
+        .as_ref()
+        .map(|q| q.first_column())
+        .transpose()?
+        .flatten();
+    #[cfg(not(feature = "sqlite"))]
+    let first_column: Option<String> = match args.from_sqlite {
+        Some(_) => {
+            bail!("--from-sqlite is not available: fstsed was built without the sqlite feature")
+        }
+        None => None,
+    };
+    let records = match (
+        columns,
+        args.profile.and_then(|profile| profile.flattener()),
+    ) {
+        (Some(columns), _) => {
+            let flatten = |text: Vec<u8>| columns.records(&text);
+            Some(read_flattened_inputs(&args.input, &flatten)?)
+        }
+        (None, Some(flatten)) => Some(read_flattened_inputs(&args.input, &flatten)?),
+        (None, None) => None,
+    };
+    let keys = match (args.key.as_slice(), args.profile) {
+        ([], Some(profile)) => profile.keys(),
+        ([], None) => vec![build::KeySpec::Field(
+            first_column.unwrap_or_else(|| "key".to_string()),
+        )],
+        (keys, _) => keys.iter().cloned().map(build::KeySpec::Field).collect(),
+    };
+    let transform = if args.keep_fields.is_empty() {
+        args.value_transform
+            .as_deref()
+            .or(args.profile.map(|p| p.value_transform()))
+            .map(transform::ValueTransform::parse)
+            .transpose()?
+    } else {
+        Some(transform::ValueTransform::keep_fields(&args.keep_fields)?)
+    };
+    let hasher = args.hash_keys.then(|| {
+        let salt = args
+            .hash_salt
+            .clone()
+            .unwrap_or_else(hashed::KeyHasher::random_salt);
+        hashed::KeyHasher::new(salt.as_bytes())
+    });
+    let options = build::BuildOptions {
+        transform: transform.as_ref(),
+        hasher: hasher.as_ref(),
+        key_type: args.key_type,
+        normalize: &args.normalize_keys,
+        on_duplicate: args.on_duplicate,
+        train_dict: args.train_dict,
+        compression: if args.no_compress {
+            build::Compression::None
+        } else {
+            build::Compression::Zstd(args.compress_level)
+        },
+        layout: args.layout,
+        quiet: args.stats_json,
+    };
+    let order = match (args.sorted, args.verify_order) {
+        (true, true) => build::InputOrder::Verified,
+        (true, false) => build::InputOrder::Sorted,
+        (false, _) => build::InputOrder::Unsorted,
+    };
+    #[cfg(feature = "sqlite")]
+    if let Some(query) = &query {
+        let rows =
+            |record: &mut dyn FnMut(serde_json::Value) -> Result<()>| query.for_each_record(record);
+        let summary = build::build_fstsed_records(rows, &keys, args.fst(), order, &options)?;
+        return report_build(&args, &summary);
+    }
+    let summary = if let Some(records) = &records {
+        build::build_fstsed(records.as_slice(), &keys, args.fst(), order, &options)?
+    } else if args.input.len() > 1 || args.build_threads.is_some() {
+        let threads = args.build_threads.unwrap_or(1);
+        build::build_fstsed_parallel(&args.input, &keys, args.fst(), threads, &options)?
+    } else {
+        let reader = get_input(args.input.first().cloned(), BUFFERSIZE).expect("need some input");
+        build::build_fstsed(reader, &keys, args.fst(), order, &options)?
+    };
+    report_build(&args, &summary)
+}
+
+// Write the summary of a build where --build-summary-json and --stats-json ask for it
+fn report_build(args: &Args, summary: &build::BuildSummary) -> Result<()> {
+    if let Some(path) = &args.build_summary_json {
+        let mut json = serde_json::to_vec(summary)?;
+        json.push(b'\n');
+        if path.as_str() == "-" {
+            io::stdout().write_all(&json)?;
+        } else {
+            std::fs::write(path, json)
+                .with_context(|| format!("Error writing build summary {path}"))?;
+        }
     }
-    // currently, just grab the first input item
-    let reader = get_input(args.input.first().cloned()).expect("need some input");
-    build::build_fstsed(reader, &args.key.unwrap(), &args.fst, args.sorted)
+    if args.stats_json {
+        eprintln!("{}", serde_json::to_string(summary)?);
+    }
+    Ok(())
+}
+
+// The records of the inputs of a feed with nested exports, flattened to one per line
+fn read_flattened_inputs(
+    inputs: &[Utf8PathBuf],
+    flatten: &dyn Fn(Vec<u8>) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let mut records = Vec::new();
+    for input in inputs {
+        let mut text = Vec::new();
+        get_input(Some(input.clone()), BUFFERSIZE)
+            .and_then(|mut reader| Ok(io::Read::read_to_end(&mut reader, &mut text)?))
+            .with_context(|| format!("Error reading {input}"))?;
+        records.extend(flatten(text).with_context(|| format!("Error reading {input}"))?);
+        // keep the last line of one input from running into the first of the next
+        if records.last().is_some_and(|&b| b != b'\n') {
+            records.push(b'\n');
+        }
+    }
+    Ok(records)
+}
+
+// An error processing a line, saying where the line was read from. The kind is kept, so a
+// closed pipe is still recognized as one
+fn line_error(name: &str, lineno: usize, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{name}:{lineno}: {e}"))
 }
 
 // Generic processing function that we use in all modes to search the given
-// input wth the given fstsed db and write to the given output
+// input wth the given fstsed db and write to the given output. Returns whether there was a match
 #[inline]
-fn process_line<W>(input: &[u8], fsed: &fstsed::FstSed, out: &mut W) -> Result<(), Error>
+fn process_line<W>(
+    input: &[u8],
+    fsed: &fstsed::FstSed,
+    line_score: Option<f64>,
+    out: &mut W,
+) -> io::Result<bool>
 where
     W: Write + Send + 'static,
 {
     let mut _lastpos: usize = 0;
+    let mut matched = false;
     // process each line
     for m in fsed.find_iter(input) {
         // print gap from last match to current match
-        out.write_all(&input[_lastpos..m])?;
+        out.write_all(&input[_lastpos..m.start])?;
         // print rendered match
-        out.write_all(fsed.get_match().render().as_bytes())?;
+        let rendered = fsed
+            .get_match(&m)
+            .with_line_score(line_score)
+            .with_match_id(fsed.next_match_id())
+            .render();
+        out.write_all(rendered.as_bytes())?;
         // advance the position past our match length
-        _lastpos = m + fsed.get_match_len();
+        _lastpos = m.end;
+        matched = true;
     }
     // print remainder
     out.write_all(&input[_lastpos..])?;
 
-    Ok(())
+    Ok(matched)
+}
+
+// With --follow, the db is opened again when its file is replaced, so a long running tail
+// picks up a rebuilt fst without being restarted
+fn follow_reloads(args: &Args, color: ColorChoice) -> Result<Option<reload::Reloading<'_>>> {
+    if !args.follow {
+        return Ok(None);
+    }
+    let live = reload::Reloading::new(args.fst().clone(), move || open_fstsed(args, color))?;
+    Ok(Some(live))
+}
+
+/// How the input is split into records: at newlines unless --record-sep or -z. Records longer
+/// than --max-line-bytes are skipped, truncated or searched in pieces, per --long-lines
+struct Records {
+    terminator: Vec<u8>,
+    max_bytes: Option<usize>,
+    long_lines: LongLines,
+}
+
+impl Records {
+    fn new(args: &Args) -> Result<Self> {
+        let terminator = match &args.record_sep {
+            _ if args.null_data => vec![0],
+            Some(sep) if sep.is_empty() => bail!("--record-sep can't be empty"),
+            Some(sep) => unescape_sep(sep).into_bytes(),
+            None => vec![b'\n'],
+        };
+        if args.max_line_bytes == Some(0) {
+            bail!("--max-line-bytes must be at least 1");
+        }
+        Ok(Self {
+            terminator,
+            max_bytes: args.max_line_bytes,
+            long_lines: args.long_lines,
+        })
+    }
+
+    /// Call `f` with each record of `reader`, terminator included, until it returns false.
+    /// Without a length limit and with a single byte terminator this is bstr's record reader,
+    /// as for_byte_line_with_terminator is. Otherwise no more than the limit is ever held
+    fn for_each<R, F>(&self, reader: &mut R, mut f: F) -> io::Result<()>
+    where
+        R: BufRead,
+        F: FnMut(&[u8]) -> io::Result<bool>,
+    {
+        let sep = &self.terminator[..];
+        if let (None, [byte]) = (self.max_bytes, sep) {
+            return reader.for_byte_record_with_terminator(*byte, f);
+        }
+        let limit = self
+            .max_bytes
+            .map_or(usize::MAX, |max| max.saturating_add(sep.len()));
+        let last = sep[sep.len() - 1];
+        let mut record = Vec::new();
+        loop {
+            // read up to each occurrence of the terminator's last byte until the record ends
+            // with all of it, or reaches the limit
+            let room = (limit - record.len()) as u64;
+            let read = io::Read::take(&mut *reader, room).read_until(last, &mut record)?;
+            if record.ends_with(sep) {
+                if !f(&record)? {
+                    return Ok(());
+                }
+                record.clear();
+            } else if read == 0 {
+                if !record.is_empty() {
+                    f(&record)?;
+                }
+                return Ok(());
+            } else if record.len() == limit && !self.long_record(reader, &mut record, &mut f)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Handle a record that reached the limit before its terminator. Returns whether to go on
+    fn long_record<R, F>(&self, reader: &mut R, record: &mut Vec<u8>, f: &mut F) -> io::Result<bool>
+    where
+        R: BufRead,
+        F: FnMut(&[u8]) -> io::Result<bool>,
+    {
+        let max = self.max_bytes.unwrap_or(usize::MAX);
+        if self.long_lines == LongLines::Chunk {
+            // cut after the last delimiter, so the token at the end carries over whole into the
+            // next piece. A key spanning delimiters can still be split
+            let cut = record[..max]
+                .iter()
+                .rposition(|&b| fstsed::is_delimiter(b))
+                .map_or(max, |i| i + 1);
+            let go_on = f(&record[..cut])?;
+            record.drain(..cut);
+            return Ok(go_on);
+        }
+        // a terminator may have started in the part cut off
+        let mut tail = record[max + 1..].to_vec();
+        record.truncate(max);
+        let ended = self.skip_rest(reader, &mut tail)?;
+        let action = match self.long_lines {
+            LongLines::Skip => "skipped",
+            _ => "truncated",
+        };
+        diagnostics::warn(
+            "long_line",
+            format!("{action} a line of more than {max} bytes"),
+            serde_json::json!({ "max_line_bytes": max, "action": action }),
+        );
+        let mut go_on = true;
+        if self.long_lines == LongLines::Truncate {
+            if ended {
+                record.extend_from_slice(&self.terminator);
+            }
+            go_on = f(record)?;
+        }
+        record.clear();
+        Ok(go_on)
+    }
+
+    // Read past the rest of a long record, holding only enough of it to spot the terminator.
+    // Whether the terminator was found rather than the end of the input
+    fn skip_rest<R: BufRead>(&self, reader: &mut R, tail: &mut Vec<u8>) -> io::Result<bool> {
+        let sep = &self.terminator[..];
+        loop {
+            if tail.ends_with(sep) {
+                return Ok(true);
+            }
+            tail.drain(..tail.len().saturating_sub(sep.len() - 1));
+            if io::Read::take(&mut *reader, BUFFERSIZE as u64)
+                .read_until(sep[sep.len() - 1], tail)?
+                == 0
+            {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// A record without its terminator. A newline's \r before it goes too
+    fn body<'a>(&self, record: &'a [u8]) -> &'a [u8] {
+        match &self.terminator[..] {
+            b"\n" => delimited::strip_terminator(record),
+            sep => record.strip_suffix(sep).unwrap_or(record),
+        }
+    }
 }
 
 // Basic mode
 #[inline]
-fn run(args: Args, colormode: ColorChoice) -> Result<(), Error> {
-    let mut out = stdout(colormode);
-    let fsed = fstsed::FstSed::new(args.fst, args.template, colormode);
+fn run(args: Args, colormode: ColorChoice) -> Result<bool, Error> {
+    let mut out = output(&args, colormode)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let filter = LineFilter::new(&args, false);
+    let with_filename = args.with_filename;
+    let line_number = args.line_number;
+    let live = follow_reloads(&args, colormode)?;
+    let mut fsed = match &live {
+        Some(live) => live.current(),
+        None => Arc::new(open_fstsed(&args, colormode)?),
+    };
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
+
+    for path in args.input.iter().cloned() {
+        limit.reset();
+        let prefix = LinePrefix::new(with_filename, line_number, &path);
+        let name = input_name(&path);
+        let mut lineno: usize = 0;
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            lineno += 1;
+            if limit.reached() {
+                return Ok(false);
+            }
+            if let Some(reloaded) = live.as_ref().and_then(|live| live.reloaded()) {
+                fsed = reloaded;
+            }
+            if filter.active() && !filter.keep(line, &fsed) {
+                return Ok(true);
+            }
+            let line_score = filter.score(line, &fsed);
+            prefix.write(&mut out, lineno)?;
+            if process_line(line, &fsed, line_score, &mut out)
+                .map_err(|e| line_error(&name, lineno, e))?
+            {
+                matched = true;
+                limit.count();
+            }
+            Ok(!limit.reached())
+        })?;
+    }
+    out.finish()?;
+    Ok(matched)
+}
+
+// lines and bytes read into a batch for a --threads worker, whichever fills first
+const BATCH_LINES: usize = 1024;
+const BATCH_BYTES: usize = 256 * 1024;
+
+/// Lines read for a --threads worker to decorate
+struct Batch {
+    seq: u64,
+    /// Which input the lines are from, for -m
+    file: usize,
+    prefix: Arc<LinePrefix>,
+    /// Line number of the first line
+    lineno: usize,
+    lines: Vec<u8>,
+    /// Where each line ends in lines, terminator included
+    ends: Vec<usize>,
+}
+
+/// A batch decorated by a --threads worker
+struct Decorated {
+    seq: u64,
+    file: usize,
+    text: Vec<u8>,
+    /// Where the output of each line ends in text, and whether the line matched
+    ends: Vec<(usize, bool)>,
+}
+
+// Decorate the lines of a batch as run does, into a buffer
+fn decorate_batch(batch: Batch, fsed: &fstsed::FstSed, filter: &LineFilter) -> Result<Decorated> {
+    let mut text = Vec::with_capacity(batch.lines.len() * 2);
+    let mut ends = Vec::with_capacity(batch.ends.len());
+    let mut start = 0;
+    for (i, &end) in batch.ends.iter().enumerate() {
+        let line = &batch.lines[start..end];
+        start = end;
+        if filter.active() && !filter.keep(line, fsed) {
+            ends.push((text.len(), false));
+            continue;
+        }
+        let line_score = filter.score(line, fsed);
+        batch.prefix.write(&mut text, batch.lineno + i)?;
+        let matched = process_line(line, fsed, line_score, &mut text)?;
+        ends.push((text.len(), matched));
+    }
+    Ok(Decorated {
+        seq: batch.seq,
+        file: batch.file,
+        text,
+        ends,
+    })
+}
+
+// Basic mode with --threads, or with as many threads as --auto-tune picks from the sample of
+// the first input. This thread writes, one reads the inputs into batches, and the workers
+// decorate them. Batches are written in the order they were read unless --unordered, so the
+// output is the same as run's
+fn run_parallel(args: Args, colormode: ColorChoice, threads: Option<usize>) -> Result<bool, Error> {
+    let mut out = output(&args, colormode)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let filter = LineFilter::new(&args, false);
+    let fsed = open_fstsed(&args, colormode)?;
+    if threads.is_some() && fsed.numbers_matches() {
+        bail!("--threads can't number matches in input order, so templates can't use {{match_id}}");
+    }
+    let (with_filename, line_number) = (args.with_filename, args.line_number);
+    let mut paths = args.input.into_iter().enumerate();
+    // the first input is opened before the workers start, so its sample can pick their number
+    let first = paths.next().map(|(file, path)| {
+        let opened = inputs.open(path.clone(), &fsed);
+        (file, path, opened)
+    });
+    let threads = match threads {
+        Some(threads) => threads.max(1),
+        None => {
+            // a db whose default template numbers matches keeps to one thread, in input order
+            let tuned = if fsed.numbers_matches() {
+                1
+            } else {
+                inputs.threads.unwrap_or(1)
+            };
+            if args.verbose {
+                eprintln!("auto-tune: decorating with {tuned} thread(s)");
+            }
+            tuned
+        }
+    };
+    let (batch_tx, batch_rx) = mpsc::sync_channel::<Batch>(threads * 2);
+    let batch_rx = Arc::new(Mutex::new(batch_rx));
+    let (done_tx, done_rx) = mpsc::sync_channel::<Result<Decorated>>(threads * 2);
+
+    thread::scope(|scope| {
+        let (fsed, filter) = (&fsed, &filter);
+        for _ in 0..threads {
+            // the workers own the receiving end, so the reader stops when they all have
+            let (batch_rx, done_tx) = (Arc::clone(&batch_rx), done_tx.clone());
+            scope.spawn(move || loop {
+                let Ok(batch) = batch_rx.lock().unwrap().recv() else {
+                    return;
+                };
+                if done_tx.send(decorate_batch(batch, fsed, filter)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop((batch_rx, done_tx));
+
+        let reader = scope.spawn(move || -> Result<()> {
+            let mut seq = 0;
+            let rest = paths.map(|(file, path)| (file, path, None));
+            let first = first.map(|(file, path, opened)| (file, path, Some(opened)));
+            for (file, path, opened) in first.into_iter().chain(rest) {
+                let prefix = Arc::new(LinePrefix::new(with_filename, line_number, &path));
+                let opened = match opened {
+                    Some(opened) => opened,
+                    None => inputs.open(path, fsed),
+                };
+                let Some(mut reader) = opened? else {
+                    continue;
+                };
+                let mut lineno = 0;
+                let mut batch: Option<Batch> = None;
+                let mut stopped = false;
+                records.for_each(&mut reader, |line| {
+                    lineno += 1;
+                    let current = batch.get_or_insert_with(|| Batch {
+                        seq,
+                        file,
+                        prefix: Arc::clone(&prefix),
+                        lineno,
+                        lines: Vec::with_capacity(BATCH_BYTES),
+                        ends: Vec::with_capacity(BATCH_LINES),
+                    });
+                    current.lines.extend_from_slice(line);
+                    current.ends.push(current.lines.len());
+                    if current.ends.len() >= BATCH_LINES || current.lines.len() >= BATCH_BYTES {
+                        seq += 1;
+                        // the workers are gone when the writer has stopped
+                        stopped = batch_tx.send(batch.take().unwrap()).is_err();
+                    }
+                    Ok(!stopped)
+                })?;
+                if let Some(batch) = batch {
+                    seq += 1;
+                    stopped = batch_tx.send(batch).is_err();
+                }
+                if stopped {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        let written = write_batches(done_rx, &mut out, args.max_count, args.unordered);
+        // a failed read ends the batches early, so it's the error to report
+        reader.join().expect("input reader panicked")?;
+        let matched = written?;
+        out.finish()?;
+        Ok(matched)
+    })
+}
+
+// Write out the decorated batches as they arrive, or in sequence unless unordered, keeping to
+// -m for each input. Returns whether any line matched
+fn write_batches(
+    done: mpsc::Receiver<Result<Decorated>>,
+    out: &mut Output,
+    max_count: Option<usize>,
+    unordered: bool,
+) -> Result<bool> {
+    let mut matched = false;
+    let mut limit = MaxCount::new(max_count);
+    let mut file = 0;
+    let mut next = 0;
+    // batches that arrived ahead of their turn
+    let mut pending: HashMap<u64, Decorated> = HashMap::new();
+    for decorated in done {
+        let decorated = decorated?;
+        let ready = if unordered {
+            vec![decorated]
+        } else {
+            pending.insert(decorated.seq, decorated);
+            let mut ready = Vec::new();
+            while let Some(decorated) = pending.remove(&next) {
+                ready.push(decorated);
+                next += 1;
+            }
+            ready
+        };
+        for decorated in ready {
+            if decorated.file != file {
+                file = decorated.file;
+                limit.reset();
+            }
+            let mut start = 0;
+            for &(end, line_matched) in &decorated.ends {
+                if limit.reached() {
+                    break;
+                }
+                out.write_all(&decorated.text[start..end])?;
+                start = end;
+                if line_matched {
+                    matched = true;
+                    limit.count();
+                }
+            }
+        }
+    }
+    Ok(matched)
+}
+
+// Test whether any json string in the line contains a match. Like json mode, strings are
+// deserialized before searching and strings that fail to deserialize are ignored
+#[inline]
+fn json_has_match(line: &[u8], fsed: &fstsed::FstSed) -> bool {
+    fsed.json_strings(line).any(|(start, end)| {
+        serde_json::from_slice::<String>(&line[start..end])
+            .map(|s| fsed.find_iter(s.as_bytes()).next().is_some())
+            .unwrap_or(false)
+    })
+}
+
+// Count all the matches in the json strings of the line
+#[inline]
+fn json_count_matches(line: &[u8], fsed: &fstsed::FstSed) -> usize {
+    fsed.json_strings(line)
+        .map(|(start, end)| {
+            serde_json::from_slice::<String>(&line[start..end])
+                .map(|s| fsed.find_iter(s.as_bytes()).count())
+                .unwrap_or(0)
+        })
+        .sum()
+}
+
+// Test whether the line has at least `min` distinct matched keys. In json mode, strings are
+// deserialized before searching like everywhere else
+#[inline]
+fn has_min_matches(line: &[u8], fsed: &fstsed::FstSed, json: bool, min: usize) -> bool {
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    let mut collect = |text: &[u8]| {
+        for m in fsed.find_iter(text) {
+            if !keys.iter().any(|k| k == m.key()) {
+                keys.push(m.key().to_vec());
+            }
+        }
+    };
+    if json {
+        for (start, end) in fsed.json_strings(line) {
+            if let Ok(s) = serde_json::from_slice::<String>(&line[start..end]) {
+                collect(s.as_bytes());
+            }
+        }
+    } else {
+        collect(line);
+    }
+    keys.len() >= min
+}
+
+/// Counts the selected lines of an input against -m/--max-count
+struct MaxCount {
+    max: Option<usize>,
+    selected: usize,
+}
+
+impl MaxCount {
+    fn new(max: Option<usize>) -> Self {
+        Self { max, selected: 0 }
+    }
+
+    /// Start counting the next input
+    fn reset(&mut self) {
+        self.selected = 0;
+    }
+
+    #[inline]
+    fn count(&mut self) {
+        self.selected += 1;
+    }
+
+    /// Whether the input has had all the selected lines it may, so reading should stop
+    #[inline]
+    fn reached(&self) -> bool {
+        self.max.is_some_and(|max| self.selected >= max)
+    }
+}
+
+/// Selects lines by --min-matches and --min-score, and sums their --score
+struct LineFilter {
+    json: bool,
+    min_matches: Option<usize>,
+    score: Option<String>,
+    min_score: Option<f64>,
+}
+
+impl LineFilter {
+    fn new(args: &Args, json: bool) -> Self {
+        Self {
+            json,
+            min_matches: args.min_matches,
+            score: args.score.clone(),
+            min_score: args.min_score,
+        }
+    }
+
+    /// Whether lines are selected by the filters rather than by having any match
+    fn active(&self) -> bool {
+        self.min_matches.is_some() || self.min_score.is_some()
+    }
+
+    fn keep(&self, line: &[u8], fsed: &fstsed::FstSed) -> bool {
+        self.min_matches
+            .is_none_or(|min| has_min_matches(line, fsed, self.json, min))
+            && self.score_ok(line, fsed)
+    }
+
+    /// Whether the line reaches --min-score, if one was given
+    fn score_ok(&self, line: &[u8], fsed: &fstsed::FstSed) -> bool {
+        self.min_score
+            .is_none_or(|min| self.score(line, fsed).is_some_and(|score| score >= min))
+    }
+
+    /// The sum of the score field over every match on the line, or None without --score.
+    /// Values without the field, or where it isn't a number, add nothing
+    fn score(&self, line: &[u8], fsed: &fstsed::FstSed) -> Option<f64> {
+        let field = self.score.as_deref()?;
+        let mut total = 0.0;
+        let mut add = |text: &[u8]| {
+            for m in fsed.find_iter(text) {
+                let m = fsed.get_match(&m);
+                let Ok(value) = serde_json::from_str::<serde_json::Value>(m.value()) else {
+                    continue;
+                };
+                let found = if field.starts_with('/') {
+                    value.pointer(field)
+                } else {
+                    value.get(field)
+                };
+                total += found
+                    .and_then(|v| v.as_f64().or_else(|| v.as_str()?.trim().parse().ok()))
+                    .unwrap_or(0.0);
+            }
+        };
+        if self.json {
+            for (start, end) in fsed.json_strings(line) {
+                if let Ok(s) = serde_json::from_slice::<String>(&line[start..end]) {
+                    add(s.as_bytes());
+                }
+            }
+        } else {
+            add(line);
+        }
+        Some(total)
+    }
+}
+
+/// Two distinct keys found on the same line, in sorted order
+type KeyPair = (Vec<u8>, Vec<u8>);
+
+// Co-occurrence mode. Collect the distinct keys of each line and tally every pair of them.
+// Pairs are stored in key order so a,b and b,a are the same pair. The report is written once
+// all inputs are read, with ties ranked by key for stable output
+#[inline]
+fn run_pairs(args: Args) -> Result<bool> {
+    let mut out = output(&args, ColorChoice::Never)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let json = args.json;
+    let filter = LineFilter::new(&args, json);
+    let fsed = open_fstsed(&args, ColorChoice::Never)?;
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
+
+    let mut pairs: HashMap<KeyPair, u64> = HashMap::new();
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+
+    for path in args.input {
+        limit.reset();
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            if limit.reached() {
+                return Ok(false);
+            }
+            if filter.active() && !filter.keep(line, &fsed) {
+                return Ok(true);
+            }
+            keys.clear();
+            let mut collect = |text: &[u8]| {
+                for m in fsed.find_iter(text) {
+                    if !keys.iter().any(|k| k == m.key()) {
+                        keys.push(m.key().to_vec());
+                    }
+                }
+            };
+            if json {
+                for (start, end) in fsed.json_strings(line) {
+                    if let Ok(s) = serde_json::from_slice::<String>(&line[start..end]) {
+                        collect(s.as_bytes());
+                    }
+                }
+            } else {
+                collect(line);
+            }
+            keys.sort_unstable();
+            if !keys.is_empty() {
+                matched = true;
+                limit.count();
+            }
+            for (i, a) in keys.iter().enumerate() {
+                for b in &keys[i + 1..] {
+                    *pairs.entry((a.clone(), b.clone())).or_default() += 1;
+                }
+            }
+            Ok(!limit.reached())
+        })?;
+    }
+
+    let mut ranked: Vec<(KeyPair, u64)> = pairs.into_iter().collect();
+    ranked.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    for ((a, b), count) in ranked
+        .into_iter()
+        .take(args.pairs_top.unwrap_or(usize::MAX))
+    {
+        write!(out, "{count}\t")?;
+        out.write_all(&a)?;
+        out.write_all(b"\t")?;
+        out.write_all(&b)?;
+        out.write_all(b"\n")?;
+    }
+    out.finish()?;
+    Ok(matched)
+}
+
+// Count mode. Like grep -c, print the number of matching lines for each input, prefixed by
+// the input name when there is more than one
+#[inline]
+fn run_count(args: Args) -> Result<bool> {
+    let mut out = output(&args, ColorChoice::Never)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let json = args.json;
+    let invert = args.invert_match;
+    let count_matches = args.count_matches;
+    let filter = LineFilter::new(&args, json);
+    let with_name = args.with_filename || args.input.len() > 1;
+    let fsed = open_fstsed(&args, ColorChoice::Never)?;
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
+
+    for path in args.input {
+        limit.reset();
+        let name = input_name(&path);
+        let mut lines: usize = 0;
+        let mut matches: usize = 0;
+
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            if limit.reached() {
+                return Ok(false);
+            }
+            let n = if json {
+                json_count_matches(line, &fsed)
+            } else {
+                fsed.find_iter(line).count()
+            };
+            let selected = if filter.active() {
+                filter.keep(line, &fsed)
+            } else {
+                n > 0
+            };
+            if selected != invert {
+                lines += 1;
+                limit.count();
+            }
+            matches += n;
+            Ok(!limit.reached())
+        })?;
+
+        matched |= lines > 0;
+        if with_name {
+            write!(out, "{name}:")?;
+        }
+        if count_matches {
+            writeln!(out, "{lines}:{matches}")?;
+        } else {
+            writeln!(out, "{lines}")?;
+        }
+    }
+    out.finish()?;
+    Ok(matched)
+}
+
+// Invert match mode. Print only the lines without any match, untouched. There is nothing to
+// decorate so color is never used
+#[inline]
+fn run_invert(args: Args) -> Result<bool> {
+    let mut out = output(&args, ColorChoice::Never)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let json = args.json;
+    let filter = LineFilter::new(&args, json);
+    let with_filename = args.with_filename;
+    let line_number = args.line_number;
+    let fsed = open_fstsed(&args, ColorChoice::Never)?;
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
 
     for path in args.input {
-        let mut reader = get_input(Some(path))?;
-        reader.for_byte_line_with_terminator(|line| {
-            // TODO: i cant figure out how to transform the std::io::error into anyhow
-            let _ = process_line(line, &fsed, &mut out);
-            Ok(true)
+        limit.reset();
+        let prefix = LinePrefix::new(with_filename, line_number, &path);
+        let mut lineno: usize = 0;
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            lineno += 1;
+            if limit.reached() {
+                return Ok(false);
+            }
+            let found = if filter.active() {
+                filter.keep(line, &fsed)
+            } else if json {
+                json_has_match(line, &fsed)
+            } else {
+                fsed.find_iter(line).next().is_some()
+            };
+            if !found {
+                prefix.write(&mut out, lineno)?;
+                out.write_all(line)?;
+                matched = true;
+                limit.count();
+            }
+            Ok(!limit.reached())
         })?;
     }
-    out.flush()?;
+    out.finish()?;
+    Ok(matched)
+}
+
+// Quiet mode. Only find out whether any line is selected, stopping at the first one. Returning
+// false from the line callback ends the read of the input early
+#[inline]
+fn run_quiet(args: Args) -> Result<bool> {
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let json = args.json;
+    let invert = args.invert_match;
+    let filter = LineFilter::new(&args, json);
+    let fsed = open_fstsed(&args, ColorChoice::Never)?;
+    let mut matched = false;
+
+    for path in args.input {
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            let found = if filter.active() {
+                filter.keep(line, &fsed)
+            } else if json {
+                json_has_match(line, &fsed)
+            } else {
+                fsed.find_iter(line).next().is_some()
+            };
+            matched = found != invert;
+            Ok(!matched)
+        })?;
+        if matched {
+            break;
+        }
+    }
+    Ok(matched)
+}
+
+/// Where the matches written by write_match_record were found
+struct Position<'a> {
+    file: &'a str,
+    line: usize,
+    /// The --score of the line
+    score: Option<f64>,
+}
+
+// Write one ndjson record describing the current match of fsed
+#[inline]
+fn write_match_record<W>(
+    out: &mut W,
+    fsed: &fstsed::FstSed,
+    m: &fstsed::Match,
+    position: &Position,
+    offset: usize,
+    match_id: u64,
+) -> Result<()>
+where
+    W: Write,
+{
+    let m = fsed
+        .get_match(m)
+        .with_match_id(match_id)
+        .with_line_score(position.score);
+    // stored values are usually json records, but fall back to a plain string if not
+    let value = serde_json::from_str::<serde_json::Value>(m.value())
+        .unwrap_or_else(|_| serde_json::Value::from(m.value()));
+    let mut record = serde_json::json!({
+        "file": position.file,
+        "line": position.line,
+        "offset": offset,
+        "match_id": match_id,
+        "key": m.key(),
+        "value": value,
+    });
+    if let Some(score) = position.score {
+        record["line_score"] = score.into();
+    }
+    if let Some(normalization) = m.normalized_by() {
+        record["normalized_by"] = normalization.into();
+        record["normalized"] = m.key().into();
+    }
+    if let Some(link) = m.link() {
+        record["link"] = link.into();
+    }
+    serde_json::to_writer(&mut *out, &record)?;
+    out.write_all(b"\n")?;
     Ok(())
 }
 
-// Print just the search matches rather than the entire line
+/// The matches recorded by an earlier --output-format json run, for --baseline
+struct Baseline {
+    seen: HashSet<(String, usize, usize, Vec<u8>)>,
+}
+
+impl Baseline {
+    fn load(path: &Utf8PathBuf) -> Result<Self> {
+        let reader = BufReader::new(
+            File::open(path).with_context(|| format!("Error opening baseline {path}"))?,
+        );
+        let mut seen = HashSet::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: serde_json::Value = serde_json::from_str(&line)
+                .with_context(|| format!("Error parsing baseline {path} line {}", i + 1))?;
+            let (Some(file), Some(lineno), Some(offset), Some(key)) = (
+                record["file"].as_str(),
+                record["line"].as_u64(),
+                record["offset"].as_u64(),
+                record["key"].as_str(),
+            ) else {
+                bail!(
+                    "baseline {path} line {} is not a match record with file, line, offset and key",
+                    i + 1
+                );
+            };
+            seen.insert((
+                file.to_string(),
+                lineno as usize,
+                offset as usize,
+                key.as_bytes().to_vec(),
+            ));
+        }
+        Ok(Self { seen })
+    }
+
+    #[inline]
+    fn contains(&self, position: &Position, offset: usize, key: &[u8]) -> bool {
+        self.seen.contains(&(
+            position.file.to_string(),
+            position.line,
+            offset,
+            key.to_vec(),
+        ))
+    }
+}
+
+// Structured output mode. Instead of decorating the input, emit one json record per match.
+// Line numbers start at 1 and offsets are bytes from the start of the input. In json mode, the
+// offset is that of the json string containing the match
 #[inline]
-fn run_onlymatching(args: Args, colormode: ColorChoice) -> Result<()> {
-    let mut out = stdout(colormode);
-    let fsed = fstsed::FstSed::new(args.fst, args.template, colormode);
+fn run_structured(args: Args) -> Result<bool> {
+    let mut out = output(&args, ColorChoice::Never)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let json = args.json;
+    let filter = LineFilter::new(&args, json);
+    let fsed = open_fstsed(&args, ColorChoice::Never)?;
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
+    let baseline = args.baseline.as_ref().map(Baseline::load).transpose()?;
+    let is_new = |position: &Position, offset: usize, m: &fstsed::Match| {
+        !baseline
+            .as_ref()
+            .is_some_and(|b| b.contains(position, offset, m.key()))
+    };
 
     for path in args.input {
-        let mut reader = get_input(Some(path))?;
-        reader.for_byte_line_with_terminator(|line| {
-            for _ in fsed.find_iter(line) {
-                // just print rendered match and a new line
-                out.write_all(fsed.get_match().render().as_bytes())?;
+        limit.reset();
+        let name = input_name(&path);
+        let mut lineno: usize = 0;
+        let mut linestart: usize = 0;
+
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            if limit.reached() {
+                return Ok(false);
+            }
+            lineno += 1;
+            if filter.active() && !filter.keep(line, &fsed) {
+                linestart += line.len();
+                return Ok(true);
+            }
+            let position = Position {
+                file: &name,
+                line: lineno,
+                score: filter.score(line, &fsed),
+            };
+            let mut selected = false;
+            if json {
+                for (start, end) in fsed.json_strings(line) {
+                    if let Ok(s) = serde_json::from_slice::<String>(&line[start..end]) {
+                        for m in fsed.find_iter(s.as_bytes()) {
+                            let offset = linestart + start;
+                            // matches left out by the baseline still use up their id
+                            let match_id = fsed.next_match_id();
+                            if !is_new(&position, offset, &m) {
+                                continue;
+                            }
+                            write_match_record(&mut out, &fsed, &m, &position, offset, match_id)
+                                .map_err(io::Error::other)?;
+                            selected = true;
+                        }
+                    }
+                }
+            } else {
+                for m in fsed.find_iter(line) {
+                    let offset = linestart + m.start;
+                    let match_id = fsed.next_match_id();
+                    if !is_new(&position, offset, &m) {
+                        continue;
+                    }
+                    write_match_record(&mut out, &fsed, &m, &position, offset, match_id)
+                        .map_err(io::Error::other)?;
+                    selected = true;
+                }
+            }
+            linestart += line.len();
+            if selected {
+                matched = true;
+                limit.count();
+            }
+            Ok(!limit.reached())
+        })?;
+    }
+    out.finish()?;
+    Ok(matched)
+}
+
+// Decorate a json string, quotes included, without decoding it: the bytes between matches are
+// copied as they are and the rendered matches are escaped into the string. Returns whether
+// there was a match
+fn decorate_raw_string<W: Write>(
+    string: &[u8],
+    fsed: &fstsed::FstSed,
+    out: &mut W,
+) -> io::Result<bool> {
+    let raw = &string[1..string.len() - 1];
+    let mut lastpos = 0;
+    out.write_all(b"\"")?;
+    for m in fsed.find_iter(raw) {
+        out.write_all(&raw[lastpos..m.start])?;
+        let rendered = serde_json::to_string(&fsed.get_match(&m).render())?;
+        out.write_all(&rendered.as_bytes()[1..rendered.len() - 1])?;
+        lastpos = m.end;
+    }
+    out.write_all(&raw[lastpos..])?;
+    out.write_all(b"\"")?;
+    Ok(lastpos > 0)
+}
+
+// Expand the few backslash escapes allowed in --match-sep and --record-sep, since tabs and
+// newlines are awkward to pass through a shell. Other backslashes are kept as is
+fn unescape_sep(sep: &str) -> String {
+    let mut out = String::with_capacity(sep.len());
+    let mut chars = sep.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('\\') => out.push('\\'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+// Print just the search matches rather than the entire line
+#[inline]
+fn run_onlymatching(args: Args, colormode: ColorChoice) -> Result<bool> {
+    let mut out = output(&args, colormode)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let filter = LineFilter::new(&args, false);
+    let group = args.group_per_line;
+    let sep = match &args.match_sep {
+        Some(sep) => unescape_sep(sep),
+        None if group => "\t".to_string(),
+        None => "\n".to_string(),
+    };
+    let byte_offset = args.byte_offset;
+    // byte spans are only meaningful with the file and line they're in
+    let with_filename = args.with_filename || byte_offset;
+    let line_number = args.line_number || byte_offset;
+    let live = follow_reloads(&args, colormode)?;
+    let mut fsed = match &live {
+        Some(live) => live.current(),
+        None => Arc::new(open_fstsed(&args, colormode)?),
+    };
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
+
+    for path in args.input.iter().cloned() {
+        limit.reset();
+        let prefix = LinePrefix::new(with_filename, line_number, &path);
+        let mut lineno: usize = 0;
+        // offset of the current line from the beginning of the input
+        let mut linestart: usize = 0;
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            lineno += 1;
+            if limit.reached() {
+                return Ok(false);
+            }
+            let offset = linestart;
+            linestart += line.len();
+            if let Some(reloaded) = live.as_ref().and_then(|live| live.reloaded()) {
+                fsed = reloaded;
+            }
+            if filter.active() && !filter.keep(line, &fsed) {
+                return Ok(true);
+            }
+            let line_score = filter.score(line, &fsed);
+            let mut any = false;
+            for m in fsed.find_iter(line) {
+                // grouped matches are joined by the separator, otherwise each one ends with it
+                if group && any {
+                    out.write_all(sep.as_bytes())?;
+                }
+                if !group || !any {
+                    prefix.write(&mut out, lineno)?;
+                }
+                if byte_offset {
+                    write!(out, "{}-{}:", offset + m.start, offset + m.end)?;
+                }
+                let m = fsed
+                    .get_match(&m)
+                    .with_line_score(line_score)
+                    .with_match_id(fsed.next_match_id());
+                out.write_all(m.render().as_bytes())?;
+                if !group {
+                    out.write_all(sep.as_bytes())?;
+                }
+                any = true;
+            }
+            if any {
+                matched = true;
+                limit.count();
+            }
+            if group && any {
                 out.write_all(b"\n")?;
             }
-            Ok(true)
+            Ok(!limit.reached())
         })?;
     }
-    out.flush()?;
-    Ok(())
+    out.finish()?;
+    Ok(matched)
 }
 
 // Json search mode. Use the jsonquotes utility in this crate to find and deserialize just the
 // json strings in the input. Also ensure all formatted output is properly json encoded.
 #[inline]
-fn runjson(args: Args, _: ColorChoice) -> Result<(), Error> {
+fn runjson(args: Args, _: ColorChoice) -> Result<bool, Error> {
     // cant colorize text inside of json strings
-    let mut out = stdout(ColorChoice::Never);
-    let fsed = fstsed::FstSed::new(args.fst, args.template, ColorChoice::Never);
+    let mut out = output(&args, ColorChoice::Never)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let filter = LineFilter::new(&args, true);
+    let strict = args.passthrough_strict;
+    let binary = args.binary;
+    let fsed = open_fstsed(&args, ColorChoice::Never)?;
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
 
     // temp buffer for holding processed string before re-serializing
     let mut buf = Vec::with_capacity(BUFFERSIZE);
 
     for path in args.input {
-        let mut reader = get_input(Some(path))?;
-        reader.for_byte_line_with_terminator(|line| {
+        limit.reset();
+        let name = input_name(&path);
+        let mut lineno: usize = 0;
+
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            if limit.reached() {
+                return Ok(false);
+            }
+            lineno += 1;
+            if filter.active() && !filter.keep(line, &fsed) {
+                return Ok(true);
+            }
+            let line_score = filter.score(line, &fsed);
+            let mut selected = false;
             let mut lastpos: usize = 0;
-            for (start, end) in jsonquotes_range_iter(line) {
+            for (start, end) in fsed.json_strings(line) {
                 // print from last spot to new start
                 out.write_all(&line[lastpos..start])?;
                 // deserialize string and process result (which is a quoted string
                 // and therefore a valid json object)
                 // note: we are allocating a new string every time
-                match serde_json::from_slice::<String>(&line[start..end]) {
+                let started = timings::start();
+                let decoded = serde_json::from_slice::<String>(&line[start..end]);
+                timings::record(timings::Phase::JsonParse, started);
+                match decoded {
                     Ok(s) => {
                         buf.clear();
                         // reuse vec buf to collect the processed line
-                        let _ = process_line(s.as_bytes(), &fsed, &mut buf);
+                        selected |= process_line(s.as_bytes(), &fsed, line_score, &mut buf)
+                            .map_err(|e| line_error(&name, lineno, e))?;
+                        if strict {
+                            let original = &line[start..end];
+                            if buf == s.as_bytes() {
+                                // nothing changed, so keep the exact original encoding
+                                out.write_all(original)?;
+                                lastpos = end;
+                                continue;
+                            }
+                            if serde_json::to_vec(&s)? != original {
+                                return Err(io::Error::other(format!(
+                                    "{name}:{lineno}: re-encoding the json string at byte {start} \
+                                     would alter bytes outside of its matches \
+                                     (--passthrough-strict)"
+                                )));
+                            }
+                        }
                         // serialize new json string directly to the output
                         serde_json::to_writer(&mut out, std::str::from_utf8(&buf).unwrap())?;
                     }
+                    // a string that isn't utf-8 is searched as it's encoded, with --binary
+                    Err(_) if binary => {
+                        selected |= decorate_raw_string(&line[start..end], &fsed, &mut out)?;
+                    }
                     // if error deserializing, just print the original content and move on
                     // we're not here to enforce json formats
                     _ => out.write_all(&line[start..end])?,
@@ -254,9 +2826,291 @@ fn runjson(args: Args, _: ColorChoice) -> Result<(), Error> {
             }
             // print remainder
             out.write_all(&line[lastpos..])?;
-            Ok(true)
+            if selected {
+                matched = true;
+                limit.count();
+            }
+            Ok(!limit.reached())
+        })?;
+    }
+    out.finish()?;
+    Ok(matched)
+}
+
+// Csv/tsv mode. Like json mode, only the selected fields are searched, unquoted, and the
+// fields with matches are written back quoted as the decorations need. Untouched fields are
+// copied byte for byte
+fn run_delimited(args: Args) -> Result<bool, Error> {
+    // colors would end up inside the fields
+    let mut out = output(&args, ColorChoice::Never)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let filter = LineFilter::new(&args, false);
+    let with_filename = args.with_filename;
+    let line_number = args.line_number;
+    let fsed = open_fstsed(&args, ColorChoice::Never)?;
+    let delimiter = if args.tsv { b'\t' } else { b',' };
+    let mut layout = delimited::Delimited::new(delimiter, &args.columns)?;
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
+
+    // temp buffer for holding a processed field before quoting it
+    let mut buf = Vec::with_capacity(BUFFERSIZE);
+
+    for path in args.input {
+        limit.reset();
+        let prefix = LinePrefix::new(with_filename, line_number, &path);
+        let name = input_name(&path);
+        let mut lineno: usize = 0;
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            lineno += 1;
+            if limit.reached() {
+                return Ok(false);
+            }
+            let body = records.body(line);
+            if lineno == 1 && layout.has_header() {
+                layout.read_header(body).map_err(io::Error::other)?;
+                prefix.write(&mut out, lineno)?;
+                out.write_all(line)?;
+                return Ok(true);
+            }
+            if filter.active() && !filter.keep(line, &fsed) {
+                return Ok(true);
+            }
+            let line_score = filter.score(line, &fsed);
+            prefix.write(&mut out, lineno)?;
+            let mut selected = false;
+            for (i, (start, end)) in layout.fields(body).into_iter().enumerate() {
+                if i > 0 {
+                    out.write_all(&[delimiter])?;
+                }
+                let raw = &body[start..end];
+                if !layout.searched(i) {
+                    out.write_all(raw)?;
+                    continue;
+                }
+                let value = delimited::unquote(raw);
+                buf.clear();
+                if process_line(&value, &fsed, line_score, &mut buf)
+                    .map_err(|e| line_error(&name, lineno, e))?
+                {
+                    selected = true;
+                    layout.write_field(&mut out, &buf)?;
+                } else {
+                    out.write_all(raw)?;
+                }
+            }
+            out.write_all(&line[body.len()..])?;
+            if selected {
+                matched = true;
+                limit.count();
+            }
+            Ok(!limit.reached())
         })?;
     }
-    out.flush()?;
+    out.finish()?;
+    Ok(matched)
+}
+
+// Parquet mode. Each input is a parquet file, written out as enriched ndjson rows
+#[cfg(feature = "parquet")]
+fn run_parquet(args: Args) -> Result<bool, Error> {
+    let mut out = output(&args, ColorChoice::Never)?;
+    let column = args.column.as_deref().expect("--parquet requires --column");
+    let fsed = open_fstsed(&args, ColorChoice::Never)?;
+    let mut matched = false;
+    for path in &args.input {
+        if path.as_os_str() == "-" {
+            bail!("--parquet can't read standard input, give the parquet files as inputs");
+        }
+        matched |= ::fstsed::parquet_input::scan_parquet(path, column, &fsed, &mut out)?;
+    }
+    out.finish()?;
+    Ok(matched)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn run_parquet(_: Args) -> Result<bool, Error> {
+    bail!("--parquet is not available: fstsed was built without the parquet feature")
+}
+
+// Set the value at the jsonpointer, creating any missing objects along the way. Fails if a
+// member along the path exists but isn't an object
+fn set_pointer(
+    root: &mut serde_json::Value,
+    pointer: &str,
+    value: serde_json::Value,
+) -> Result<(), String> {
+    let mut target = root;
+    let mut segments = pointer.split('/').skip(1).peekable();
+    while let Some(segment) = segments.next() {
+        // unescape per rfc 6901
+        let segment = segment.replace("~1", "/").replace("~0", "~");
+        let Some(object) = target.as_object_mut() else {
+            return Err(format!("parent of {segment:?} is not an object"));
+        };
+        if segments.peek().is_none() {
+            object.insert(segment, value);
+            return Ok(());
+        }
+        target = object
+            .entry(segment)
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+    }
     Ok(())
 }
+
+// Remove and return the value at the jsonpointer, keeping the order of the other members
+fn take_pointer(root: &mut serde_json::Value, pointer: &str) -> Option<serde_json::Value> {
+    let (parent, name) = pointer.rsplit_once('/')?;
+    let name = name.replace("~1", "/").replace("~0", "~");
+    root.pointer_mut(parent)?
+        .as_object_mut()?
+        .shift_remove(&name)
+}
+
+// Json enrichment mode. Matches are searched for the same way as in json mode, but instead of
+// rewriting the strings, the distinct matches are collected into an array that is spliced in
+// as the last field of the line's json object. All the original bytes are left as they were.
+// With --enrich-path, the array is set at the jsonpointer of the parsed object instead.
+// Lines that already carry the array from an earlier pass are skipped, merged or replaced per
+// --enrich-mode. Lines that aren't json objects are passed through
+#[inline]
+fn run_enrich(args: Args) -> Result<bool, Error> {
+    let mut out = output(&args, ColorChoice::Never)?;
+    let mut inputs = InputOpener::new(&args);
+    let records = Records::new(&args)?;
+    let min_matches = args.min_matches;
+    let filter = LineFilter::new(&args, true);
+    let mode = args.enrich_mode;
+    let pointer = args
+        .enrich_path
+        .clone()
+        .unwrap_or_else(|| "/fstsed".to_string());
+    if !pointer.starts_with('/') {
+        bail!("--enrich-path {pointer:?} must be a jsonpointer such as /threat/matches");
+    }
+    let splice = args.enrich_path.is_none();
+    // quick test for lines that may have been enriched before: the quoted field name
+    let (_, name) = pointer.rsplit_once('/').unwrap_or_default();
+    let quoted_name = serde_json::to_vec(&name.replace("~1", "/").replace("~0", "~"))?;
+    let live = follow_reloads(&args, ColorChoice::Never)?;
+    let mut fsed = match &live {
+        Some(live) => live.current(),
+        None => Arc::new(open_fstsed(&args, ColorChoice::Never)?),
+    };
+    let mut matched = false;
+    let mut limit = MaxCount::new(args.max_count);
+
+    // collected (key, value) pairs for the current line
+    let mut found: Vec<(String, serde_json::Value)> = Vec::new();
+
+    for path in args.input.iter().cloned() {
+        limit.reset();
+        let mut lineno: usize = 0;
+
+        let Some(mut reader) = inputs.open(path, &fsed)? else {
+            continue;
+        };
+        records.for_each(&mut reader, |line| {
+            if limit.reached() {
+                return Ok(false);
+            }
+            lineno += 1;
+            if let Some(reloaded) = live.as_ref().and_then(|live| live.reloaded()) {
+                fsed = reloaded;
+            }
+            found.clear();
+            // find the closing brace of the object, ignoring trailing whitespace and terminator
+            let body = records.body(line).trim_ascii_end();
+            let is_object = body.trim_ascii_start().starts_with(b"{") && body.ends_with(b"}");
+
+            // a line enriched by an earlier pass is parsed and the old array taken out, so
+            // that it isn't searched too: every key in it would match again
+            let mut previous = None;
+            if is_object && memchr::memmem::find(body, &quoted_name).is_some() {
+                if let Ok(mut record) = serde_json::from_slice::<serde_json::Value>(body) {
+                    if let Some(old) = take_pointer(&mut record, &pointer) {
+                        previous = Some((record, old));
+                    }
+                }
+            }
+
+            let Some((mut record, old)) = previous else {
+                enrich::collect_enrichment(line, &fsed, &mut found);
+                if min_matches.is_some_and(|min| found.len() < min) || !filter.score_ok(line, &fsed)
+                {
+                    return Ok(true);
+                }
+                if found.is_empty() || !is_object {
+                    out.write_all(line)?;
+                    return Ok(true);
+                }
+                matched = true;
+                limit.count();
+
+                if !splice {
+                    let placed = serde_json::from_slice::<serde_json::Value>(body)
+                        .map_err(|e| e.to_string())
+                        .and_then(|mut record| {
+                            set_pointer(&mut record, &pointer, enrich::enrichment_array(&found))
+                                .map(|_| record)
+                        });
+                    match placed {
+                        Ok(record) => {
+                            serde_json::to_writer(&mut out, &record)?;
+                            out.write_all(&line[body.len()..])?;
+                        }
+                        Err(e) => {
+                            diagnostics::warn_quiet(
+                                "enrich_path",
+                                format!("line {lineno}: cannot set {pointer}: {e}"),
+                                serde_json::json!({ "line": lineno, "pointer": pointer, "error": e }),
+                            );
+                            out.write_all(line)?;
+                        }
+                    }
+                    return Ok(true);
+                }
+
+                enrich::splice_enrichment(&mut out, line, body.len(), &found)?;
+                return Ok(true);
+            };
+
+            if mode == EnrichMode::Skip {
+                out.write_all(line)?;
+                return Ok(true);
+            }
+            if mode == EnrichMode::Merge {
+                // keep the earlier matches, including ones for keys no longer in the db
+                for entry in old.as_array().into_iter().flatten() {
+                    if let (Some(key), Some(_)) = (entry["key"].as_str(), entry.get("value")) {
+                        if !found.iter().any(|(k, _)| k == key) {
+                            found.push((key.to_string(), entry.clone()));
+                        }
+                    }
+                }
+            }
+            let text = serde_json::to_vec(&record)?;
+            enrich::collect_enrichment(&text, &fsed, &mut found);
+            if min_matches.is_some_and(|min| found.len() < min) || !filter.score_ok(&text, &fsed) {
+                return Ok(true);
+            }
+            // with nothing left to add, a replaced enrichment is just removed
+            if !found.is_empty() {
+                matched = true;
+                limit.count();
+                // the old array's parent exists and is an object, so this can't fail
+                let _ = set_pointer(&mut record, &pointer, enrich::enrichment_array(&found));
+            }
+            serde_json::to_writer(&mut out, &record)?;
+            out.write_all(&line[body.len()..])?;
+            Ok(!limit.reached())
+        })?;
+    }
+    out.finish()?;
+    Ok(matched)
+}
@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+
+// the members of an event kept with each of its attributes, as in the "Event" of an
+// /attributes/restSearch result
+const EVENT_CONTEXT: [&str; 10] = [
+    "id",
+    "uuid",
+    "info",
+    "date",
+    "threat_level_id",
+    "analysis",
+    "published",
+    "timestamp",
+    "Org",
+    "Orgc",
+];
+
+/// Flatten MISP event exports into one attribute per line for building. `input` is returned
+/// unchanged unless its first json value is an event export: an {"Event": ..} object or a
+/// {"response": [..]} search result. Then it's read as a stream of json values, and each
+/// attribute of each event, including the attributes of its objects, is written on its own
+/// line with the event's context under "Event" and the event's tags added to its own "Tag"
+pub fn expand_events(input: Vec<u8>) -> Result<Vec<u8>> {
+    let mut values = serde_json::Deserializer::from_slice(&input).into_iter::<Value>();
+    match values.next() {
+        Some(Ok(first)) if is_export(&first) => {}
+        _ => return Ok(input),
+    }
+    let mut lines = Vec::with_capacity(input.len());
+    let values = serde_json::Deserializer::from_slice(&input).into_iter::<Value>();
+    for value in values {
+        let mut value = value.context("Error reading MISP export")?;
+        let events = match value.get_mut("response").map(Value::take) {
+            Some(Value::Array(events)) => events,
+            // an attribute search answers {"response": {"Attribute": [..]}}
+            Some(response) => vec![response],
+            None => vec![value],
+        };
+        for event in events {
+            for attribute in event_attributes(event) {
+                serde_json::to_writer(&mut lines, &attribute)?;
+                lines.push(b'\n');
+            }
+        }
+    }
+    Ok(lines)
+}
+
+// the attributes of a search have an "Event" too, but only events hold attributes
+fn is_export(value: &Value) -> bool {
+    let event = value.get("Event");
+    value.get("response").is_some()
+        || event.is_some_and(|e| e.get("Attribute").is_some() || e.get("Object").is_some())
+}
+
+// The attributes of an {"Event": ..} object, each with the event's context, or the
+// attributes of an attribute search result as they are
+fn event_attributes(mut event: Value) -> Vec<Value> {
+    let Some(Value::Object(mut event)) = event.get_mut("Event").map(Value::take) else {
+        return match event.get_mut("Attribute").map(Value::take) {
+            Some(Value::Array(attributes)) => attributes,
+            _ => Vec::new(),
+        };
+    };
+    let mut attributes = take_array(&mut event, "Attribute");
+    for mut object in take_array(&mut event, "Object") {
+        if let Some(Value::Array(members)) = object.get_mut("Attribute").map(Value::take) {
+            attributes.extend(members);
+        }
+    }
+    let tags = take_array(&mut event, "Tag");
+    let context: Map<String, Value> = EVENT_CONTEXT
+        .iter()
+        .filter_map(|&name| Some((name.to_string(), event.remove(name)?)))
+        .collect();
+
+    for attribute in &mut attributes {
+        let Value::Object(members) = attribute else {
+            continue;
+        };
+        split_composite(members);
+        members.insert("Event".to_string(), Value::Object(context.clone()));
+        if !tags.is_empty() {
+            let own = members
+                .entry("Tag")
+                .or_insert_with(|| Value::Array(Vec::new()));
+            if let Value::Array(own) = own {
+                for tag in &tags {
+                    if !own.iter().any(|t| t["name"] == tag["name"]) {
+                        own.push(tag.clone());
+                    }
+                }
+            }
+        }
+    }
+    attributes
+}
+
+// Give a composite attribute (domain|ip, filename|sha256...) the value1 and value2 halves of
+// its value that searches return, and that the misp profile keys by
+fn split_composite(members: &mut Map<String, Value>) {
+    let text = |name| members.get(name).and_then(Value::as_str);
+    if members.contains_key("value1") || !text("type").is_some_and(|t| t.contains('|')) {
+        return;
+    }
+    let Some((first, second)) = text("value").and_then(|v| v.split_once('|')) else {
+        return;
+    };
+    let (first, second) = (first.to_string(), second.to_string());
+    members.insert("value1".to_string(), first.into());
+    members.insert("value2".to_string(), second.into());
+}
+
+fn take_array(members: &mut Map<String, Value>, name: &str) -> Vec<Value> {
+    match members.remove(name) {
+        Some(Value::Array(items)) => items,
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,55 @@
+use crate::enrich::{collect_text_enrichment, enrichment_array};
+use crate::fstsed::FstSed;
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use serde_json::Value;
+use std::fs::File;
+use std::io::Write;
+
+/// Search the string `column` of every row of a parquet file and write the rows as ndjson,
+/// with a "fstsed" array of the matched keys and their values added to the rows with matches,
+/// as --enrich does. Returns whether any row matched
+pub fn scan_parquet<W: Write>(
+    path: &Utf8Path,
+    column: &str,
+    fsed: &FstSed,
+    out: &mut W,
+) -> Result<bool> {
+    let file = File::open(path).with_context(|| format!("Error opening parquet file {path}"))?;
+    let reader = SerializedFileReader::new(file)
+        .with_context(|| format!("Error reading parquet file {path}"))?;
+    let schema = reader.metadata().file_metadata().schema_descr();
+    if !schema
+        .root_schema()
+        .get_fields()
+        .iter()
+        .any(|f| f.name() == column)
+    {
+        bail!("parquet file {path} has no column {column:?}");
+    }
+
+    let mut matched = false;
+    // collected (key, value) pairs for the current row
+    let mut found: Vec<(String, Value)> = Vec::new();
+    for row in reader.get_row_iter(None)? {
+        let row = row.with_context(|| format!("Error reading parquet file {path}"))?;
+        found.clear();
+        // null and non-string values have nothing to search
+        if let Some((_, Field::Str(text))) = row.get_column_iter().find(|(name, _)| *name == column)
+        {
+            collect_text_enrichment(text.as_bytes(), fsed, &mut found);
+        }
+        let mut record = row.to_json_value();
+        if !found.is_empty() {
+            matched = true;
+            if let Value::Object(members) = &mut record {
+                members.insert("fstsed".to_string(), enrichment_array(&found));
+            }
+        }
+        serde_json::to_writer(&mut *out, &record)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(matched)
+}
@@ -0,0 +1,82 @@
+use crate::build::KeySpec;
+use crate::{misp, stix};
+use anyhow::Result;
+use clap::ValueEnum;
+
+/// Rewrites an input of a feed's export format as one record per line
+pub type Flattener = fn(Vec<u8>) -> Result<Vec<u8>>;
+
+/// Build presets for well known threat intel feed formats. Each one sets the key extraction
+/// (including alias fields that should resolve to the same record) and a value projection that
+/// keeps the useful context fields under consistent names.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum BuildProfile {
+    /// MISP attributes, one per line as from /attributes/restSearch, or event exports whose
+    /// attributes get the event's info, org and tags. Composite attributes (domain|ip,
+    /// filename|sha256...) are keyed by both halves
+    Misp,
+    /// AlienVault OTX pulse indicators
+    Otx,
+    /// abuse.ch ThreatFox iocs, URLhaus urls and MalwareBazaar samples
+    Abusech,
+    /// STIX 2.1 exports: bundles, or objects one per line. Indicators are keyed by the values in
+    /// their pattern and cyber observables by their value. Other objects of a bundle are skipped
+    Stix,
+}
+
+impl BuildProfile {
+    pub fn keys(&self) -> Vec<KeySpec> {
+        let fields = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| KeySpec::Field(name.to_string()))
+                .collect::<Vec<_>>()
+        };
+        match self {
+            Self::Misp => fields(&["/value", "/value1", "/value2"]),
+            Self::Otx => fields(&["/indicator"]),
+            Self::Abusech => fields(&[
+                "/ioc",
+                "/ioc_value",
+                "/url",
+                "/sha256_hash",
+                "/sha1_hash",
+                "/md5_hash",
+            ]),
+            Self::Stix => vec![
+                KeySpec::StixPattern("/pattern".to_string()),
+                KeySpec::Field("/value".to_string()),
+            ],
+        }
+    }
+
+    /// Flatten an input in the feed's export format into one record per line, for feeds whose
+    /// exports nest their records. None for feeds always read one record per line
+    pub fn flattener(&self) -> Option<Flattener> {
+        match self {
+            Self::Misp => Some(misp::expand_events),
+            Self::Stix => Some(stix::expand_bundles),
+            Self::Otx | Self::Abusech => None,
+        }
+    }
+
+    /// The value transform expression applied to records of this feed
+    pub fn value_transform(&self) -> &'static str {
+        match self {
+            Self::Misp => {
+                "value,type,category,comment,to_ids,event_id,event_info=/Event/info,\
+                 org=/Event/Orgc/name,tags=/Tag,timestamp"
+            }
+            Self::Otx => "indicator,type,title,description,created,expiration,pulse_key,pulse_name",
+            Self::Abusech => {
+                "ioc=/ioc,ioc=/ioc_value,ioc=/url,ioc=/sha256_hash,ioc_type,threat_type,threat,\
+                 malware=/malware_printable,signature,confidence_level,first_seen,\
+                 first_seen=/first_seen_utc,first_seen=/date_added,tags,reference"
+            }
+            Self::Stix => {
+                "id,type,name,description,pattern,value,indicator_types,labels,confidence,\
+                 valid_from,valid_until,created_by_ref,external_references"
+            }
+        }
+    }
+}
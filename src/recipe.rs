@@ -0,0 +1,116 @@
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::fs;
+
+/// A recipe describes a whole enrichment job in a yaml file, so that it can be kept and rerun
+/// instead of retyped. Its jobs run one after the other, each like a fstsed command of its
+/// own, so a later job can read the output of an earlier one, e.g. to decorate with a second
+/// db. Relative fst, input and output paths are taken from the recipe's directory.
+///
+/// ```yaml
+/// jobs:
+///   - name: tag domains
+///     fst: domains.fst
+///     input: [logs/proxy.log]
+///     template: "{key} [{actor|default:unattributed}]"
+///     output: out/proxy.tagged.log
+///     options:
+///       min-matches: 2
+///       with-filename: true
+///   - fst: ips.fst
+///     input: [out/proxy.tagged.log]
+///     output: out/proxy.ips.ndjson
+///     options: { output-format: json }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Recipe {
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// What the job is for, used in error messages
+    pub name: Option<String>,
+    /// The fst db to search, or to create with `build: true`
+    pub fst: String,
+    /// The files to read. Standard input when empty
+    #[serde(default)]
+    pub input: Vec<String>,
+    /// The decoration template, or @name for one from the config file
+    pub template: Option<String>,
+    /// Where to write the output instead of stdout
+    pub output: Option<String>,
+    /// Any other fstsed options by their long name, without the dashes. true turns a flag
+    /// on, a list repeats the option for each item, and anything else is the option's value
+    #[serde(default)]
+    pub options: BTreeMap<String, Value>,
+}
+
+impl Recipe {
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Error reading recipe {path}"))?;
+        let recipe: Self =
+            serde_yaml::from_str(&text).with_context(|| format!("Error reading recipe {path}"))?;
+        if recipe.jobs.is_empty() {
+            bail!("recipe {path} has no jobs");
+        }
+        Ok(recipe)
+    }
+}
+
+impl Job {
+    /// How to refer to the job in messages: its name or else its position in the recipe
+    pub fn describe(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("job {} ({name})", index + 1),
+            None => format!("job {}", index + 1),
+        }
+    }
+
+    /// The fstsed command line equivalent to the job, paths resolved against `base`
+    pub fn args(&self, base: &Utf8Path) -> Result<Vec<String>> {
+        let resolve = |path: &String| {
+            if Utf8Path::new(path).is_absolute() || path == "-" {
+                path.clone()
+            } else {
+                base.join(path).to_string()
+            }
+        };
+        let mut args = vec!["fstsed".to_string(), "-f".to_string(), resolve(&self.fst)];
+        if let Some(template) = &self.template {
+            args.extend(["--template".to_string(), template.clone()]);
+        }
+        if let Some(output) = &self.output {
+            args.extend(["--output".to_string(), resolve(output)]);
+        }
+        for (name, value) in &self.options {
+            let flag = format!("--{}", name.trim_start_matches('-').replace('_', "-"));
+            if flag == "--recipe" {
+                bail!("recipes can't include other recipes");
+            }
+            let values = match value {
+                Value::Sequence(items) => items.as_slice(),
+                value => std::slice::from_ref(value),
+            };
+            for value in values {
+                match value {
+                    Value::Bool(true) | Value::Null => args.push(flag.clone()),
+                    Value::Bool(false) => {}
+                    Value::String(s) => args.extend([flag.clone(), s.clone()]),
+                    Value::Number(n) => args.extend([flag.clone(), n.to_string()]),
+                    _ => bail!("option {name} must be a flag, a string, a number or a list"),
+                }
+            }
+        }
+        // whatever the options, the inputs are never taken for options themselves
+        args.push("--".to_string());
+        args.extend(self.input.iter().map(resolve));
+        Ok(args)
+    }
+}
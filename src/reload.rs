@@ -0,0 +1,120 @@
+use crate::diagnostics;
+use crate::fstsed::FstSed;
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+// how often the fst file is looked at for a replacement
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A fst db that's opened again when its file is replaced, for the long running modes: --follow
+/// and serve. Each caller keeps the db it was handed until it asks again, so a reload never
+/// happens in the middle of a line or a request
+///
+/// Replace the db by renaming a finished build over it; a file rewritten in place can't be
+/// read safely while it's mapped. With the map layout, rename the .values file first; a fst
+/// is never opened with a .values file from another build. A replacement that fails to open
+/// is reported and the db already loaded is kept
+pub struct Reloading<'a> {
+    path: Utf8PathBuf,
+    open: Box<dyn Fn() -> Result<FstSed> + Send + Sync + 'a>,
+    current: RwLock<Arc<FstSed>>,
+    seen: Mutex<Seen>,
+}
+
+struct Seen {
+    checked: Instant,
+    stamp: Option<Stamp>,
+}
+
+// what tells a replaced file apart: renaming a new build into place changes its inode, and
+// copying one over it its size or modification time
+#[derive(PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    #[cfg(unix)]
+    ino: u64,
+}
+
+impl Stamp {
+    fn of(path: &Utf8PathBuf) -> Option<Self> {
+        let meta = fs::metadata(path).ok()?;
+        Some(Self {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            #[cfg(unix)]
+            ino: std::os::unix::fs::MetadataExt::ino(&meta),
+        })
+    }
+}
+
+impl<'a> Reloading<'a> {
+    /// Open the db at `path` with `open`, which is called again for each replacement
+    pub fn new(
+        path: Utf8PathBuf,
+        open: impl Fn() -> Result<FstSed> + Send + Sync + 'a,
+    ) -> Result<Self> {
+        let stamp = Stamp::of(&path);
+        let fsed = open()?;
+        Ok(Self {
+            path,
+            open: Box::new(open),
+            current: RwLock::new(Arc::new(fsed)),
+            seen: Mutex::new(Seen {
+                checked: Instant::now(),
+                stamp,
+            }),
+        })
+    }
+
+    /// The db, opened again first if its file has been replaced
+    pub fn current(&self) -> Arc<FstSed> {
+        match self.reloaded() {
+            Some(fsed) => fsed,
+            None => self.current.read().unwrap().clone(),
+        }
+    }
+
+    /// The db opened again, if its file has been replaced since it was last opened. The file
+    /// is looked at no more than once a second, so this is cheap enough to ask for every line
+    pub fn reloaded(&self) -> Option<Arc<FstSed>> {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.checked.elapsed() < CHECK_INTERVAL {
+            return None;
+        }
+        seen.checked = Instant::now();
+        // a file missing for a moment is being replaced; keep the old one until it's back
+        let stamp = Stamp::of(&self.path)?;
+        if seen.stamp.as_ref() == Some(&stamp) {
+            return None;
+        }
+        // a replacement that fails isn't tried again until the file changes once more
+        seen.stamp = Some(stamp);
+        match (self.open)() {
+            Ok(fsed) => {
+                let fsed = Arc::new(fsed);
+                *self.current.write().unwrap() = fsed.clone();
+                diagnostics::warn(
+                    "reloaded_fst",
+                    format!("reloaded {}", self.path),
+                    serde_json::json!({ "file": self.path.as_str() }),
+                );
+                Some(fsed)
+            }
+            Err(e) => {
+                diagnostics::warn(
+                    "reload_failed",
+                    format!(
+                        "{} was replaced but can't be opened, keeping the loaded db: {e:#}",
+                        self.path
+                    ),
+                    serde_json::json!({ "file": self.path.as_str(), "error": format!("{e:#}") }),
+                );
+                None
+            }
+        }
+    }
+}
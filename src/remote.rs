@@ -0,0 +1,229 @@
+use crate::diagnostics;
+use crate::fstsed::mmap_fst;
+use crate::valuestore::{sidecar_path, Layout};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::env;
+use std::fs;
+use std::process::Command;
+
+/// Whether a db is given as a url to download rather than a path: http://, https:// or s3://
+pub fn is_remote(fst: &str) -> bool {
+    ["http://", "https://", "s3://"]
+        .iter()
+        .any(|scheme| fst.starts_with(scheme))
+}
+
+/// Where downloaded dbs are kept: $FSTSED_CACHE, else fstsed under $XDG_CACHE_HOME (~/.cache)
+pub fn cache_dir() -> Option<Utf8PathBuf> {
+    if let Some(dir) = env::var_os("FSTSED_CACHE") {
+        return Utf8PathBuf::from_path_buf(dir.into()).ok();
+    }
+    let base = env::var_os("XDG_CACHE_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| std::path::PathBuf::from(home).join(".cache"))
+        })?;
+    Utf8PathBuf::from_path_buf(base.join("fstsed")).ok()
+}
+
+/// The local copy of the db at `url`, downloaded into the cache dir first unless the copy
+/// there is current. A cached copy is revalidated by its ETag, so an unchanged db isn't
+/// downloaded again, and is used as it is if the url can't be reached. The value store of a
+/// map layout db is fetched from URL.values the same way
+///
+/// http(s) urls are fetched with curl and s3 urls with the aws command line tool, with its
+/// usual credentials, which must be on the PATH. A new copy is renamed into place, so a
+/// --follow or serve already using the cached db reloads it
+pub fn fetch(url: &str) -> Result<Utf8PathBuf> {
+    let dir = cache_dir().context("no cache dir for a downloaded db: set $FSTSED_CACHE")?;
+    fs::create_dir_all(&dir).with_context(|| format!("Error creating cache dir {dir}"))?;
+    let local = dir.join(cache_name(url));
+
+    let mut fresh = download(url, &local, true)?;
+    let fst_file = fresh.as_ref().map_or(&local, |d| &d.tmp);
+    let fst =
+        unsafe { mmap_fst(fst_file.clone()) }.with_context(|| format!("{url} isn't a fst db"))?;
+    // the value store goes in first, so the new fst never pairs with the old values. A new fst
+    // needs a new value store: without one the cached pair is kept as it is
+    if Layout::of(&fst) == Layout::Map {
+        let values = sidecar_path(&local);
+        let values_url = format!("{url}.values");
+        match download(&values_url, &values, fresh.is_none()) {
+            Ok(Some(download)) => download.install(&values)?,
+            Ok(None) => {}
+            Err(e) if fresh.is_some() && local.exists() && values.exists() => {
+                if let Some(download) = fresh.take() {
+                    let _ = fs::remove_file(&download.tmp);
+                }
+                diagnostics::warn(
+                    "stale_cache",
+                    format!("can't fetch {values_url}, using the cached copy of {url}: {e:#}"),
+                    serde_json::json!({
+                        "url": url,
+                        "file": local.as_str(),
+                        "error": format!("{e:#}"),
+                    }),
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    drop(fst);
+    if let Some(download) = fresh {
+        download.install(&local)?;
+    }
+    Ok(local)
+}
+
+// The file name of a url's copy in the cache: the url with anything but letters, digits,
+// dots and dashes replaced, so the value store's name follows from the fst's as usual
+fn cache_name(url: &str) -> String {
+    url.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn etag_path(local: &Utf8Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{local}.etag"))
+}
+
+/// A new copy of a remote file, downloaded next to where it's cached
+struct Download {
+    tmp: Utf8PathBuf,
+    etag: Option<String>,
+}
+
+impl Download {
+    // move the copy into place, then record its etag
+    fn install(self, local: &Utf8Path) -> Result<()> {
+        fs::rename(&self.tmp, local).with_context(|| format!("Error saving {local}"))?;
+        let etag = etag_path(local);
+        match self.etag {
+            Some(tag) => fs::write(&etag, tag)?,
+            // a server without etags can't revalidate, so the next run downloads again
+            None => {
+                let _ = fs::remove_file(&etag);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Download `url` unless the copy at `local` is current. None means the cached copy is to be
+// used: it matched the remote etag, or the remote couldn't be reached. Unless `reuse`, the
+// cached copy is ignored and the download has to succeed
+fn download(url: &str, local: &Utf8Path, reuse: bool) -> Result<Option<Download>> {
+    let etag = etag_path(local);
+    let cached = reuse && local.exists();
+    let known = fs::read_to_string(&etag)
+        .ok()
+        .filter(|_| cached)
+        .map(|tag| tag.trim().to_string());
+    // named for the process, so concurrent runs don't write into each other's download
+    let tmp = Utf8PathBuf::from(format!("{local}.{}.download", std::process::id()));
+
+    let fetched = match url.strip_prefix("s3://") {
+        Some(location) => download_s3(url, location, known.as_deref(), &tmp),
+        None => download_http(url, known.as_ref().map(|_| etag.as_path()), &tmp),
+    };
+    match fetched {
+        Ok(Some(etag)) => Ok(Some(Download { tmp, etag })),
+        Ok(None) => Ok(None),
+        Err(e) if cached => {
+            let _ = fs::remove_file(&tmp);
+            diagnostics::warn(
+                "stale_cache",
+                format!("can't revalidate {url}, using the cached copy: {e:#}"),
+                serde_json::json!({ "url": url, "file": local.as_str(), "error": format!("{e:#}") }),
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Fetch with curl, sending the saved etag if there's a copy to revalidate. Some(etag) when a
+// new copy was written to `tmp`, None when the server answered not modified
+fn download_http(
+    url: &str,
+    etag: Option<&Utf8Path>,
+    tmp: &Utf8Path,
+) -> Result<Option<Option<String>>> {
+    let saved = Utf8PathBuf::from(format!("{tmp}.etag"));
+    let mut curl = Command::new("curl");
+    curl.args(["--silent", "--show-error", "--fail", "--location"])
+        .args(["--write-out", "%{http_code}", "--output"])
+        .arg(tmp)
+        .arg("--etag-save")
+        .arg(&saved);
+    if let Some(etag) = etag {
+        curl.arg("--etag-compare").arg(etag);
+    }
+    let output = curl
+        .arg(url)
+        .output()
+        .context("Error running curl, which an http -f needs on the PATH")?;
+    let tag = fs::read_to_string(&saved)
+        .ok()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty());
+    let _ = fs::remove_file(&saved);
+    if !output.status.success() {
+        let _ = fs::remove_file(tmp);
+        bail!(
+            "curl failed fetching {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    if output.stdout == b"304" {
+        let _ = fs::remove_file(tmp);
+        return Ok(None);
+    }
+    Ok(Some(tag))
+}
+
+// Fetch with the aws cli, after comparing the object's etag with the saved one
+fn download_s3(
+    url: &str,
+    location: &str,
+    known: Option<&str>,
+    tmp: &Utf8Path,
+) -> Result<Option<Option<String>>> {
+    let Some((bucket, key)) = location.split_once('/') else {
+        bail!("{url} has no key, expected s3://BUCKET/KEY");
+    };
+    let head = aws(
+        Command::new("aws")
+            .args(["s3api", "head-object", "--bucket", bucket, "--key", key])
+            .args(["--query", "ETag", "--output", "text"]),
+        url,
+    )?;
+    let tag = String::from_utf8_lossy(&head).trim().to_string();
+    if known == Some(tag.as_str()) {
+        return Ok(None);
+    }
+    aws(
+        Command::new("aws")
+            .args(["s3", "cp", "--only-show-errors", url])
+            .arg(tmp),
+        url,
+    )?;
+    Ok(Some(Some(tag).filter(|tag| !tag.is_empty())))
+}
+
+fn aws(command: &mut Command, url: &str) -> Result<Vec<u8>> {
+    let output = command
+        .output()
+        .context("Error running aws, which an s3 -f needs on the PATH")?;
+    if !output.status.success() {
+        bail!(
+            "aws failed fetching {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,179 @@
+use crate::enrich;
+use crate::fstsed::{FstSed, ValueLimits};
+use crate::reload::Reloading;
+use crate::remote;
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use std::io::Read;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+// cap on the size of a bulk enrichment request body
+const MAX_BODY: u64 = 256 * 1024 * 1024;
+
+/// Serve key lookups and ndjson enrichment over http, for services that want enrichment on
+/// demand without a copy of the fst
+///
+/// GET /lookup?key=KEY answers {"key":..,"value":..} or 404. POST /enrich takes ndjson and
+/// answers each line enriched as with --enrich. A db replaced on disk is picked up without a
+/// restart
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// The fst db to serve, or an http(s):// or s3:// url to download it from
+    #[clap(short = 'f', value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    fst: Utf8PathBuf,
+
+    /// Address and port to listen on
+    #[clap(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Number of requests handled at once
+    #[clap(long, value_name = "N", default_value_t = 4)]
+    threads: usize,
+
+    /// Keep the decompressed values of up to N recently matched keys
+    #[clap(long, value_name = "N", default_value_t = 10_000)]
+    value_cache: usize,
+
+    /// Refuse stored values that decompress to more than N bytes
+    #[clap(long, value_name = "N", default_value_t = ValueLimits::default().max_value_bytes)]
+    max_value_bytes: usize,
+
+    /// Refuse stored values whose json nests deeper than N levels
+    #[clap(long, value_name = "N", default_value_t = ValueLimits::default().max_json_depth)]
+    max_json_depth: usize,
+}
+
+pub fn run_serve(args: &ServeArgs) -> Result<()> {
+    let fst = if remote::is_remote(args.fst.as_str()) {
+        remote::fetch(args.fst.as_str())?
+    } else {
+        args.fst.clone()
+    };
+    let fsed = Reloading::new(fst.clone(), || {
+        Ok(FstSed::new(fst.clone(), None)?
+            .with_value_cache(args.value_cache)
+            .with_value_limits(ValueLimits {
+                max_value_bytes: args.max_value_bytes,
+                max_json_depth: args.max_json_depth,
+            }))
+    })?;
+    let server =
+        Server::http(&args.listen).map_err(|e| anyhow!("cannot listen on {}: {e}", args.listen))?;
+    eprintln!("fstsed: serving {} on http://{}", args.fst, args.listen);
+
+    std::thread::scope(|scope| {
+        for _ in 0..args.threads.max(1) {
+            scope.spawn(|| {
+                while let Ok(request) = server.recv() {
+                    // a client that went away is no reason to stop serving
+                    let _ = handle(request, &fsed.current());
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn json_header() -> Header {
+    Header::from_bytes("Content-Type", "application/json").unwrap()
+}
+
+fn error(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    let body = serde_json::json!({ "error": message }).to_string();
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(json_header())
+}
+
+fn too_large() -> Response<std::io::Cursor<Vec<u8>>> {
+    error(413, &format!("request body is over {MAX_BODY} bytes"))
+}
+
+fn handle(mut request: Request, fsed: &FstSed) -> std::io::Result<()> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let response = match (request.method(), path) {
+        (Method::Get, "/lookup") => lookup(query, fsed),
+        (Method::Post, "/enrich")
+            if request
+                .body_length()
+                .is_some_and(|len| len as u64 > MAX_BODY) =>
+        {
+            too_large()
+        }
+        (Method::Post, "/enrich") => {
+            let mut body = Vec::new();
+            // read one byte past the cap to tell a body of exactly MAX_BODY from a longer one
+            let read = request
+                .as_reader()
+                .take(MAX_BODY + 1)
+                .read_to_end(&mut body);
+            match read {
+                Ok(len) if len as u64 > MAX_BODY => too_large(),
+                Ok(_) => enrich_body(&body, fsed),
+                Err(e) => error(400, &format!("reading request body: {e}")),
+            }
+        }
+        (_, "/lookup" | "/enrich") => error(405, "method not allowed"),
+        _ => error(404, "not found"),
+    };
+    request.respond(response)
+}
+
+fn lookup(query: &str, fsed: &FstSed) -> Response<std::io::Cursor<Vec<u8>>> {
+    let key = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == "key")
+        .map(|(_, value)| percent_decode(value));
+    let Some(key) = key else {
+        return error(400, "missing key parameter, e.g. /lookup?key=evil.com");
+    };
+    let Some(m) = fsed.get(key.as_bytes()) else {
+        return error(404, "key not found");
+    };
+    let m = fsed.get_match(&m);
+    let value =
+        serde_json::from_str(m.value()).unwrap_or_else(|_| serde_json::Value::from(m.value()));
+    let body = serde_json::json!({ "key": m.key(), "value": value }).to_string();
+    Response::from_string(body).with_header(json_header())
+}
+
+fn enrich_body(body: &[u8], fsed: &FstSed) -> Response<std::io::Cursor<Vec<u8>>> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut found = Vec::new();
+    // lines keep their newline, so a last line without one is answered without one too
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        // writing to a vec can't fail
+        let _ = enrich::enrich_line(&mut out, line, fsed, &mut found);
+    }
+    let header = Header::from_bytes("Content-Type", "application/x-ndjson").unwrap();
+    Response::from_data(out).with_header(header)
+}
+
+// Decode a query string value: %XX escapes, and + for space
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,169 @@
+use crate::fstsed::{value_text, FstSed};
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use serde_json::Value;
+use std::io::{self, IsTerminal};
+use termcolor::{Color, ColorChoice, ColorSpec, WriteColor};
+
+// the fields templates fill from the match itself, which hide record members of the same name
+const BUILTIN_FIELDS: [&str; 7] = [
+    "key",
+    "match",
+    "value",
+    "line_score",
+    "match_id",
+    "normalized_by",
+    "normalized",
+];
+
+/// Print the record stored with one key of a fstsed database, pretty-printed and colored for
+/// reading, followed by the template fields it fills and what each of them renders as
+#[derive(clap::Args, Debug)]
+pub struct ShowArgs {
+    /// The fst db to look the key up in
+    #[clap(short = 'f', value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    fst: Utf8PathBuf,
+
+    /// The key to show. Dbs of case-insensitive keys find it in any case
+    #[clap(value_name = "KEY")]
+    key: String,
+
+    /// When to color the record: always, never, or auto for when writing to a terminal
+    #[clap(
+        short = 'C',
+        long,
+        value_name = "WHEN",
+        default_value = "auto",
+        value_parser = ["always", "never", "auto"]
+    )]
+    color: String,
+}
+
+impl ShowArgs {
+    pub fn color(&self) -> ColorChoice {
+        match self.color.as_str() {
+            "always" => ColorChoice::Always,
+            "auto" if io::stdout().is_terminal() => ColorChoice::Always,
+            _ => ColorChoice::Never,
+        }
+    }
+}
+
+/// Show the record of the key, returning whether the db has it
+pub fn run_show<W: WriteColor>(args: &ShowArgs, out: &mut W) -> Result<bool> {
+    let fsed = FstSed::new(args.fst.clone(), None)?;
+    let Some(m) = fsed.get(args.key.as_bytes()) else {
+        eprintln!("key {:?} not found in {}", args.key, args.fst);
+        return Ok(false);
+    };
+    let value = fsed.value(&m)?;
+    let key = String::from_utf8_lossy(m.key());
+
+    let mut fields = vec![
+        ("key".to_string(), key.to_string()),
+        ("match".to_string(), key.to_string()),
+    ];
+    match serde_json::from_slice::<Value>(&value) {
+        Ok(record) => {
+            write_json(out, &record, 0)?;
+            writeln!(out)?;
+            fields.push(("value".to_string(), "the whole record".to_string()));
+            if let Value::Object(members) = &record {
+                // members hidden by a builtin field are still there by pointer
+                for (name, member) in members {
+                    let field = match BUILTIN_FIELDS.contains(&name.as_str()) {
+                        true => format!("/{}", escape(name)),
+                        false => name.clone(),
+                    };
+                    fields.push((field, value_text(member).into_owned()));
+                }
+                for (name, member) in members {
+                    nested_fields(&format!("/{}", escape(name)), member, &mut fields);
+                }
+            }
+        }
+        // not json, so there's nothing but the value as a whole to show
+        Err(_) => {
+            writeln!(out, "{}", String::from_utf8_lossy(&value))?;
+            fields.push(("value".to_string(), "the whole value".to_string()));
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "template fields:")?;
+    let width = fields.iter().map(|(name, _)| name.len()).max().unwrap_or(0) + 2;
+    for (name, text) in fields {
+        write!(out, "  ")?;
+        colored(out, Color::Blue, true, &format!("{{{name}}}"))?;
+        writeln!(out, "{:pad$}{text}", "", pad = width - name.len())?;
+    }
+    Ok(true)
+}
+
+// Escape a member name for a json pointer, per rfc 6901
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+// The pointers to everything inside of the nested object or array at `pointer`
+fn nested_fields(pointer: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    let children: Vec<(String, &Value)> = match value {
+        Value::Object(members) => members
+            .iter()
+            .map(|(name, member)| (format!("{pointer}/{}", escape(name)), member))
+            .collect(),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (format!("{pointer}/{i}"), item))
+            .collect(),
+        _ => return,
+    };
+    for (pointer, child) in children {
+        fields.push((pointer.clone(), value_text(child).into_owned()));
+        nested_fields(&pointer, child, fields);
+    }
+}
+
+fn colored<W: WriteColor>(out: &mut W, color: Color, bold: bool, text: &str) -> io::Result<()> {
+    out.set_color(ColorSpec::new().set_fg(Some(color)).set_bold(bold))?;
+    write!(out, "{text}")?;
+    out.reset()
+}
+
+// Pretty-print a json value indented by `indent` spaces, with member names, strings, numbers
+// and literals each in their own color
+fn write_json<W: WriteColor>(out: &mut W, value: &Value, indent: usize) -> io::Result<()> {
+    match value {
+        Value::Object(members) if !members.is_empty() => {
+            writeln!(out, "{{")?;
+            for (i, (name, member)) in members.iter().enumerate() {
+                write!(out, "{:indent$}", "", indent = indent + 2)?;
+                colored(
+                    out,
+                    Color::Blue,
+                    true,
+                    &Value::from(name.as_str()).to_string(),
+                )?;
+                write!(out, ": ")?;
+                write_json(out, member, indent + 2)?;
+                writeln!(out, "{}", if i + 1 < members.len() { "," } else { "" })?;
+            }
+            write!(out, "{:indent$}}}", "")
+        }
+        Value::Array(items) if !items.is_empty() => {
+            writeln!(out, "[")?;
+            for (i, item) in items.iter().enumerate() {
+                write!(out, "{:indent$}", "", indent = indent + 2)?;
+                write_json(out, item, indent + 2)?;
+                writeln!(out, "{}", if i + 1 < items.len() { "," } else { "" })?;
+            }
+            write!(out, "{:indent$}]", "")
+        }
+        Value::String(_) => colored(out, Color::Green, false, &value.to_string()),
+        Value::Number(_) => colored(out, Color::Cyan, false, &value.to_string()),
+        Value::Bool(_) | Value::Null => colored(out, Color::Yellow, false, &value.to_string()),
+        // an empty object or array
+        _ => write!(out, "{value}"),
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::{Map, Value};
+
+/// A query against a SQLite db, whose rows are records to build a fst from. Each row is an
+/// object of its columns by name. A text column holding a json object or array, e.g. from
+/// json() or json_object(), is nested as json, and a blob is written as hex
+pub struct Query {
+    conn: Connection,
+    sql: String,
+    db: String,
+}
+
+impl Query {
+    /// Open the db at `db` read-only to run `sql` against
+    pub fn open(db: &Utf8Path, sql: &str) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            db,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .with_context(|| format!("Error opening SQLite db {db}"))?;
+        Ok(Self {
+            conn,
+            sql: sql.to_string(),
+            db: db.to_string(),
+        })
+    }
+
+    /// The name of the query's first column
+    pub fn first_column(&self) -> Result<Option<String>> {
+        let stmt = self.prepare()?;
+        Ok(stmt.column_names().first().map(|name| name.to_string()))
+    }
+
+    /// Run the query, handing each row to `record` as it's read
+    pub fn for_each_record(&self, record: &mut dyn FnMut(Value) -> Result<()>) -> Result<()> {
+        let mut stmt = self.prepare()?;
+        let names: Vec<String> = stmt.column_names().iter().map(|n| n.to_string()).collect();
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows
+            .next()
+            .with_context(|| format!("Error querying {}", self.db))?
+        {
+            let mut columns = Map::with_capacity(names.len());
+            for (i, name) in names.iter().enumerate() {
+                columns.insert(name.clone(), column_value(row.get_ref(i)?));
+            }
+            record(Value::Object(columns))?;
+        }
+        Ok(())
+    }
+
+    fn prepare(&self) -> Result<rusqlite::Statement<'_>> {
+        self.conn
+            .prepare(&self.sql)
+            .with_context(|| format!("Error preparing the query against {}", self.db))
+    }
+}
+
+fn column_value(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::from(i),
+        ValueRef::Real(f) => serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number),
+        ValueRef::Text(text) => {
+            let text = String::from_utf8_lossy(text);
+            json_text(&text).unwrap_or_else(|| Value::from(text.into_owned()))
+        }
+        ValueRef::Blob(blob) => {
+            Value::from(blob.iter().map(|b| format!("{b:02x}")).collect::<String>())
+        }
+    }
+}
+
+// The json object or array a text column holds, if it holds one
+fn json_text(text: &str) -> Option<Value> {
+    let text = text.trim_start();
+    if !text.starts_with(['{', '[']) {
+        return None;
+    }
+    serde_json::from_str(text).ok()
+}
@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Counters reported by --stats
+#[derive(Copy, Clone)]
+pub enum Stat {
+    /// Matches found by the fst walk and kept
+    Matches,
+    /// Matches ignored because their key had expired (--respect-ttl)
+    Expired,
+    /// Output lines cut short by --max-output-line-bytes
+    Truncated,
+    /// Positions the fst walk was tried from
+    Candidates,
+}
+
+const STATS: [(Stat, &str); 4] = [
+    (Stat::Matches, "matches"),
+    (Stat::Expired, "expired"),
+    (Stat::Truncated, "truncated lines"),
+    (Stat::Candidates, "candidates tested"),
+];
+
+// process wide like the timings, and likewise nothing is counted unless --stats is given
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static COUNTS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Turn counting on or off for a while, returning whether it was on
+pub fn set_enabled(enabled: bool) -> bool {
+    ENABLED.swap(enabled, Ordering::Relaxed)
+}
+
+#[inline]
+pub fn count(stat: Stat) {
+    if ENABLED.load(Ordering::Relaxed) {
+        COUNTS[stat as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The count of one counter so far
+pub fn get(stat: Stat) -> u64 {
+    COUNTS[stat as usize].load(Ordering::Relaxed)
+}
+
+/// Print the counters to stderr
+pub fn report() {
+    for (stat, name) in STATS {
+        eprintln!("{name}: {}", COUNTS[stat as usize].load(Ordering::Relaxed));
+    }
+}
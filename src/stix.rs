@@ -0,0 +1,146 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+enum Token {
+    /// A plain quoted string literal
+    Literal(String),
+    /// Anything else: object paths, keywords, operators, typed literals and punctuation
+    Other(String),
+}
+
+/// Split a STIX pattern into string literals and everything else. Quoted object path
+/// components (hashes.'SHA-256') and typed literals (t'..', b'..', h'..') are kept as part of
+/// the surrounding token instead of being treated as string literals
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut chars = pattern.chars().peekable();
+
+    let flush = |current: &mut String, tokens: &mut Vec<Token>| {
+        if !current.is_empty() {
+            tokens.push(Token::Other(std::mem::take(current)));
+        }
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // read the literal, unescaping \' and \\
+                let mut literal = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => literal.extend(chars.next()),
+                        '\'' => break,
+                        _ => literal.push(c),
+                    }
+                }
+                if current.ends_with(|p: char| p == '.' || p.is_ascii_alphanumeric()) {
+                    // path component or typed literal, glue it to the current token
+                    current.push('\'');
+                    current.push_str(&literal);
+                    current.push('\'');
+                } else {
+                    flush(&mut current, &mut tokens);
+                    tokens.push(Token::Literal(literal));
+                }
+            }
+            '[' | ']' | '(' | ')' | ',' => {
+                flush(&mut current, &mut tokens);
+                tokens.push(Token::Other(c.to_string()));
+            }
+            c if c.is_whitespace() => flush(&mut current, &mut tokens),
+            '=' | '!' | '<' | '>' => {
+                // operators may be written without surrounding spaces
+                if !current.ends_with(['!', '<', '>']) {
+                    flush(&mut current, &mut tokens);
+                }
+                current.push(c);
+                if chars.peek() != Some(&'=') {
+                    flush(&mut current, &mut tokens);
+                }
+            }
+            _ => {
+                if current.ends_with(['=', '!', '<', '>']) {
+                    flush(&mut current, &mut tokens);
+                }
+                current.push(c);
+            }
+        }
+    }
+    flush(&mut current, &mut tokens);
+    tokens
+}
+
+/// Extract the indicator values from a STIX 2.1 pattern, e.g. `evil.com` from
+/// `[domain-name:value = 'evil.com']` or both hashes from
+/// `[file:hashes.MD5 = 'abc' OR file:hashes.'SHA-256' = 'def']`.
+///
+/// Only string literals compared for equality (`=` and `IN (...)`, not negated with NOT) are
+/// returned. Wildcards and regexes (LIKE, MATCHES) and inequalities aren't indicator values.
+pub fn pattern_values(pattern: &str) -> Vec<String> {
+    let tokens = tokenize(pattern);
+    let mut values = Vec::new();
+
+    let is = |i: usize, word: &str| match tokens.get(i) {
+        Some(Token::Other(t)) => t.eq_ignore_ascii_case(word),
+        _ => false,
+    };
+
+    for i in 0..tokens.len() {
+        if !(is(i, "=") || is(i, "IN")) || (i > 0 && is(i - 1, "NOT")) {
+            continue;
+        }
+        match tokens.get(i + 1) {
+            Some(Token::Literal(value)) => values.push(value.clone()),
+            Some(Token::Other(paren)) if paren == "(" => {
+                for token in &tokens[i + 2..] {
+                    match token {
+                        Token::Literal(value) => values.push(value.clone()),
+                        Token::Other(t) if t == "," => {}
+                        Token::Other(_) => break,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    values.retain(|v| !v.is_empty());
+    values
+}
+
+/// Flatten STIX bundles into one object per line for building. `input` is returned unchanged
+/// unless its first json value is a bundle, as in a STIX export: then it's read as a stream of
+/// json values, bundles or single objects, and each indicator and each cyber observable with
+/// a value is written on its own line. Other objects (identities, relationships, markings...)
+/// have no keys and are left out
+pub fn expand_bundles(input: Vec<u8>) -> Result<Vec<u8>> {
+    let mut values = serde_json::Deserializer::from_slice(&input).into_iter::<Value>();
+    match values.next() {
+        Some(Ok(first)) if is_bundle(&first) => {}
+        _ => return Ok(input),
+    }
+    let mut lines = Vec::with_capacity(input.len());
+    let values = serde_json::Deserializer::from_slice(&input).into_iter::<Value>();
+    for value in values {
+        let mut value = value.context("Error reading STIX bundle")?;
+        let objects = if is_bundle(&value) {
+            match value.get_mut("objects").map(Value::take) {
+                Some(Value::Array(objects)) => objects,
+                _ => Vec::new(),
+            }
+        } else {
+            vec![value]
+        };
+        for object in objects {
+            if object["type"] == "indicator" || object.get("value").is_some() {
+                serde_json::to_writer(&mut lines, &object)?;
+                lines.push(b'\n');
+            }
+        }
+    }
+    Ok(lines)
+}
+
+fn is_bundle(value: &Value) -> bool {
+    value.get("type").is_some_and(|t| t == "bundle")
+}
@@ -0,0 +1,118 @@
+use crate::template::Template;
+use anyhow::{anyhow, bail, Context, Result};
+use termcolor::{Ansi, Color, ColorSpec, WriteColor};
+
+/// How highlighted matches look: a style for the whole rendered match and styles for single
+/// fields of the template within it. Each is given as a STYLE, or FIELD=STYLE for a field,
+/// where STYLE is a comma separated list of
+/// - a color for the text: black, blue, green, red, cyan, magenta, yellow, white or an ansi
+///   number 0-255
+/// - bg:COLOR for the background
+/// - bold, dimmed, italic, underline, strikethrough or intense
+/// - none, for no styling at all
+///
+/// e.g. "yellow,underline" or "actor=cyan,bold". Without a style for the whole match, it's
+/// bold red
+pub struct ColorStyles {
+    matched: ColorSpec,
+    fields: Vec<(String, ColorSpec)>,
+}
+
+impl Default for ColorStyles {
+    fn default() -> Self {
+        let mut matched = ColorSpec::new();
+        matched.set_fg(Some(Color::Red)).set_bold(true);
+        Self {
+            matched,
+            fields: Vec::new(),
+        }
+    }
+}
+
+impl ColorStyles {
+    /// Read the styles of a list of STYLE and FIELD=STYLE. A later style of the same field or
+    /// the whole match replaces an earlier one
+    pub fn parse<S: AsRef<str>>(styles: &[S]) -> Result<Self> {
+        let mut parsed = Self::default();
+        for style in styles {
+            let style = style.as_ref();
+            match style.split_once('=') {
+                Some((field, spec)) => {
+                    let field = field.trim().to_string();
+                    let spec = parse_spec(spec).with_context(|| format!("in style of {field}"))?;
+                    parsed.fields.retain(|(name, _)| *name != field);
+                    parsed.fields.push((field, spec));
+                }
+                None => parsed.matched = parse_spec(style)?,
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Wrap the rendered matches of `template` in the escapes of the styles. A styled field
+    /// turns the style of the whole match back on after itself
+    pub fn apply(&self, template: &mut Template) -> Result<()> {
+        let start = escape(&self.matched);
+        for (field, spec) in &self.fields {
+            if !template.fields().any(|name| name == field) {
+                bail!("--color-style styles field {field:?}, which isn't in the template");
+            }
+            template.style_field(field, &escape(spec), &(reset() + &start));
+        }
+        if !start.is_empty() {
+            template.bookend(&start, &reset());
+        }
+        Ok(())
+    }
+}
+
+fn parse_spec(spec: &str) -> Result<ColorSpec> {
+    let mut parsed = ColorSpec::new();
+    for word in spec.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+        match word {
+            "none" => {
+                parsed.clear();
+                continue;
+            }
+            "bold" => parsed.set_bold(true),
+            "dimmed" => parsed.set_dimmed(true),
+            "italic" => parsed.set_italic(true),
+            "underline" => parsed.set_underline(true),
+            "strikethrough" => parsed.set_strikethrough(true),
+            "intense" => parsed.set_intense(true),
+            _ => match word.strip_prefix("bg:") {
+                Some(color) => parsed.set_bg(Some(parse_color(color)?)),
+                None => parsed.set_fg(Some(parse_color(word)?)),
+            },
+        };
+    }
+    Ok(parsed)
+}
+
+fn parse_color(color: &str) -> Result<Color> {
+    color.parse().map_err(|_| {
+        anyhow!(
+            "unknown color style {color:?}, expected a color name or number, bg:COLOR, bold, \
+             dimmed, italic, underline, strikethrough, intense or none"
+        )
+    })
+}
+
+// The ansi escape that turns a style on, empty for no style
+fn escape(spec: &ColorSpec) -> String {
+    let mut spec = spec.clone();
+    spec.set_reset(false);
+    ansi(|ansi| ansi.set_color(&spec))
+}
+
+// The ansi escape that turns all styles off
+fn reset() -> String {
+    ansi(|ansi| ansi.reset())
+}
+
+fn ansi(write: impl FnOnce(&mut Ansi<Vec<u8>>) -> std::io::Result<()>) -> String {
+    let mut ansi = Ansi::new(Vec::new());
+    // writing to a vec can't fail
+    let _ = write(&mut ansi);
+    String::from_utf8(ansi.into_inner()).unwrap_or_default()
+}
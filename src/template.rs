@@ -0,0 +1,211 @@
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+
+/// A source of field values for rendering a template
+pub trait Context {
+    fn get_field(&self, field_name: &str) -> Cow<'_, str>;
+}
+
+/// Transformations applied to a field value, written after the field name and separated by |
+/// e.g. {name|trim|upper} or {org|default:unknown}
+enum Filter {
+    Upper,
+    Lower,
+    Trim,
+    /// Substitute the given text when the value is empty or missing
+    Default(String),
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Result<Self> {
+        let (name, arg) = match spec.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (spec, None),
+        };
+        Ok(match (name.trim(), arg) {
+            ("upper", None) => Self::Upper,
+            ("lower", None) => Self::Lower,
+            ("trim", None) => Self::Trim,
+            ("default", Some(arg)) => Self::Default(arg.to_string()),
+            ("default", None) => {
+                bail!("template filter default needs a value, e.g. default:unknown")
+            }
+            _ => bail!(
+                "unknown template filter {spec:?}, expected upper, lower, trim or default:TEXT"
+            ),
+        })
+    }
+
+    fn apply(&self, value: String) -> String {
+        match self {
+            Self::Upper => value.to_uppercase(),
+            Self::Lower => value.to_lowercase(),
+            Self::Trim => value.trim().to_string(),
+            Self::Default(default) if value.is_empty() => default.clone(),
+            Self::Default(_) => value,
+        }
+    }
+}
+
+enum Part {
+    Literal(String),
+    Field {
+        name: String,
+        filters: Vec<Filter>,
+        /// {-name}: when the value is empty, drop the whitespace before the field
+        trim_before: bool,
+        /// {name-}: when the value is empty, drop the whitespace after the field
+        trim_after: bool,
+        /// Fixed text written before and after the value, when it isn't empty
+        style: Option<(String, String)>,
+    },
+}
+
+/// Template is a parsed decoration format. Field names are enclosed in {}, and any text
+/// outside of them is copied as is. A { without a closing } is literal text. A - just inside
+/// the braces, as in {-name} or {name-}, removes the whitespace on that side of an empty field
+pub struct Template {
+    parts: Vec<Part>,
+    collapse: bool,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            let Some(close) = rest[open..].find('}').map(|i| open + i) else {
+                break;
+            };
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let mut spec = &rest[open + 1..close];
+            let trim_before = spec.len() > 1 && spec.starts_with('-');
+            if trim_before {
+                spec = &spec[1..];
+            }
+            let trim_after = spec.len() > 1 && spec.ends_with('-');
+            if trim_after {
+                spec = &spec[..spec.len() - 1];
+            }
+            let mut spec = spec.split('|');
+            let name = spec.next().unwrap_or_default().to_string();
+            let filters = spec.map(Filter::parse).collect::<Result<Vec<_>>>()?;
+            parts.push(Part::Field {
+                name,
+                filters,
+                trim_before,
+                trim_after,
+                style: None,
+            });
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        Ok(Self {
+            parts,
+            collapse: false,
+        })
+    }
+
+    /// Squeeze each run of spaces and tabs in the rendered output down to one space, so empty
+    /// optional fields don't leave gaps
+    pub fn collapse_whitespace(&mut self) {
+        self.collapse = true;
+    }
+
+    /// Wrap the template in fixed text, e.g. color escapes
+    pub fn bookend(&mut self, before: &str, after: &str) {
+        self.parts.insert(0, Part::Literal(before.to_string()));
+        self.parts.push(Part::Literal(after.to_string()));
+    }
+
+    /// Wrap each nonempty value of the field `name` in fixed text, e.g. its own color escapes
+    pub fn style_field(&mut self, name: &str, before: &str, after: &str) {
+        for part in &mut self.parts {
+            if let Part::Field {
+                name: field, style, ..
+            } = part
+            {
+                if field == name {
+                    *style = Some((before.to_string(), after.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Names of all the fields the template refers to
+    pub fn fields(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Field { name, .. } => Some(name.as_str()),
+            Part::Literal(_) => None,
+        })
+    }
+
+    pub fn render<C: Context>(&self, context: &C) -> String {
+        let mut out = String::new();
+        // set by an empty {name-} field to drop the leading whitespace of the next literal
+        let mut trim_next = false;
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) if trim_next => out.push_str(text.trim_start()),
+                Part::Literal(text) => out.push_str(text),
+                Part::Field {
+                    name,
+                    filters,
+                    trim_before,
+                    trim_after,
+                    style,
+                } => {
+                    let value = if filters.is_empty() {
+                        context.get_field(name)
+                    } else {
+                        Cow::Owned(
+                            filters
+                                .iter()
+                                .fold(context.get_field(name).into_owned(), |v, f| f.apply(v)),
+                        )
+                    };
+                    if value.is_empty() && *trim_before {
+                        out.truncate(out.trim_end().len());
+                    }
+                    match style {
+                        Some((before, after)) if !value.is_empty() => {
+                            out.push_str(before);
+                            out.push_str(&value);
+                            out.push_str(after);
+                        }
+                        _ => out.push_str(&value),
+                    }
+                    trim_next = value.is_empty() && *trim_after;
+                    continue;
+                }
+            }
+            trim_next = false;
+        }
+        if self.collapse {
+            out = collapse_blanks(&out);
+        }
+        out
+    }
+}
+
+// Replace each run of spaces and tabs with a single space
+fn collapse_blanks(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+    for c in text.chars() {
+        if c == ' ' || c == '\t' {
+            if !blank {
+                out.push(' ');
+            }
+            blank = true;
+        } else {
+            out.push(c);
+            blank = false;
+        }
+    }
+    out
+}
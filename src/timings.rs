@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The stages of the search that --timings accounts for. Whatever isn't spent in one of them
+/// is reading, writing and bookkeeping, reported as io and other
+#[derive(Copy, Clone)]
+pub enum Phase {
+    /// Finding candidate start positions with the boundary regexes
+    Scan,
+    /// Walking the fst from a candidate position
+    Walk,
+    /// zstd decompression of matched values
+    Decompress,
+    /// Deserializing matched values for templates and json strings for json mode
+    JsonParse,
+    /// Rendering the template of a match
+    Render,
+}
+
+const PHASES: [(Phase, &str); 5] = [
+    (Phase::Scan, "candidate scan"),
+    (Phase::Walk, "fst walk"),
+    (Phase::Decompress, "decompression"),
+    (Phase::JsonParse, "json parse"),
+    (Phase::Render, "render"),
+];
+
+// process wide so the matcher doesn't need to carry a profiler around. Timing is off unless
+// --timings is given, in which case every measured call pays for two clock reads
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NANOS: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+static CALLS: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Turn timing on or off for a while, returning whether it was on
+pub fn set_enabled(enabled: bool) -> bool {
+    ENABLED.swap(enabled, Ordering::Relaxed)
+}
+
+/// Start timing a phase. Returns None, and costs next to nothing, when timings are off
+#[inline]
+pub fn start() -> Option<Instant> {
+    if ENABLED.load(Ordering::Relaxed) {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+/// Account the time since `started` to the phase
+#[inline]
+pub fn record(phase: Phase, started: Option<Instant>) {
+    if let Some(started) = started {
+        let i = phase as usize;
+        NANOS[i].fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        CALLS[i].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Print where the time of the run went to stderr
+pub fn report(total: Duration) {
+    let total_nanos = total.as_nanos().max(1) as u64;
+    let mut accounted = 0;
+    eprintln!("timings: {:.3}s total", total.as_secs_f64());
+    for (phase, name) in PHASES {
+        let nanos = NANOS[phase as usize].load(Ordering::Relaxed);
+        let calls = CALLS[phase as usize].load(Ordering::Relaxed);
+        accounted += nanos;
+        eprintln!(
+            "  {name:<15} {:>9.3}s {:>5.1}% {calls:>12} calls",
+            nanos as f64 / 1e9,
+            nanos as f64 * 100.0 / total_nanos as f64,
+        );
+    }
+    let other = total_nanos.saturating_sub(accounted);
+    eprintln!(
+        "  {:<15} {:>9.3}s {:>5.1}%",
+        "io and other",
+        other as f64 / 1e9,
+        other as f64 * 100.0 / total_nanos as f64,
+    );
+}
@@ -0,0 +1,107 @@
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+/// One item of a value transform projection list
+enum Projection {
+    /// Set field `name` to the value found at the json pointer
+    Field { name: String, pointer: String },
+    /// Merge all members of the object found at the json pointer into the result
+    Lift { pointer: String },
+}
+
+impl Projection {
+    // copy the top level field `name`, whatever characters it holds
+    fn field(name: &str) -> Self {
+        Self::Field {
+            name: name.to_string(),
+            pointer: format!("/{}", name.replace('~', "~0").replace('/', "~1")),
+        }
+    }
+}
+
+/// ValueTransform reshapes a json record before it is stored as a fst value. The expression
+/// is a comma separated projection list, where each item is one of:
+///
+/// - `name` copies the top level field `name`
+/// - `/json/pointer` copies the value at the pointer, named after the last path segment
+/// - `name=/json/pointer` copies the value at the pointer into field `name` (renaming)
+/// - `/json/pointer/*` lifts all members of the object at the pointer to the top level
+///
+/// Items are applied in order, so later items overwrite earlier ones with the same name.
+/// Missing values are left out of the result.
+pub struct ValueTransform {
+    projections: Vec<Projection>,
+}
+
+impl ValueTransform {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let mut projections = Vec::new();
+        for item in expr.split(',').map(str::trim) {
+            if item.is_empty() {
+                continue;
+            }
+            let projection = if let Some((name, pointer)) = item.split_once('=') {
+                let (name, pointer) = (name.trim(), pointer.trim());
+                if name.is_empty() || !pointer.starts_with('/') {
+                    bail!("invalid value transform item {item:?}, expected name=/json/pointer");
+                }
+                Projection::Field {
+                    name: name.to_string(),
+                    pointer: pointer.to_string(),
+                }
+            } else if let Some(pointer) = item.strip_suffix("/*") {
+                Projection::Lift {
+                    pointer: pointer.to_string(),
+                }
+            } else if item.starts_with('/') {
+                // name after the last segment, unescaped per rfc 6901
+                let name = item.rsplit('/').next().unwrap_or_default();
+                Projection::Field {
+                    name: name.replace("~1", "/").replace("~0", "~"),
+                    pointer: item.to_string(),
+                }
+            } else {
+                Projection::field(item)
+            };
+            projections.push(projection);
+        }
+        if projections.is_empty() {
+            bail!("value transform {expr:?} is empty");
+        }
+        Ok(Self { projections })
+    }
+
+    /// A transform that keeps just the named top level fields of a record. Unlike in an
+    /// expression, the names are taken as they are, even with a / or = in them
+    pub fn keep_fields(names: &[String]) -> Result<Self> {
+        if names.iter().all(String::is_empty) {
+            bail!("no fields to keep");
+        }
+        let projections = names
+            .iter()
+            .filter(|name| !name.is_empty())
+            .map(|name| Projection::field(name))
+            .collect();
+        Ok(Self { projections })
+    }
+
+    /// Build the transformed record from the given source record
+    pub fn apply(&self, source: &Value) -> Value {
+        let mut result = Map::new();
+        for projection in &self.projections {
+            match projection {
+                Projection::Field { name, pointer } => {
+                    if let Some(v) = source.pointer(pointer) {
+                        result.insert(name.clone(), v.clone());
+                    }
+                }
+                Projection::Lift { pointer } => {
+                    if let Some(Value::Object(obj)) = source.pointer(pointer) {
+                        result.extend(obj.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    }
+                }
+            }
+        }
+        Value::Object(result)
+    }
+}
@@ -0,0 +1,103 @@
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Key expiry read from a field of each record, e.g. {"key":"evil.com","expires":"2024-06-30"}.
+/// Records without the field, or with a value that isn't a time, never expire
+pub struct Expiry {
+    /// Field name or jsonpointer holding the expiration time
+    field: String,
+    /// Unix time that expiration times are compared against
+    now: i64,
+}
+
+impl Expiry {
+    /// Expire records whose `field` is at or before the current time
+    pub fn new(field: &str) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        Self {
+            field: field.to_string(),
+            now,
+        }
+    }
+
+    /// Whether the decompressed json value of a key has expired
+    pub fn is_expired(&self, value: &[u8]) -> bool {
+        let Ok(record) = serde_json::from_slice::<Value>(value) else {
+            return false;
+        };
+        let found = if self.field.starts_with('/') {
+            record.pointer(&self.field)
+        } else {
+            record.get(&self.field)
+        };
+        found
+            .and_then(unix_time)
+            .is_some_and(|expires| expires <= self.now)
+    }
+}
+
+/// Seconds since the epoch of a json time: a number of seconds, or a string holding either
+/// that or a utc date or rfc 3339 date time such as 2024-06-30 or 2024-06-30T12:00:00Z
+pub fn unix_time(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
+        Value::String(s) => s.trim().parse().ok().or_else(|| parse_datetime(s.trim())),
+        _ => None,
+    }
+}
+
+// The number in s[range], if it's all digits
+fn digits(s: &str, range: std::ops::Range<usize>) -> Option<i64> {
+    let part = s.get(range)?;
+    if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    part.parse().ok()
+}
+
+// yyyy-mm-dd, optionally followed by Thh:mm:ss, fractional seconds and a Z or +hh:mm offset
+fn parse_datetime(s: &str) -> Option<i64> {
+    if s.get(4..5) != Some("-") || s.get(7..8) != Some("-") {
+        return None;
+    }
+    let days = days_from_civil(digits(s, 0..4)?, digits(s, 5..7)?, digits(s, 8..10)?)?;
+    let time = &s[10..];
+    if time.is_empty() {
+        return Some(days * 86400);
+    }
+    if !time.starts_with(['T', 't', ' ']) || time.get(3..4) != Some(":") {
+        return None;
+    }
+    let secs =
+        days * 86400 + digits(time, 1..3)? * 3600 + digits(time, 4..6)? * 60 + digits(time, 7..9)?;
+    // skip fractional seconds, then apply the offset
+    let zone = time[9..].trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    match zone {
+        "" | "Z" | "z" => Some(secs),
+        _ if zone.len() == 6 && zone.get(3..4) == Some(":") => {
+            let offset = digits(zone, 1..3)? * 3600 + digits(zone, 4..6)? * 60;
+            match &zone[..1] {
+                "+" => Some(secs - offset),
+                "-" => Some(secs + offset),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// days since 1970-01-01 of a proleptic gregorian date, from Howard Hinnant's date algorithms
+fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146097 + doe - 719468)
+}
@@ -0,0 +1,80 @@
+use crate::build::{update_fstsed, KeySpec};
+use crate::transform::ValueTransform;
+use anyhow::{bail, Context, Result};
+use bstr::io::BufReadExt;
+use camino::Utf8PathBuf;
+use std::fs::File;
+use std::io::{self, BufReader};
+
+const BUFFERSIZE: usize = 64 * 1024;
+
+/// Copy a fstsed database with new json records added. The new records' values replace those
+/// of keys already in the db, and the db's other entries are copied over as they are, so this
+/// is much faster than building from all the source records again
+#[derive(clap::Args, Debug)]
+pub struct UpdateArgs {
+    /// The fst db to update
+    #[clap(short = 'f', value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    fst: Utf8PathBuf,
+
+    /// Where to write the updated fst db. Must not exist yet
+    #[clap(short = 'o', long, value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    output: Utf8PathBuf,
+
+    /// The json field or jsonpointer of the new records' key. Repeat -k for records with
+    /// several key fields. Defaults to "key"
+    #[clap(short = 'k', long, value_name = "KEY")]
+    key: Vec<String>,
+
+    /// Reshape each new record before storing it as the value, as --value-transform does
+    /// when building
+    #[clap(long, value_name = "EXPR")]
+    value_transform: Option<String>,
+
+    /// The zstd level to compress the new values at, from 1 (fastest) to 22 (smallest)
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(i32).range(1..=22)
+    )]
+    compress_level: i32,
+
+    /// The new json records, one per line. Reads stdin if not given or -
+    #[clap(value_name = "INPUT")]
+    input: Option<Utf8PathBuf>,
+}
+
+pub fn run_update(args: &UpdateArgs) -> Result<()> {
+    if args.output.exists() {
+        bail!(
+            "fst path {} already exists. Please specify an alternate path or rename/delete existing fst.",
+            args.output
+        );
+    }
+    let keys: Vec<KeySpec> = if args.key.is_empty() {
+        vec![KeySpec::Field("key".to_string())]
+    } else {
+        args.key.iter().cloned().map(KeySpec::Field).collect()
+    };
+    let transform = args
+        .value_transform
+        .as_deref()
+        .map(ValueTransform::parse)
+        .transpose()?;
+    let reader: Box<dyn BufReadExt> = match &args.input {
+        Some(path) if path.as_str() != "-" => {
+            let file = File::open(path).with_context(|| format!("Error opening {path}"))?;
+            Box::new(BufReader::with_capacity(BUFFERSIZE, file))
+        }
+        _ => Box::new(BufReader::with_capacity(BUFFERSIZE, io::stdin())),
+    };
+    update_fstsed(
+        reader,
+        &keys,
+        &args.fst,
+        &args.output,
+        transform.as_ref(),
+        args.compress_level,
+    )
+}
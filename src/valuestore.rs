@@ -0,0 +1,217 @@
+use crate::fstsed::{meta, meta_entry, FstData};
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use fst::raw::Fst;
+use memmap2::Mmap;
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+/// Name of the metadata entry saying how keys and values are laid out, present for dbs that
+/// don't keep their values in the fst
+pub const META_LAYOUT: &str = "layout";
+
+/// Name of the metadata entry holding the id of the value store written with a map layout db.
+/// The store has the same id in its header, so a store that doesn't go with the fst, e.g. one
+/// renamed into place ahead of its new fst, isn't read
+pub const META_STORE_ID: &str = "store_id";
+
+/// The version of the value store format, the number at the end of its magic
+pub const FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 8] = b"FSTSEDV1";
+// the magic, the number of values and the store id
+const HEADER_LEN: usize = 24;
+
+/// How a db stores its values
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Layout {
+    /// An fst map from each key to the number of its value in a value store file next to the
+    /// fst, FST.values. Keys may hold any byte, and the automaton holds only the keys
+    #[default]
+    Map,
+    /// Each value in the fst itself, after its key and a NUL sentinel. A single file, e.g. for
+    /// loading into a browser with its values, but keys can't contain NUL
+    Sentinel,
+}
+
+impl Layout {
+    /// The layout of a db, per its metadata. Dbs from before the map layout have none
+    pub fn of<D: AsRef<[u8]>>(fst: &Fst<D>) -> Self {
+        if meta(fst, META_LAYOUT).is_some_and(|layout| layout == b"map") {
+            Self::Map
+        } else {
+            Self::Sentinel
+        }
+    }
+}
+
+/// The value store that goes with the map layout db at `fst`
+pub fn sidecar_path(fst: &Utf8Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{fst}.values"))
+}
+
+/// The values of a map layout db, numbered in the order they were written. The file is the
+/// magic, the number of values, the store id and a table of the offset of each value, all
+/// little endian u64s, followed by the values, each a u32 length and its bytes. A value can be
+/// replaced by appending the new one and pointing its table slot at it, without touching the
+/// fst
+pub struct ValueStore {
+    data: FstData,
+    len: u64,
+    id: u64,
+}
+
+impl ValueStore {
+    /// Memory map the value store at `path`
+    pub fn open(path: &Utf8Path) -> Result<Self> {
+        let mmap = File::open(path)
+            .and_then(|file| unsafe { Mmap::map(&file) })
+            .with_context(|| format!("Error opening value store {path}"))?;
+        Self::new(FstData::Mmap(mmap)).with_context(|| format!("Error opening value store {path}"))
+    }
+
+    /// Memory map the value store at `path`, failing unless it was written with `fst`
+    pub fn open_for<D: AsRef<[u8]>>(fst: &Fst<D>, path: &Utf8Path) -> Result<Self> {
+        let store = Self::open(path)?;
+        store
+            .check(fst)
+            .with_context(|| format!("Error opening value store {path}"))?;
+        Ok(store)
+    }
+
+    /// Fail unless this store was written with `fst`: the store ids in the fst's metadata and
+    /// the store's header are the same
+    pub fn check<D: AsRef<[u8]>>(&self, fst: &Fst<D>) -> Result<()> {
+        let expected = meta(fst, META_STORE_ID);
+        if expected.as_deref() != Some(id_text(self.id).as_bytes()) {
+            bail!("value store doesn't go with the fst, it was written by another build");
+        }
+        Ok(())
+    }
+
+    /// A value store already read into memory
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Self::new(FstData::Bytes(bytes)).context("Error loading value store")
+    }
+
+    fn new(data: FstData) -> Result<Self> {
+        let bytes = data.as_ref();
+        if bytes.get(..8) != Some(&MAGIC[..]) {
+            bail!("not a fstsed value store");
+        }
+        if bytes.len() < HEADER_LEN {
+            bail!("value store is truncated");
+        }
+        let len = u64::from_le_bytes(bytes[8..16].try_into()?);
+        let id = u64::from_le_bytes(bytes[16..24].try_into()?);
+        if (bytes.len() as u64 - HEADER_LEN as u64) / 8 < len {
+            bail!("value store is truncated");
+        }
+        Ok(Self { data, len, id })
+    }
+
+    /// The number of values
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The size of the store in bytes
+    pub fn size(&self) -> usize {
+        self.data.as_ref().len()
+    }
+
+    /// The value numbered `index`, None if there's no such value or the store is corrupt
+    pub fn get(&self, index: u64) -> Option<&[u8]> {
+        if index >= self.len {
+            return None;
+        }
+        let bytes = self.data.as_ref();
+        let slot = HEADER_LEN + index as usize * 8;
+        // the offsets and lengths come from the file, so a corrupt one must not overflow
+        let offset =
+            usize::try_from(u64::from_le_bytes(bytes[slot..slot + 8].try_into().ok()?)).ok()?;
+        let start = offset.checked_add(4)?;
+        let len = u32::from_le_bytes(bytes.get(offset..start)?.try_into().ok()?) as usize;
+        bytes.get(start..start.checked_add(len)?)
+    }
+}
+
+/// Collects the values of a db being built, to write them out as a ValueStore
+pub struct ValueStoreWriter {
+    offsets: Vec<u64>,
+    values: Vec<u8>,
+    id: u64,
+}
+
+impl Default for ValueStoreWriter {
+    fn default() -> Self {
+        // a random id, different for every store written
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(now.as_nanos());
+        }
+        hasher.write_u32(std::process::id());
+        Self {
+            offsets: Vec::new(),
+            values: Vec::new(),
+            id: hasher.finish(),
+        }
+    }
+}
+
+impl ValueStoreWriter {
+    /// The metadata entries `meta` of the fst the values go with, sorted, with the id of this
+    /// store in place of any other store's
+    pub fn stamp(&self, meta: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let other = meta_entry(META_STORE_ID, b"");
+        let mut stamped: Vec<Vec<u8>> = meta
+            .iter()
+            .filter(|entry| !entry.starts_with(&other))
+            .cloned()
+            .collect();
+        stamped.push(meta_entry(META_STORE_ID, id_text(self.id).as_bytes()));
+        stamped.sort_unstable();
+        stamped
+    }
+
+    /// Add a value, returning its number
+    pub fn push(&mut self, value: &[u8]) -> Result<u64> {
+        let len = u32::try_from(value.len()).context("value is larger than 4 GiB")?;
+        self.offsets.push(self.values.len() as u64);
+        self.values.extend_from_slice(&len.to_le_bytes());
+        self.values.extend_from_slice(value);
+        Ok(self.offsets.len() as u64 - 1)
+    }
+
+    /// Write the value store to `path`
+    pub fn save(self, path: &Utf8Path) -> Result<()> {
+        let file =
+            File::create(path).with_context(|| format!("Error creating value store {path}"))?;
+        let mut out = io::BufWriter::new(file);
+        let start = (HEADER_LEN + self.offsets.len() * 8) as u64;
+        out.write_all(MAGIC)?;
+        out.write_all(&(self.offsets.len() as u64).to_le_bytes())?;
+        out.write_all(&self.id.to_le_bytes())?;
+        for offset in &self.offsets {
+            out.write_all(&(start + offset).to_le_bytes())?;
+        }
+        out.write_all(&self.values)?;
+        out.flush()
+            .with_context(|| format!("Error writing value store {path}"))
+    }
+}
+
+// how a store id is written in the fst's metadata
+fn id_text(id: u64) -> String {
+    format!("{id:016x}")
+}
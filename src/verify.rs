@@ -0,0 +1,121 @@
+use crate::charset::KeyType;
+use crate::fstsed::{decompress, meta, mmap_fst, ValueCodec, META_KEY_TYPE, SENTINEL};
+use crate::hashed::META_MAX_KEY_LEN;
+use crate::valuestore::{sidecar_path, Layout, ValueStore};
+use anyhow::{Context, Result};
+use camino::Utf8PathBuf;
+use fst::{Map, Streamer};
+use std::io::Write;
+
+/// Check a fstsed database for corruption: the fst's checksum, the metadata, and that every
+/// entry is well formed and its value decompresses and parses as json. A truncated or damaged
+/// copy otherwise only shows at search time, as <decompressionerror> in the output
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// The fst db to check
+    #[clap(short = 'f', value_name = "FST", value_hint = clap::ValueHint::FilePath)]
+    fst: Utf8PathBuf,
+
+    /// Only check that values decompress, for dbs whose values aren't json records
+    #[clap(long)]
+    raw_values: bool,
+}
+
+/// Check the db, writing a line per problem found and a summary. Returns whether it's sound
+pub fn run_verify<W: Write>(args: &VerifyArgs, out: &mut W) -> Result<bool> {
+    let fst = unsafe {
+        mmap_fst(args.fst.clone())
+            .with_context(|| format!("Error opening fst database {}", args.fst))?
+    };
+    let mut problems: u64 = 0;
+    let mut report = |out: &mut W, what: String| -> Result<()> {
+        problems += 1;
+        writeln!(out, "{what}")?;
+        Ok(())
+    };
+
+    // dbs from before fst 0.4 have no checksum, which isn't a problem in itself
+    match fst.verify() {
+        Ok(()) | Err(fst::Error::Fst(fst::raw::Error::ChecksumMissing)) => {}
+        Err(e) => report(out, format!("fst: {e}"))?,
+    }
+    if let Some(name) = meta(&fst, META_KEY_TYPE) {
+        if KeyType::from_name(&name).is_none() {
+            report(
+                out,
+                format!("metadata: unknown key type {}", name.escape_ascii()),
+            )?;
+        }
+    }
+    if let Some(len) = meta(&fst, META_MAX_KEY_LEN) {
+        if std::str::from_utf8(&len).map_or(true, |len| len.parse::<usize>().is_err()) {
+            report(
+                out,
+                format!("metadata: bad max key length {}", len.escape_ascii()),
+            )?;
+        }
+    }
+
+    let codec = ValueCodec::of(&fst);
+    let layout = Layout::of(&fst);
+    // check one value, returning what's wrong with it
+    let check = |compressed: &[u8]| -> Option<String> {
+        let value = match decompress(compressed, &codec) {
+            Ok(value) => value,
+            Err(e) => return Some(format!("value doesn't decompress: {e}")),
+        };
+        match serde_json::from_slice::<serde_json::Value>(&value) {
+            Err(e) if !args.raw_values => Some(format!("value isn't json: {e}")),
+            _ => None,
+        }
+    };
+    let mut entries: u64 = 0;
+
+    if layout == Layout::Map {
+        let path = sidecar_path(&args.fst);
+        let store = ValueStore::open_for(&fst, &path)?;
+        let keys = Map::from(fst);
+        let mut stream = keys.stream();
+        while let Some((key, index)) = stream.next() {
+            if key.first() == Some(&SENTINEL) {
+                continue;
+            }
+            entries += 1;
+            let problem = match store.get(index) {
+                Some(compressed) => check(compressed),
+                None => Some(format!("value {index} is missing from {path}")),
+            };
+            if let Some(problem) = problem {
+                report(out, format!("key {}: {problem}", key.escape_ascii()))?;
+            }
+        }
+    } else {
+        let mut stream = fst.stream();
+        while let Some((entry, _)) = stream.next() {
+            if entry.first() == Some(&SENTINEL) {
+                continue;
+            }
+            entries += 1;
+            // the key, the sentinel, then the value
+            let Some(i) = entry.iter().position(|&b| b == SENTINEL) else {
+                report(out, format!("entry {}: no sentinel", entry.escape_ascii()))?;
+                continue;
+            };
+            if let Some(problem) = check(&entry[i + 1..]) {
+                report(out, format!("key {}: {problem}", entry[..i].escape_ascii()))?;
+            }
+        }
+    }
+
+    writeln!(
+        out,
+        "checked {entries} entries of {}: {}",
+        args.fst,
+        match problems {
+            0 => "ok".to_string(),
+            1 => "1 problem".to_string(),
+            n => format!("{n} problems"),
+        }
+    )?;
+    Ok(problems == 0)
+}
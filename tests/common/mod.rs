@@ -0,0 +1,42 @@
+//! Helpers shared by the integration tests, which run the fstsed binary in a scratch directory
+
+// each test crate uses its own subset of the helpers
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A fresh directory for the test's files
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fstsed-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Build db.fst in `dir` from ndjson records
+pub fn build_db(dir: &Path, records: &str) {
+    fs::write(dir.join("records.ndjson"), records).unwrap();
+    fstsed(dir, &["--build", "-f", "db.fst", "records.ndjson"]);
+}
+
+/// Run fstsed in `dir`, whatever its exit code
+pub fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fstsed"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Run fstsed in `dir` and return its output, failing the test on an error (exit code 2)
+pub fn fstsed(dir: &Path, args: &[&str]) -> Vec<u8> {
+    let output = run(dir, args);
+    assert!(
+        output.status.code().is_some_and(|code| code < 2),
+        "fstsed {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
@@ -0,0 +1,46 @@
+//! The exit code follows grep: 0 when a line matched, 1 when none did, and 2 when an input
+//! couldn't be read, unless -q already found a match
+
+mod common;
+
+use common::{build_db, run, scratch};
+use std::fs;
+use std::path::Path;
+
+fn code(dir: &Path, args: &[&str]) -> i32 {
+    run(dir, args).status.code().unwrap()
+}
+
+#[test]
+fn missing_input_exits_2() {
+    let dir = scratch("exitcode");
+    build_db(&dir, "{\"key\":\"evil.com\"}\n");
+    fs::write(dir.join("hit.txt"), "seen evil.com\n").unwrap();
+    fs::write(dir.join("miss.txt"), "nothing here\n").unwrap();
+
+    assert_eq!(code(&dir, &["-f", "db.fst", "hit.txt"]), 0);
+    assert_eq!(code(&dir, &["-f", "db.fst", "miss.txt"]), 1);
+    assert_eq!(code(&dir, &["-f", "db.fst", "hit.txt", "missing.txt"]), 2);
+    assert_eq!(code(&dir, &["-f", "db.fst", "missing.txt", "hit.txt"]), 2);
+    assert_eq!(code(&dir, &["-f", "db.fst", "missing.txt"]), 2);
+    assert_eq!(
+        code(&dir, &["-c", "-f", "db.fst", "hit.txt", "missing.txt"]),
+        2
+    );
+    // the other inputs are still searched
+    let output = run(
+        &dir,
+        &["-f", "db.fst", "-t", "[{key}]", "missing.txt", "hit.txt"],
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "seen [evil.com]\n");
+
+    assert_eq!(
+        code(&dir, &["-q", "-f", "db.fst", "missing.txt", "hit.txt"]),
+        0
+    );
+    assert_eq!(
+        code(&dir, &["-q", "-f", "db.fst", "missing.txt", "miss.txt"]),
+        2
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,76 @@
+//! Keys must be found wherever a boundary lets one start, including at the start of the line and
+//! right after another match
+
+mod common;
+
+use common::{build_db, fstsed, scratch};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const RECORDS: &str = r#"{"key":"evil.com","actor":"APT1"}
+{"key":"bad.org","actor":"FIN7"}
+"#;
+
+// A fresh directory with a db of the records
+fn db(name: &str) -> PathBuf {
+    let dir = scratch(name);
+    build_db(&dir, RECORDS);
+    dir
+}
+
+// Decorate `line`, marking each match with its key
+fn decorate(dir: &Path, line: &str) -> String {
+    let input = dir.join("input.txt");
+    fs::write(&input, format!("{line}\n")).unwrap();
+    let out = fstsed(
+        dir,
+        &["-C", "never", "-f", "db.fst", "-t", "[{key}]", "input.txt"],
+    );
+    String::from_utf8(out).unwrap().trim_end().to_string()
+}
+
+#[test]
+fn match_right_after_a_match() {
+    let dir = db("adjacent");
+    assert_eq!(decorate(&dir, "evil.com,bad.org"), "[evil.com],[bad.org]");
+    assert_eq!(decorate(&dir, "bad.org,bad.org"), "[bad.org],[bad.org]");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn match_after_one_at_the_start_of_the_line() {
+    let dir = db("start");
+    assert_eq!(
+        decorate(&dir, "evil.com then bad.org"),
+        "[evil.com] then [bad.org]"
+    );
+    assert_eq!(
+        decorate(&dir, "x evil.com bad.org"),
+        "x [evil.com] [bad.org]"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn domain_keys_dont_end_before_another_label() {
+    let dir = scratch("domain");
+    fs::write(dir.join("records.ndjson"), RECORDS).unwrap();
+    fstsed(
+        &dir,
+        &[
+            "--build",
+            "--key-type",
+            "domain",
+            "-f",
+            "db.fst",
+            "records.ndjson",
+        ],
+    );
+    assert_eq!(decorate(&dir, "see evil.com.au"), "see evil.com.au");
+    assert_eq!(decorate(&dir, "see www.evil.com."), "see www.[evil.com].");
+    assert_eq!(
+        decorate(&dir, "EVIL.COM, bad.org.uk"),
+        "[evil.com], bad.org.uk"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
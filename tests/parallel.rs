@@ -0,0 +1,85 @@
+//! --threads must write exactly what a single threaded run writes, whatever the flags
+
+mod common;
+
+use common::{build_db, fstsed, scratch};
+use std::fs;
+use std::path::Path;
+
+const RECORDS: &str = r#"{"key":"evil.com","actor":"APT1","score":5}
+{"key":"bad.org","actor":"FIN7","score":2}
+{"key":"1.2.3.4","actor":"X","score":1}
+"#;
+
+// Enough lines for many batches, with matches scattered through them, and a last line without
+// a newline
+fn write_input(path: &Path, lines: usize) {
+    let mut text = String::new();
+    for i in 0..lines {
+        match i % 7 {
+            0 => text.push_str(&format!("{i} seen evil.com then bad.org\n")),
+            3 => text.push_str(&format!("{i} connect 1.2.3.4:443 from evil.com\n")),
+            _ => text.push_str(&format!(
+                "{i} nothing to see on line {i} of host{i}.example\n"
+            )),
+        }
+    }
+    text.push_str("last evil.com");
+    fs::write(path, text).unwrap();
+}
+
+#[test]
+fn threads_match_single_threaded_output() {
+    let dir = scratch("parallel");
+    build_db(&dir, RECORDS);
+    write_input(&dir.join("a.txt"), 5000);
+    write_input(&dir.join("b.txt"), 3000);
+
+    let flag_sets: [&[&str]; 6] = [
+        &[],
+        &["-H", "-n"],
+        &["-m", "100"],
+        &["--min-matches", "2"],
+        &["--score", "score", "-t", "{actor}:{line_score}"],
+        &["--max-output-line-bytes", "30"],
+    ];
+    for flags in flag_sets {
+        let mut args = vec!["-f", "db.fst"];
+        args.extend_from_slice(flags);
+        args.extend(["a.txt", "b.txt"]);
+        let expected = fstsed(&dir, &args);
+        for threads in ["1", "3", "8"] {
+            let mut threaded = vec!["--threads", threads];
+            threaded.extend_from_slice(&args);
+            assert!(
+                fstsed(&dir, &threaded) == expected,
+                "--threads {threads} output differs with {flags:?}"
+            );
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unordered_writes_the_same_lines() {
+    let dir = scratch("unordered");
+    build_db(&dir, RECORDS);
+    let mut text = String::new();
+    for i in 0..20000 {
+        text.push_str(&format!("{i} evil.com {}\n", i % 13));
+    }
+    fs::write(dir.join("a.txt"), text).unwrap();
+
+    let sorted = |output: Vec<u8>| {
+        let mut lines: Vec<Vec<u8>> = output.split(|&b| b == b'\n').map(<[u8]>::to_vec).collect();
+        lines.sort_unstable();
+        lines
+    };
+    let expected = sorted(fstsed(&dir, &["-f", "db.fst", "a.txt"]));
+    let unordered = fstsed(
+        &dir,
+        &["-f", "db.fst", "--threads", "4", "--unordered", "a.txt"],
+    );
+    assert!(sorted(unordered) == expected);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,65 @@
+use ::fstsed::fstsed::FstSed as Matcher;
+use wasm_bindgen::prelude::*;
+
+/// A fstsed database loaded from bytes, e.g. the body of a fetch
+#[wasm_bindgen]
+pub struct FstSed {
+    inner: Matcher,
+}
+
+// Convert the byte offsets of the matches in `text` to utf16 offsets, which is how javascript
+// strings are indexed
+fn utf16_offsets(text: &str, byte_offsets: impl Iterator<Item = usize>) -> Vec<u32> {
+    let mut units = 0;
+    let mut bytes = 0;
+    byte_offsets
+        .map(|byte| {
+            units += text[bytes..byte].encode_utf16().count();
+            bytes = byte;
+            units as u32
+        })
+        .collect()
+}
+
+#[wasm_bindgen]
+impl FstSed {
+    /// Load a database from its bytes. `template` decorates matches in render(), by default
+    /// "<{key}|{value}>"
+    #[wasm_bindgen(constructor)]
+    pub fn new(bytes: Vec<u8>, template: Option<String>) -> Result<FstSed, JsError> {
+        let inner =
+            Matcher::from_bytes(bytes, template).map_err(|e| JsError::new(&format!("{e:#}")))?;
+        Ok(Self { inner })
+    }
+
+    /// The spans of the matches in `text` as a flat [start, end, start, end, ...] array of
+    /// string indexes, e.g. for building highlight ranges
+    pub fn find(&self, text: &str) -> Vec<u32> {
+        let spans = self
+            .inner
+            .find_iter(text.as_bytes())
+            .flat_map(|m| [m.start, m.end]);
+        utf16_offsets(text, spans)
+    }
+
+    /// The keys that match in `text`, in order
+    pub fn keys(&self, text: &str) -> Vec<String> {
+        self.inner
+            .find_iter(text.as_bytes())
+            .map(|m| String::from_utf8_lossy(m.key()).into_owned())
+            .collect()
+    }
+
+    /// The text with every match decorated by the template
+    pub fn render(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut lastpos = 0;
+        for m in self.inner.find_iter(text.as_bytes()) {
+            out.push_str(&text[lastpos..m.start]);
+            out.push_str(&self.inner.get_match(&m).render());
+            lastpos = m.end;
+        }
+        out.push_str(&text[lastpos..]);
+        out
+    }
+}
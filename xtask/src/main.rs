@@ -0,0 +1,175 @@
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const USAGE: &str = "\
+Usage: cargo xtask dist [OPTIONS]
+
+Build static release binaries of fstsed for each target and feature set, and package each
+as target/dist/fstsed-<version>-<target>-<variant>.tar.gz with a SHA256SUMS file
+
+Options:
+  --target <TRIPLE>   Target to build for, may be repeated
+                      [default: x86_64-unknown-linux-musl, aarch64-unknown-linux-musl]
+  --variant <NAME>    Feature set to build, may be repeated: default, parquet, sqlite
+                      or minimal [default: all]
+  --cross             Build with cross instead of cargo, for targets without a local linker
+  --out <DIR>         Where to write the archives [default: target/dist]
+";
+
+const TARGETS: [&str; 2] = ["x86_64-unknown-linux-musl", "aarch64-unknown-linux-musl"];
+
+/// The feature sets released, by name. The binary needs the cli feature, which includes the
+/// --serve http server, so every variant has it. minimal leaves out the regex crate's unicode
+/// tables, for small binaries deployed to every host
+const VARIANTS: [(&str, &str); 4] = [
+    ("default", "cli,unicode"),
+    ("parquet", "cli,unicode,parquet"),
+    ("sqlite", "cli,unicode,sqlite"),
+    ("minimal", "cli"),
+];
+
+struct DistArgs {
+    targets: Vec<String>,
+    variants: Vec<(&'static str, &'static str)>,
+    cross: bool,
+    out: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        Some("dist") => dist(parse_dist_args(args)?),
+        Some("-h" | "--help") | None => {
+            print!("{USAGE}");
+            Ok(())
+        }
+        Some(task) => bail!("unknown task {task:?}\n\n{USAGE}"),
+    }
+}
+
+fn parse_dist_args(mut args: impl Iterator<Item = String>) -> Result<DistArgs> {
+    let mut dist = DistArgs {
+        targets: Vec::new(),
+        variants: Vec::new(),
+        cross: false,
+        out: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{arg} needs a value\n\n{USAGE}"))
+        };
+        match arg.as_str() {
+            "--target" => dist.targets.push(value()?),
+            "--variant" => {
+                let name = value()?;
+                match VARIANTS.iter().find(|(n, _)| *n == name) {
+                    Some(variant) => dist.variants.push(*variant),
+                    None => {
+                        bail!("unknown variant {name:?}, expected default, parquet, sqlite or minimal")
+                    }
+                }
+            }
+            "--out" => dist.out = Some(PathBuf::from(value()?)),
+            "--cross" => dist.cross = true,
+            _ => bail!("unknown option {arg:?}\n\n{USAGE}"),
+        }
+    }
+    if dist.targets.is_empty() {
+        dist.targets = TARGETS.iter().map(|t| t.to_string()).collect();
+    }
+    if dist.variants.is_empty() {
+        dist.variants = VARIANTS.to_vec();
+    }
+    Ok(dist)
+}
+
+// the workspace root, the parent of this crate
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask is inside the workspace")
+        .to_path_buf()
+}
+
+// the version of the fstsed package, from its manifest
+fn version(root: &Path) -> Result<String> {
+    let manifest = fs::read_to_string(root.join("Cargo.toml"))?;
+    manifest
+        .lines()
+        .find_map(|line| {
+            let value = line
+                .strip_prefix("version")?
+                .trim_start()
+                .strip_prefix('=')?;
+            value.split('"').nth(1).map(str::to_string)
+        })
+        .context("no version in Cargo.toml")
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    eprintln!("+ {command:?}");
+    let status = command
+        .status()
+        .with_context(|| format!("Error running {:?}", command.get_program()))?;
+    if !status.success() {
+        bail!("{:?} failed: {status}", command.get_program());
+    }
+    Ok(())
+}
+
+fn dist(args: DistArgs) -> Result<()> {
+    let root = root();
+    let version = version(&root)?;
+    let out = args.out.unwrap_or_else(|| root.join("target/dist"));
+    fs::create_dir_all(&out).with_context(|| format!("Error creating {}", out.display()))?;
+
+    let mut sums = String::new();
+    for target in &args.targets {
+        for (variant, features) in &args.variants {
+            let builder = if args.cross { "cross" } else { "cargo" };
+            // musl links statically by default; asking for it keeps a glibc target static too
+            run(Command::new(builder)
+                .current_dir(&root)
+                .env("RUSTFLAGS", "-C target-feature=+crt-static")
+                .args(["build", "--release", "--package", "fstsed"])
+                .args(["--bin", "fstsed", "--no-default-features", "--features"])
+                .arg(features)
+                .args(["--target", target]))?;
+
+            // stage the binary with the readme and license, then archive the directory
+            let name = format!("fstsed-{version}-{target}-{variant}");
+            let stage = out.join(&name);
+            if stage.exists() {
+                fs::remove_dir_all(&stage)?;
+            }
+            fs::create_dir_all(&stage)?;
+            let binary = root.join("target").join(target).join("release/fstsed");
+            fs::copy(&binary, stage.join("fstsed"))
+                .with_context(|| format!("Error copying {}", binary.display()))?;
+            for doc in ["README.md", "LICENSE"] {
+                fs::copy(root.join(doc), stage.join(doc))
+                    .with_context(|| format!("Error copying {doc}"))?;
+            }
+            let archive = format!("{name}.tar.gz");
+            run(Command::new("tar")
+                .current_dir(&out)
+                .args(["-czf", &archive, &name]))?;
+            fs::remove_dir_all(&stage)?;
+
+            let digest = Sha256::digest(fs::read(out.join(&archive))?);
+            for byte in digest {
+                write!(sums, "{byte:02x}")?;
+            }
+            writeln!(sums, "  {archive}")?;
+        }
+    }
+    fs::write(out.join("SHA256SUMS"), sums)?;
+    eprintln!("wrote {}", out.display());
+    Ok(())
+}